@@ -0,0 +1,419 @@
+//! Automatic alignment of the map overlay.
+//!
+//! Timestamp errors shift the overlay by many lines, and the yaw and scale
+//! corrections on `MapSettings` are hard to tune by hand. Here I look for land
+//! and sea edges on both channels and search for the time offset, yaw and
+//! scales that put the projected coastlines on top of strong edges.
+//!
+//! The score is the mean edge strength under the coastline points, relative to
+//! the mean edge strength of the whole image. The search starts on a blurred
+//! edge map to find big offsets and then refines on a sharper one.
+
+use log::{info, warn};
+
+use crate::decode::{
+    PX_CHANNEL_IMAGE_DATA, PX_PER_CHANNEL, PX_PER_ROW, PX_SPACE_DATA, PX_SYNC_FRAME,
+};
+use crate::dsp::Signal;
+use crate::err;
+use crate::georef::{self, Georef};
+use crate::map;
+use crate::misc;
+use crate::noaa_apt::{MapSettings, OrbitSettings, RefTime};
+use crate::projection;
+use crate::tle;
+
+/// Largest time offset to search, in rows. Two rows per second.
+const MAX_OFFSET: i32 = 60;
+
+/// Limits for the yaw correction in radians, the same allowed on the GUI.
+const MAX_YAW: f64 = 0.5;
+
+/// Limits for the horizontal and vertical scales.
+const MIN_SCALE: f64 = 0.9;
+const MAX_SCALE: f64 = 1.1;
+
+/// Blur radius in pixels of the edge maps used on each stage of the search.
+const BLUR_RADIUS: [usize; 2] = [6, 1];
+
+/// Distance in degrees between coastline points.
+const COASTLINE_SPACING: f64 = 0.04;
+
+/// If the best score is lower than this, coastlines are probably not visible
+/// because of clouds or lack of contrast, so I keep the original settings.
+const MIN_SCORE: f64 = 1.3;
+
+/// Fitted map overlay parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alignment {
+    /// Rows to add to the recording time.
+    pub offset: i32,
+
+    pub yaw: f64,
+    pub hscale: f64,
+    pub vscale: f64,
+
+    /// Mean edge strength under the coastlines relative to the whole image.
+    pub score: f64,
+}
+
+impl Alignment {
+    /// Time offset to apply to the recording time.
+    pub fn time_offset(&self) -> chrono::Duration {
+        georef::line_duration() * self.offset
+    }
+
+    /// Apply to some orbit settings, shifting the recording time and setting
+    /// the map overlay corrections.
+    pub fn apply(&self, orbit: &OrbitSettings) -> OrbitSettings {
+        let ref_time = match orbit.ref_time {
+            RefTime::Start(time) => RefTime::Start(time + self.time_offset()),
+            RefTime::End(time) => RefTime::End(time + self.time_offset()),
+        };
+
+        OrbitSettings {
+            ref_time,
+            draw_map: orbit.draw_map.as_ref().map(|m| MapSettings {
+                yaw: self.yaw,
+                hscale: self.hscale,
+                vscale: self.vscale,
+                ..m.clone()
+            }),
+            ..orbit.clone()
+        }
+    }
+}
+
+/// Find the map overlay parameters for an image.
+///
+/// Starts from the recording time and the corrections given on the orbit
+/// settings. If the coastlines are not visible I return the starting
+/// parameters, with a warning.
+pub fn align(signal: &Signal, orbit: &OrbitSettings) -> err::Result<Alignment> {
+    let height = signal.len() as u32 / PX_PER_ROW;
+    if height < 2 {
+        return Err(err::Error::InvalidInput("Image too short to align".to_string()));
+    }
+
+    let tle = tle::for_orbit(orbit)?;
+    let start_time =
+        georef::start_time(&orbit.ref_time, height) - georef::line_duration() * MAX_OFFSET;
+    let track = georef::track(
+        &tle,
+        &orbit.sat_name,
+        start_time,
+        height + 2 * MAX_OFFSET as u32,
+    )?;
+
+    // Coastlines around every position the image can take
+    let swath = projection::swath_bbox(&Georef::from_track(
+        track.clone(),
+        0.,
+        MIN_SCALE,
+        MIN_SCALE,
+    )?);
+    let points = map::coastline(&swath, COASTLINE_SPACING)?;
+    info!("Aligning map using {} coastline points", points.len());
+
+    let initial = match &orbit.draw_map {
+        Some(m) => Alignment {
+            offset: 0,
+            yaw: m.yaw,
+            hscale: m.hscale,
+            vscale: m.vscale,
+            score: 0.,
+        },
+        None => Alignment {
+            offset: 0,
+            yaw: 0.,
+            hscale: 1.,
+            vscale: 1.,
+            score: 0.,
+        },
+    };
+
+    let edges = edge_map(signal)?;
+    let result = search(&edges, height, &track, &points, &initial)?;
+
+    if result.score < MIN_SCORE {
+        warn!(
+            "Coastlines not found on the image (score {:.2}), keeping the map settings",
+            result.score
+        );
+        return Ok(Alignment {
+            score: result.score,
+            ..initial
+        });
+    }
+
+    Ok(result)
+}
+
+/// Search the parameters on edge maps of increasing sharpness.
+fn search(
+    edges: &[f32],
+    height: u32,
+    track: &[(f64, f64)],
+    points: &[(f64, f64)],
+    initial: &Alignment,
+) -> err::Result<Alignment> {
+    let width = PX_CHANNEL_IMAGE_DATA as usize;
+
+    let mut best = initial.clone();
+
+    for (stage, &radius) in BLUR_RADIUS.iter().enumerate() {
+        let blurred = normalize(blur(edges, width, radius));
+        let score = |a: &Alignment| score(&blurred, height, track, points, a);
+
+        if stage == 0 {
+            // Exhaustive search of the time offset, it can be far from the
+            // correct one
+            for offset in -MAX_OFFSET..=MAX_OFFSET {
+                let candidate = Alignment {
+                    offset,
+                    ..best.clone()
+                };
+                if score(&candidate)? > score(&best)? {
+                    best = candidate;
+                }
+            }
+        }
+
+        best.score = score(&best)?;
+
+        // Pattern search, trying every combination of steps because the
+        // parameters are coupled. The steps are halved when nothing improves
+        let mut steps = (4, 0.04, 0.02, 0.02);
+        while steps.1 > 0.0005 {
+            let center = best.clone();
+            for i in 1..81 {
+                // Each parameter moves -1, 0 or +1 steps
+                let d = |n: u32| ((i / 3_i32.pow(n)) % 3 - 1) as f64;
+
+                let mut candidate = Alignment {
+                    offset: (center.offset + d(0) as i32 * steps.0).clamp(-MAX_OFFSET, MAX_OFFSET),
+                    yaw: (center.yaw + d(1) * steps.1).clamp(-MAX_YAW, MAX_YAW),
+                    hscale: (center.hscale + d(2) * steps.2).clamp(MIN_SCALE, MAX_SCALE),
+                    vscale: (center.vscale + d(3) * steps.3).clamp(MIN_SCALE, MAX_SCALE),
+                    score: 0.,
+                };
+                candidate.score = score(&candidate)?;
+                if candidate.score > best.score {
+                    best = candidate;
+                }
+            }
+
+            if best == center {
+                steps = ((steps.0 / 2).max(1), steps.1 / 2., steps.2 / 2., steps.3 / 2.);
+            }
+        }
+    }
+
+    Ok(best)
+}
+
+/// Mean edge strength under the coastline points that fall inside the image.
+///
+/// Returns 0 if too few points are inside, so the search doesn't move the
+/// image away from the coastlines.
+fn score(
+    edges: &[f32],
+    height: u32,
+    track: &[(f64, f64)],
+    points: &[(f64, f64)],
+    alignment: &Alignment,
+) -> err::Result<f64> {
+    let width = PX_CHANNEL_IMAGE_DATA as f64;
+    let nadir = projection::nadir_column(false);
+
+    let first = (MAX_OFFSET + alignment.offset) as usize;
+    let georef = Georef::from_track(
+        track[first..first + height as usize].to_vec(),
+        alignment.yaw,
+        alignment.hscale,
+        alignment.vscale,
+    )?;
+
+    let mut sum = 0.;
+    let mut count = 0;
+    for &latlon in points {
+        let (x, y) = georef.latlon_to_rel_px(latlon);
+        let col = (x + nadir).round();
+        let row = y.round();
+        if col >= 0. && col < width && row >= 0. && row < height as f64 {
+            sum += edges[row as usize * PX_CHANNEL_IMAGE_DATA as usize + col as usize] as f64;
+            count += 1;
+        }
+    }
+
+    if count < 10 {
+        return Ok(0.);
+    }
+
+    Ok(sum / count as f64)
+}
+
+/// Gradient magnitude of both channels added, one value per pixel of the
+/// channel image data.
+fn edge_map(signal: &Signal) -> err::Result<Vec<f32>> {
+    let width = PX_CHANNEL_IMAGE_DATA as usize;
+    let height = signal.len() / PX_PER_ROW as usize;
+
+    let (low, high) = misc::percent(signal, 0.98)?;
+    let value = |channel_start: u32, x: usize, y: usize| {
+        let first = (channel_start + PX_SYNC_FRAME + PX_SPACE_DATA) as usize;
+        let i = y * PX_PER_ROW as usize + first + x;
+        ((signal[i] - low) / (high - low)).clamp(0., 1.)
+    };
+
+    let mut edges = vec![0.; width * height];
+
+    for channel_start in [0, PX_PER_CHANNEL] {
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width - 1 {
+                let p = |dx: usize, dy: usize| value(channel_start, x + dx - 1, y + dy - 1);
+
+                // Sobel operator
+                let gx = p(2, 0) + 2. * p(2, 1) + p(2, 2) - p(0, 0) - 2. * p(0, 1) - p(0, 2);
+                let gy = p(0, 2) + 2. * p(1, 2) + p(2, 2) - p(0, 0) - 2. * p(1, 0) - p(2, 0);
+
+                edges[y * width + x] += (gx * gx + gy * gy).sqrt();
+            }
+        }
+    }
+
+    Ok(edges)
+}
+
+/// Box blur applied twice, horizontally and vertically.
+fn blur(data: &[f32], width: usize, radius: usize) -> Vec<f32> {
+    let height = data.len() / width;
+    let mut result = data.to_vec();
+
+    let mut pass = |len: usize, count: usize, index: &dyn Fn(usize, usize) -> usize| {
+        let mut line = vec![0.; len];
+        for j in 0..count {
+            for _ in 0..2 {
+                for (i, value) in line.iter_mut().enumerate() {
+                    *value = result[index(j, i)];
+                }
+
+                // Running sum over the window, clamped on the borders
+                let mut sum: f32 = line[..radius.min(len)].iter().sum();
+                for i in 0..len {
+                    if i + radius < len {
+                        sum += line[i + radius];
+                    }
+                    if i > radius {
+                        sum -= line[i - radius - 1];
+                    }
+                    let size = (i + radius).min(len - 1) + 1 - i.saturating_sub(radius);
+                    result[index(j, i)] = sum / size as f32;
+                }
+            }
+        }
+    };
+
+    pass(width, height, &|row, i| row * width + i);
+    pass(height, width, &|col, i| i * width + col);
+
+    result
+}
+
+/// Divide by the mean, so scores are relative to the average edge.
+fn normalize(mut data: Vec<f32>) -> Vec<f32> {
+    let mean = data.iter().sum::<f32>() / data.len() as f32;
+    if mean > 0. {
+        for value in data.iter_mut() {
+            *value /= mean;
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Blurring a constant signal should not change it.
+    #[test]
+    fn test_blur() {
+        let data = vec![3.; 20 * 10];
+        for value in blur(&data, 20, 4) {
+            assert!((value - 3.).abs() < 1e-5);
+        }
+
+        let mut data = vec![0.; 40 * 20];
+        data[10 * 40 + 20] = 1.;
+        let blurred = blur(&data, 40, 2);
+        assert!((blurred.iter().sum::<f32>() - 1.).abs() < 1e-5);
+        assert!(blurred[10 * 40 + 20] > blurred[10 * 40 + 22]);
+    }
+
+    /// Draw an image of the land mask with known parameters and check that
+    /// they are found.
+    #[test]
+    fn test_search() {
+        let height = 400;
+
+        // Going north over the east coast of South America
+        let track: Vec<(f64, f64)> = (0..height + 2 * MAX_OFFSET as usize)
+            .map(|i| {
+                let (lat, lon) = (-40. + i as f64 * 0.036, -52. + i as f64 * 0.004);
+                (lat.to_radians(), lon.to_radians())
+            })
+            .collect();
+
+        let expected = Alignment {
+            offset: 13,
+            yaw: 0.03,
+            hscale: 1.04,
+            vscale: 0.97,
+            score: 0.,
+        };
+
+        let first = (MAX_OFFSET + expected.offset) as usize;
+        let georef = Georef::from_track(
+            track[first..first + height].to_vec(),
+            expected.yaw,
+            expected.hscale,
+            expected.vscale,
+        )
+        .unwrap();
+        let land = map::land_mask(&georef).unwrap();
+
+        let mut signal = vec![0.; height * PX_PER_ROW as usize];
+        for y in 0..height {
+            for x in 0..PX_CHANNEL_IMAGE_DATA as usize {
+                let value = if land[y * PX_CHANNEL_IMAGE_DATA as usize + x] { 200. } else { 50. };
+                for channel_start in [0, PX_PER_CHANNEL] {
+                    let i = y * PX_PER_ROW as usize
+                        + (channel_start + PX_SYNC_FRAME + PX_SPACE_DATA) as usize
+                        + x;
+                    signal[i] = value;
+                }
+            }
+        }
+
+        let wide = Georef::from_track(track.clone(), 0., MIN_SCALE, MIN_SCALE).unwrap();
+        let swath = projection::swath_bbox(&wide);
+        let points = map::coastline(&swath, COASTLINE_SPACING).unwrap();
+
+        let initial = Alignment {
+            offset: 0,
+            yaw: 0.,
+            hscale: 1.,
+            vscale: 1.,
+            score: 0.,
+        };
+        let edges = edge_map(&signal).unwrap();
+        let result = search(&edges, height as u32, &track, &points, &initial).unwrap();
+
+        assert!(result.score > MIN_SCORE);
+        assert!((result.offset - expected.offset).abs() <= 1);
+        assert!((result.yaw - expected.yaw).abs() < 0.01);
+        assert!((result.hscale - expected.hscale).abs() < 0.01);
+        assert!((result.vscale - expected.vscale).abs() < 0.01);
+    }
+}
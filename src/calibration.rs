@@ -0,0 +1,264 @@
+//! Radiometric calibration of the thermal channels.
+//!
+//! Converts channel B values to brightness temperatures following the NOAA KLM
+//! User's Guide, section 7.1.2. The black body temperature comes from the PRTs
+//! on the telemetry, and the back scan and space view counts give the two
+//! points needed for a linear calibration. A quadratic correction is applied
+//! afterwards for channels 4 and 5.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::decode::{
+    PX_CHANNEL_IMAGE_DATA, PX_PER_CHANNEL, PX_PER_ROW, PX_SPACE_DATA, PX_SYNC_FRAME,
+};
+use crate::dsp::Signal;
+use crate::err;
+use crate::misc;
+use crate::noaa_apt::SatName;
+use crate::telemetry::{Channel, Telemetry};
+
+/// First radiation constant in mW/(m2 sr cm^-4).
+const C1: f64 = 1.191_042_7e-5;
+
+/// Second radiation constant in cm K.
+const C2: f64 = 1.438_775_2;
+
+/// Calibration coefficients of a thermal channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelCoefficients {
+    /// Central wavenumber in cm^-1.
+    pub wavenumber: f64,
+
+    /// Band correction, the effective temperature is `a + b * T`.
+    pub a: f64,
+    pub b: f64,
+
+    /// Radiance of space in mW/(m2 sr cm^-1).
+    pub space_radiance: f64,
+
+    /// Non-linear correction coefficients.
+    pub nonlinear: [f64; 3],
+}
+
+/// Get calibration coefficients, from the NOAA KLM User's Guide, Appendix D.
+///
+/// Takes the channel name as given by `Telemetry::get_channel_name()`, returns
+/// `None` if it is not a thermal channel.
+pub fn channel_coefficients(sat_name: &SatName, channel: &str) -> Option<ChannelCoefficients> {
+    let coefficients = |wavenumber, a, b, space_radiance, nonlinear| ChannelCoefficients {
+        wavenumber,
+        a,
+        b,
+        space_radiance,
+        nonlinear,
+    };
+
+    match (sat_name, channel) {
+        (SatName::Noaa15, "3b") => Some(coefficients(
+            2695.9743,
+            1.621256,
+            0.998015,
+            0.,
+            [0., 0., 0.],
+        )),
+        (SatName::Noaa15, "4") => Some(coefficients(
+            925.4075,
+            0.337810,
+            0.998719,
+            -4.50,
+            [4.76, -0.0932, 0.0004524],
+        )),
+        (SatName::Noaa15, "5") => Some(coefficients(
+            839.8979,
+            0.304558,
+            0.999024,
+            -3.61,
+            [3.72, -0.0659, 0.0002811],
+        )),
+        (SatName::Noaa18, "3b") => Some(coefficients(
+            2659.7952,
+            1.698704,
+            0.996960,
+            0.,
+            [0., 0., 0.],
+        )),
+        (SatName::Noaa18, "4") => Some(coefficients(
+            928.1460,
+            0.436645,
+            0.998607,
+            -5.53,
+            [5.82, -0.11069, 0.00052337],
+        )),
+        (SatName::Noaa18, "5") => Some(coefficients(
+            833.2532,
+            0.253179,
+            0.999057,
+            -2.22,
+            [2.67, -0.04360, 0.00017715],
+        )),
+        (SatName::Noaa19, "3b") => Some(coefficients(2670.0, 1.67396, 0.997364, 0., [0., 0., 0.])),
+        (SatName::Noaa19, "4") => Some(coefficients(
+            928.9,
+            0.53959,
+            0.998534,
+            -5.49,
+            [5.70, -0.11187, 0.00054668],
+        )),
+        (SatName::Noaa19, "5") => Some(coefficients(
+            831.9,
+            0.36064,
+            0.998913,
+            -3.39,
+            [3.58, -0.05991, 0.00024985],
+        )),
+        _ => None,
+    }
+}
+
+/// Planck function, radiance in mW/(m2 sr cm^-1) for some temperature.
+fn planck(wavenumber: f64, temperature: f64) -> f64 {
+    C1 * wavenumber.powi(3) / ((C2 * wavenumber / temperature).exp() - 1.)
+}
+
+/// Inverse of the Planck function, temperature for some radiance.
+fn inverse_planck(wavenumber: f64, radiance: f64) -> f64 {
+    C2 * wavenumber / (1. + C1 * wavenumber.powi(3) / radiance).ln()
+}
+
+/// Calibration of a pass, maps counts to temperatures.
+pub struct Calibration {
+    coefficients: ChannelCoefficients,
+
+    /// Radiance of the black body.
+    black_body_radiance: f64,
+
+    /// Counts when looking at the black body and at space.
+    back_scan: f64,
+    space_view: f64,
+}
+
+impl Calibration {
+    /// Get calibration of channel B from telemetry.
+    pub fn new(telemetry: &Telemetry, sat_name: &SatName) -> err::Result<Calibration> {
+        let channel_name = telemetry.get_channel_name(Channel::B);
+
+        let coefficients = channel_coefficients(sat_name, channel_name).ok_or_else(|| {
+            err::Error::InvalidInput(format!(
+                "Channel B is channel {}, only thermal channels 3b, 4 and 5 can be calibrated",
+                channel_name
+            ))
+        })?;
+
+        let space_view = telemetry
+            .space_view_counts(Channel::B)
+            .ok_or_else(|| err::Error::Internal("Unknown space view counts".to_string()))?
+            as f64;
+        let back_scan = telemetry.back_scan_counts(Channel::B) as f64;
+
+        if (space_view - back_scan).abs() < 1. {
+            return Err(err::Error::Internal(
+                "Back scan and space view counts are equal, can't calibrate".to_string(),
+            ));
+        }
+
+        // Effective black body temperature
+        let temperature =
+            coefficients.a + coefficients.b * telemetry.black_body_temperature(sat_name) as f64;
+
+        Ok(Calibration {
+            black_body_radiance: planck(coefficients.wavenumber, temperature),
+            coefficients,
+            back_scan,
+            space_view,
+        })
+    }
+
+    /// Brightness temperature in Kelvin for some counts.
+    pub fn temperature(&self, counts: f64) -> f64 {
+        let c = &self.coefficients;
+
+        let linear = c.space_radiance
+            + (self.black_body_radiance - c.space_radiance) * (self.space_view - counts)
+                / (self.space_view - self.back_scan);
+
+        let radiance =
+            linear + c.nonlinear[0] + c.nonlinear[1] * linear + c.nonlinear[2] * linear * linear;
+
+        if radiance <= 0. {
+            return f64::NAN;
+        }
+
+        (inverse_planck(c.wavenumber, radiance) - c.a) / c.b
+    }
+}
+
+/// Brightness temperatures of the channel B image data, row by row.
+///
+/// Takes the raw image data returned by `decode()`, the result is
+/// `PX_CHANNEL_IMAGE_DATA` values wide.
+pub fn channel_b_temperatures(
+    telemetry: &Telemetry,
+    calibration: &Calibration,
+    signal: &Signal,
+) -> Vec<f32> {
+    let start = (PX_PER_CHANNEL + PX_SYNC_FRAME + PX_SPACE_DATA) as usize;
+    let end = start + PX_CHANNEL_IMAGE_DATA as usize;
+
+    signal
+        .chunks_exact(PX_PER_ROW as usize)
+        .flat_map(|line| line[start..end].iter())
+        .map(|&value| calibration.temperature(telemetry.to_counts(value) as f64) as f32)
+        .collect()
+}
+
+/// Write a two dimensional array of floats as NumPy `.npy` file.
+pub fn write_npy(data: &[f32], width: u32, height: u32, filename: &Path) -> err::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    file.write_all(&misc::npy_header(&[height as usize, width as usize]))?;
+    for value in data {
+        file.write_all(&value.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// The black body should give the black body temperature and the
+    /// temperatures should decrease with the counts.
+    #[test]
+    fn test_calibration() {
+        let coefficients = channel_coefficients(&SatName::Noaa19, "4").unwrap();
+        assert!(channel_coefficients(&SatName::Noaa19, "2").is_none());
+
+        let t = 290.;
+        assert!((inverse_planck(928.9, planck(928.9, t)) - t).abs() < 1e-9);
+
+        let calibration = Calibration {
+            coefficients: ChannelCoefficients {
+                space_radiance: 0.,
+                nonlinear: [0., 0., 0.],
+                ..coefficients
+            },
+            black_body_radiance: planck(928.9, coefficients.a + coefficients.b * t),
+            back_scan: 400.,
+            space_view: 990.,
+        };
+        assert!((calibration.temperature(400.) - t).abs() < 1e-6);
+
+        let calibration = Calibration {
+            coefficients,
+            black_body_radiance: planck(928.9, coefficients.a + coefficients.b * t),
+            back_scan: 400.,
+            space_view: 990.,
+        };
+        assert!((calibration.temperature(400.) - t).abs() < 1.);
+        assert!(calibration.temperature(500.) < calibration.temperature(400.));
+        assert!(calibration.temperature(700.) > 200.);
+    }
+}
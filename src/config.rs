@@ -13,11 +13,17 @@ use serde::Deserialize;
 use crate::err;
 use crate::misc;
 use crate::noaa_apt::{
-    ColorSettings, Contrast, MapSettings, OrbitSettings, RefTime, Rotate, SatName,
+    BoundingBox, Channel, ColorSettings, Contrast, DopplerSettings, Enhancement, GeorefSettings,
+    IqFormat, IqSettings, MapSettings, OrbitSettings, Projection, ProjectionSettings, RawFormat,
+    RefTime, Rotate, SatName,
 };
+use crate::orbit::GroundStation;
+use crate::parallel;
+use crate::sinks::FloatFormat;
+use crate::watch::{Product, WatchSettings};
 
 // Expected configuration file version.
-const SETTINGS_VERSION: u32 = 4;
+const SETTINGS_VERSION: u32 = 7;
 
 /// Returns a PathBuf of the requested resource file.
 ///
@@ -58,8 +64,76 @@ pub enum Mode {
         rotate: Rotate,
         color_settings: Option<ColorSettings>,
         orbit_settings: Option<OrbitSettings>,
+        iq_settings: Option<IqSettings>,
+        georef_settings: Option<GeorefSettings>,
+        projection_settings: Option<ProjectionSettings>,
+
+        /// Where to save the per-row sync report as CSV.
+        sync_report: Option<PathBuf>,
+
+        /// Where to save the per-line quality report as JSON or CSV.
+        quality_report: Option<PathBuf>,
+
+        /// Where to save the telemetry report as JSON or CSV.
+        telemetry_report: Option<PathBuf>,
+
+        /// Where to save brightness temperatures of channel B.
+        temperatures_filename: Option<PathBuf>,
+
+        /// Fit the recording time and map corrections before processing.
+        auto_align: bool,
     },
 
+    /// Decode raw samples as they arrive, from a file or from stdin if no
+    /// filename is given.
+    Stream {
+        settings: Settings,
+        input_filename: Option<PathBuf>,
+        raw_format: RawFormat,
+        input_rate: u32,
+        output_filename: PathBuf,
+        sync: bool,
+        contrast_adjustment: Contrast,
+        rotate: Rotate,
+        color_settings: Option<ColorSettings>,
+        orbit_settings: Option<OrbitSettings>,
+    },
+
+    /// Decode several passes and blend them on a map.
+    Mosaic {
+        settings: Settings,
+
+        /// Input filenames and orbit information of each one.
+        passes: Vec<(PathBuf, OrbitSettings)>,
+        sync: bool,
+        contrast_adjustment: Contrast,
+        projection_settings: ProjectionSettings,
+    },
+
+    /// Decode recordings as they appear on a folder.
+    Watch {
+        settings: Settings,
+        watch_settings: WatchSettings,
+    },
+
+    /// List upcoming passes over the ground station.
+    Passes {
+        settings: Settings,
+        custom_tle: Option<String>,
+
+        /// How far ahead to look.
+        hours: f64,
+
+        /// Minimum maximum elevation of passes to list, in degrees.
+        min_elevation: f64,
+
+        /// Print JSON instead of a table.
+        json: bool,
+    },
+
+    /// Add TLE files to the archive.
+    ImportTle { filenames: Vec<PathBuf> },
+
     /// Resample image from commandline.
     Resample {
         settings: Settings,
@@ -77,6 +151,12 @@ pub struct Settings {
     /// If we are exporting steps to WAV.
     pub export_wav: bool,
 
+    /// If we are exporting steps as PNG plots.
+    pub export_plots: bool,
+
+    /// If we are exporting steps as NPY or raw float files.
+    pub export_float: Option<FloatFormat>,
+
     /// If we are exporting the filtered signal on resample. When using
     /// `fast_resampling()` this step is VERY slow and RAM heavy (gigabytes!),
     /// so that function checks if this variable is set before doing extra work.
@@ -126,14 +206,31 @@ pub struct Settings {
 
     /// Default thresholds for false color (water, vegetation, clouds)
     pub default_palette_filename: PathBuf,
+
+    /// Location of the receiver.
+    pub ground_station: GroundStation,
+
+    /// If we should estimate the real sample rate of the recording from the
+    /// sync frames and decode again with it.
+    pub correct_sample_rate: bool,
+
+    /// Number of threads to use, zero to use one per processor.
+    pub threads: usize,
+
+    /// Warn if the TLE epoch is more than this number of days away from the
+    /// recording time.
+    pub tle_max_epoch_days: i64,
 }
 
 /// Holds the deserialized raw parsed settings file.
 #[derive(Deserialize)]
 struct DeSettings {
     check_updates: bool,
+    threads: usize,
+    tle_max_epoch_days: i64,
     version: u32,
     timestamps: DeTimestamps,
+    ground_station: GroundStation,
     profiles: DeProfiles,
     map_overlay: DeMapOverlay,
     false_color: DeFalseColor,
@@ -251,6 +348,56 @@ fn load_de_settings() -> DeSettings {
     }
 }
 
+/// Parse ground station location given as "LAT,LON,ALT".
+fn parse_ground_station(text: &str) -> Option<GroundStation> {
+    let values: Vec<f64> = text
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    match values.as_slice() {
+        [latitude, longitude, altitude] => Some(GroundStation {
+            latitude: *latitude,
+            longitude: *longitude,
+            altitude: *altitude,
+        }),
+        _ => None,
+    }
+}
+
+/// Read custom TLE file, exits on failure.
+fn load_custom_tle(filename: &str) -> String {
+    let mut file = File::open(filename).unwrap_or_else(|e| {
+        println!("Could not open custom TLE file: {}", e);
+        std::process::exit(0);
+    });
+    let mut tle = String::new();
+    if let Err(e) = file.read_to_string(&mut tle) {
+        println!("Could not read custom TLE file: {}", e);
+        std::process::exit(0);
+    }
+
+    tle
+}
+
+/// Parse bounding box given as "WEST,SOUTH,EAST,NORTH".
+fn parse_bbox(text: &str) -> Option<BoundingBox> {
+    let values: Vec<f64> = text
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    match values.as_slice() {
+        [west, south, east, north] => Some(BoundingBox {
+            west: *west,
+            south: *south,
+            east: *east,
+            north: *north,
+        }),
+        _ => None,
+    }
+}
+
 /// Read commandline arguments and load settings to decide the settings to
 /// return.
 ///
@@ -260,14 +407,19 @@ fn load_de_settings() -> DeSettings {
 pub fn get_config() -> (bool, log::LevelFilter, Mode) {
     // Parse commandline
 
-    let mut arg_input_filename: Option<PathBuf> = None;
+    let mut arg_input_filenames: Vec<PathBuf> = Vec::new();
     let mut arg_debug = false;
     let mut arg_quiet = false;
     let mut arg_wav_steps = false;
+    let mut arg_plot_steps = false;
+    let mut arg_float_steps: Option<String> = None;
     let mut arg_export_resample_filtered = false;
     let mut arg_sync = true;
+    let mut arg_correct_rate = false;
     let mut arg_contrast_adjustment: Option<String> = None;
     let mut arg_profile: Option<String> = None;
+    let mut arg_threads: Option<usize> = None;
+    let mut arg_tle_max_days: Option<i64> = None;
     let mut arg_print_version = false;
     let mut arg_output_filename: Option<PathBuf> = None;
     let mut arg_resample_output: Option<u32> = None;
@@ -275,6 +427,7 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
     let mut arg_start_time: Option<String> = None;
     let mut arg_tle_filename: Option<String> = None;
     let mut arg_map: Option<String> = None;
+    let mut arg_auto_align = false;
     let mut arg_yaw: Option<f64> = None;
     let mut arg_hscale: Option<f64> = None;
     let mut arg_vscale: Option<f64> = None;
@@ -282,6 +435,33 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
     let mut arg_rotate_deprecated = false;
     let mut arg_false_color = false;
     let mut arg_palette: Option<PathBuf> = None;
+    let mut arg_enhancement: Option<String> = None;
+    let mut arg_day_night = false;
+    let mut arg_mcir = false;
+    let mut arg_night_palette: Option<PathBuf> = None;
+    let mut arg_raw_format: Option<String> = None;
+    let mut arg_raw_rate: Option<u32> = None;
+    let mut arg_iq_format: Option<String> = None;
+    let mut arg_iq_rate: Option<u32> = None;
+    let mut arg_iq_offset: Option<f32> = None;
+    let mut arg_doppler = false;
+    let mut arg_georef: Option<PathBuf> = None;
+    let mut arg_georef_channel: Option<String> = None;
+    let mut arg_project: Option<PathBuf> = None;
+    let mut arg_projection: Option<String> = None;
+    let mut arg_bbox: Option<String> = None;
+    let mut arg_resolution: f64 = 0.03;
+    let mut arg_mosaic = false;
+    let mut arg_sync_report: Option<PathBuf> = None;
+    let mut arg_quality_report: Option<PathBuf> = None;
+    let mut arg_telemetry_report: Option<PathBuf> = None;
+    let mut arg_temperatures: Option<PathBuf> = None;
+    let mut arg_station: Option<String> = None;
+    let mut arg_products: String = "raw".to_string();
+    let mut arg_template: String = "{name}-{product}.png".to_string();
+    let mut arg_hours: f64 = 24.;
+    let mut arg_min_elevation: f64 = 0.;
+    let mut arg_json = false;
     {
         let mut parser = argparse::ArgumentParser::new();
         parser
@@ -289,11 +469,15 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 "Decode NOAA APT images from WAV files. Run without arguments to launch the GUI",
             );
         parser
-            .refer(&mut arg_input_filename)
+            .refer(&mut arg_input_filenames)
             .add_argument(
                 "input_filename",
-                argparse::StoreOption,
-                "Input WAV file.",
+                argparse::Collect,
+                "Input WAV file. When using --raw, use \"-\" or nothing to read from \
+                standard input. Several files can be given when using --mosaic. Use \"watch \
+                FOLDER\" to decode recordings as they appear on a folder. Use \"passes\" to \
+                list upcoming passes over the ground station. Use \"import-tle FILE...\" to add \
+                TLE files to the archive, used to find the TLE closest to each recording.",
             );
         parser
             .refer(&mut arg_output_filename)
@@ -333,6 +517,70 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 "Resample WAV file to a given sample rate, no APT image will be decoded.",
             )
             .metavar("SAMPLE_RATE");
+        parser
+            .refer(&mut arg_raw_format)
+            .add_option(
+                &["--raw"],
+                argparse::StoreOption,
+                "Decode raw samples instead of a WAV file, the image is rewritten while the \
+                samples arrive. Useful for decoding from a pipe, e.g. from rtl_fm. Possible \
+                values: \"u8\", \"s16le\" or \"f32le\". Also see \"--raw-rate\".",
+            )
+            .metavar("FORMAT");
+        parser
+            .refer(&mut arg_raw_rate)
+            .add_option(
+                &["--raw-rate"],
+                argparse::StoreOption,
+                "Sample rate of the raw samples given with \"--raw\".",
+            )
+            .metavar("SAMPLE_RATE");
+        parser
+            .refer(&mut arg_iq_format)
+            .add_option(
+                &["--iq"],
+                argparse::StoreOption,
+                "Input file is an IQ recording, it will be FM demodulated before decoding. \
+                Possible values: \"cu8\" (e.g. from rtl_sdr), \"cs16\", \"cf32\" or \"wav\" \
+                (two channels, I and Q). Raw formats need \"--iq-rate\".",
+            )
+            .metavar("FORMAT");
+        parser
+            .refer(&mut arg_iq_rate)
+            .add_option(
+                &["--iq-rate"],
+                argparse::StoreOption,
+                "Sample rate of raw IQ recordings.",
+            )
+            .metavar("SAMPLE_RATE");
+        parser
+            .refer(&mut arg_iq_offset)
+            .add_option(
+                &["--iq-offset"],
+                argparse::StoreOption,
+                "Frequency of the satellite signal relative to the center of the IQ recording, in \
+                Hz. If not given it will be estimated from the recording.",
+            )
+            .metavar("FREQUENCY");
+        parser
+            .refer(&mut arg_doppler)
+            .add_option(
+                &["--doppler"],
+                argparse::StoreTrue,
+                "Correct the Doppler shift of IQ recordings using orbit calculations. Needs the \
+                satellite name, the recording time and the location of the ground station, that \
+                is loaded from the settings file or given with \"--station\".",
+            );
+        parser
+            .refer(&mut arg_station)
+            .add_option(
+                &["--station"],
+                argparse::StoreOption,
+                "Location of the ground station as latitude and longitude in degrees and \
+                altitude in meters, e.g. \"-34.6,-58.4,25\". Overrides the location on the \
+                settings file.",
+            )
+            .metavar("LAT,LON,ALT");
         parser
             .refer(&mut arg_sync)
             .add_option(
@@ -341,6 +589,58 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 "Disable syncing, useful when the sync frames are noisy and the syncing attempts do \
                 more harm than good.",
             );
+        parser
+            .refer(&mut arg_correct_rate)
+            .add_option(
+                &["--correct-rate"],
+                argparse::StoreTrue,
+                "Estimate the real sample rate of the recording from the spacing of the sync \
+                frames and decode again with it. Useful with sound cards or SDR dongles whose \
+                clock is slightly off, works even with --no-sync.",
+            );
+        parser
+            .refer(&mut arg_sync_report)
+            .add_option(
+                &["--sync-report"],
+                argparse::StoreOption,
+                "Save a CSV file with the sync frames found on each row: position, correlation \
+                with sync A and sync B, if the row was interpolated or dropped and if the \
+                channels look swapped.",
+            )
+            .metavar("FILENAME");
+        parser
+            .refer(&mut arg_quality_report)
+            .add_option(
+                &["--quality-report"],
+                argparse::StoreOption,
+                "Save the signal quality of each line: SNR of each channel, correlation of the \
+                sync frames, telemetry variance and if the line is a dropout. Saved as JSON \
+                including a summary and a score of the pass if the filename ends in .json, \
+                otherwise as CSV.",
+            )
+            .metavar("FILENAME");
+        parser
+            .refer(&mut arg_telemetry_report)
+            .add_option(
+                &["--telemetry-report"],
+                argparse::StoreOption,
+                "Save the telemetry: wedge values, black body temperatures, patch temperature, \
+                back scan and space view. If the filename ends in \".json\" it is saved as \
+                JSON, otherwise as CSV. Temperatures need the satellite name.",
+            )
+            .metavar("FILENAME");
+        parser
+            .refer(&mut arg_temperatures)
+            .add_option(
+                &["--temperatures"],
+                argparse::StoreOption,
+                "Calibrate channel B using the telemetry and save brightness temperatures in \
+                Kelvin. Only available when channel B is a thermal channel (3B, 4 or 5). If the \
+                filename ends in \".npy\" a NumPy array is saved, if it ends in \".tif\" a \
+                float GeoTIFF with ground control points. Needs the satellite name and \
+                recording time.",
+            )
+            .metavar("FILENAME");
         parser
             .refer(&mut arg_contrast_adjustment)
             .add_option(
@@ -392,6 +692,16 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 "Vertical map scale correction for map overlay. Default: 1.",
             )
             .metavar("VSCALE");
+        parser
+            .refer(&mut arg_auto_align)
+            .add_option(
+                &["--map-auto-align"],
+                argparse::StoreTrue,
+                "Find the time offset, yaw and scale corrections of the map overlay by matching \
+                coastlines to the edges on the image. The values given with --map-yaw, \
+                --map-hscale and --map-vscale are used as a starting point. Works only if some \
+                coastline is visible.",
+            );
         parser
             .refer(&mut arg_rotate)
             .add_option(
@@ -421,6 +731,116 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 channel A brightness and Y axis is channel B brigtness. Built-in palettes are \
                 available in the folder \"res/palettes/\".",
             );
+        parser
+            .refer(&mut arg_enhancement)
+            .add_option(
+                &["--enhancement"],
+                argparse::StoreOption,
+                "Colorize the image using brightness temperatures of channel B, calibrated from \
+                the telemetry, and add a temperature scale below. Only available when channel B \
+                is a thermal channel (3B, 4 or 5). Possible values: \"sea\" for sea surface \
+                temperature, \"cloud-top\" for cloud top temperature or \"thermal\" to color \
+                every temperature. Needs the satellite name.",
+            )
+            .metavar("NAME");
+        parser
+            .refer(&mut arg_day_night)
+            .add_option(
+                &["--day-night"],
+                argparse::StoreTrue,
+                "Produce a false color image using the palette given by \"--palette\" on \
+                daylight and the one given by \"--night-palette\" at night, blending them \
+                smoothly according to the solar zenith angle of each pixel. Needs the satellite \
+                name and recording time.",
+            );
+        parser
+            .refer(&mut arg_mcir)
+            .add_option(
+                &["--mcir"],
+                argparse::StoreTrue,
+                "Map color IR, replace channel A with the clouds from channel B drawn over a land \
+                and sea background rasterized from the map shapefiles. Needs the satellite name \
+                and recording time.",
+            );
+        parser
+            .refer(&mut arg_night_palette)
+            .add_option(
+                &["--night-palette"],
+                argparse::StoreOption,
+                "Palette used at night by \"--day-night\", by default \"noaa-apt-night.png\" \
+                from the folder \"res/palettes/\".",
+            );
+        parser
+            .refer(&mut arg_georef)
+            .add_option(
+                &["--georef"],
+                argparse::StoreOption,
+                "Also save a single channel with ground control points to the given path, so it \
+                can be reprojected by GIS software like QGIS or GDAL. If the filename ends in \
+                \".tif\" a GeoTIFF is written, otherwise the image is saved with a \".aux.xml\" \
+                sidecar file. Needs the satellite name and recording time, map corrections are \
+                taken from the map options. Also see \"--georef-channel\".",
+            )
+            .metavar("FILENAME");
+        parser
+            .refer(&mut arg_georef_channel)
+            .add_option(
+                &["--georef-channel"],
+                argparse::StoreOption,
+                "Channel to save with \"--georef\" or \"--project\". Possible values: \"a\" \
+                (default) or \"b\".",
+            )
+            .metavar("CHANNEL");
+        parser
+            .refer(&mut arg_project)
+            .add_option(
+                &["--project"],
+                argparse::StoreOption,
+                "Also save a single channel reprojected to a map to the given path. If the \
+                filename ends in \".tif\" a GeoTIFF is written, otherwise the image is saved with \
+                a \".aux.xml\" sidecar file. Needs the satellite name and recording time, map \
+                corrections are taken from the map options. Also see \"--projection\", \
+                \"--bbox\", \"--resolution\" and \"--georef-channel\".",
+            )
+            .metavar("FILENAME");
+        parser
+            .refer(&mut arg_projection)
+            .add_option(
+                &["--projection"],
+                argparse::StoreOption,
+                "Projection used by \"--project\". Possible values: \"equirectangular\" \
+                (default) or \"mercator\".",
+            )
+            .metavar("PROJECTION");
+        parser
+            .refer(&mut arg_bbox)
+            .add_option(
+                &["--bbox"],
+                argparse::StoreOption,
+                "Region saved by \"--project\" in degrees, e.g. \"-80,-45,-50,-20\". By default \
+                the whole pass is saved.",
+            )
+            .metavar("WEST,SOUTH,EAST,NORTH");
+        parser
+            .refer(&mut arg_resolution)
+            .add_option(
+                &["--resolution"],
+                argparse::Store,
+                "Size of the pixels saved by \"--project\" in degrees of longitude, by default \
+                0.03.",
+            )
+            .metavar("DEGREES");
+        parser
+            .refer(&mut arg_mosaic)
+            .add_option(
+                &["--mosaic"],
+                argparse::StoreTrue,
+                "Decode several passes and blend them on a map saved to the output filename, \
+                pixels closer to the nadir of each pass are preferred. The satellite name and \
+                recording time of each file are inferred, so \"--start-time\" is not allowed. \
+                Also see \"--projection\", \"--bbox\", \"--resolution\" and \
+                \"--georef-channel\".",
+            );
         parser
             .refer(&mut arg_start_time)
             .add_option(
@@ -449,6 +869,24 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 "Profile to use, values loaded from settings file. Possible values: \"standard\", \
                 \"fast\" or \"slow\".",
             );
+        parser
+            .refer(&mut arg_threads)
+            .add_option(
+                &["--threads"],
+                argparse::StoreOption,
+                "Number of threads to use, 0 to use one per processor. Overrides the value on \
+                the settings file.",
+            )
+            .metavar("THREADS");
+        parser
+            .refer(&mut arg_tle_max_days)
+            .add_option(
+                &["--tle-max-days"],
+                argparse::StoreOption,
+                "Warn if the TLE epoch is more than this number of days away from the \
+                recording time. Overrides the value on the settings file.",
+            )
+            .metavar("DAYS");
         parser
             .refer(&mut arg_wav_steps)
             .add_option(
@@ -457,14 +895,78 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 "Export a WAV for every step of the decoding process for debugging, the files \
                 will be located on the current folder, named {number}_{description}.wav",
             );
+        parser
+            .refer(&mut arg_plot_steps)
+            .add_option(
+                &["--plot-steps"],
+                argparse::StoreTrue,
+                "Save PNG plots of every step of the decoding process for debugging: the signal \
+                over time and its spectrum, or the frequency response for filters. The files \
+                will be located on the current folder, open steps.html to see all of them.",
+            );
+        parser
+            .refer(&mut arg_float_steps)
+            .add_option(
+                &["--float-steps"],
+                argparse::StoreOption,
+                "Export every step of the decoding process as an array of 32 bit floats, as \
+                \"npy\" (NumPy array files) or \"raw\" (little endian floats without header). \
+                The files will be located on the current folder.",
+            )
+            .metavar("FORMAT");
         parser
             .refer(&mut arg_export_resample_filtered)
             .add_option(
                 &["--export-resample-filtered"],
                 argparse::StoreTrue,
-                "Export a WAV for the expanded and filtered signal on the resampling step. Very \
-                expensive operation, can take several GiB of both RAM and disk. --wav-steps should \
-                be set.",
+                "Export the expanded and filtered signal on the resampling step. Very expensive \
+                operation, can take several GiB of both RAM and disk. --wav-steps, --plot-steps or \
+                --float-steps should be set.",
+            );
+        parser
+            .refer(&mut arg_products)
+            .add_option(
+                &["--products"],
+                argparse::Store,
+                "Images to generate for each recording when watching a folder, separated by \
+                commas. Possible values: \"raw\" (default), \"false-color\", \"day-night\", \
+                \"mcir\", \"sea\", \"cloud-top\" and \"thermal\".",
+            )
+            .metavar("LIST");
+        parser
+            .refer(&mut arg_template)
+            .add_option(
+                &["--template"],
+                argparse::Store,
+                "Output filename when watching a folder, relative to the folder. \"{name}\" is \
+                replaced by the recording filename without extension, \"{product}\" by the \
+                product, \"{sat}\" by the satellite and \"{time}\" by the recording time. \
+                The default is \"{name}-{product}.png\".",
+            )
+            .metavar("TEMPLATE");
+        parser
+            .refer(&mut arg_hours)
+            .add_option(
+                &["--hours"],
+                argparse::Store,
+                "How many hours ahead to look when listing passes. The default is 24.",
+            )
+            .metavar("HOURS");
+        parser
+            .refer(&mut arg_min_elevation)
+            .add_option(
+                &["--min-elevation"],
+                argparse::Store,
+                "Skip passes whose maximum elevation in degrees is lower than this when listing \
+                passes. The default is 0.",
+            )
+            .metavar("DEGREES");
+        parser
+            .refer(&mut arg_json)
+            .add_option(
+                &["--json"],
+                argparse::StoreTrue,
+                "Print passes as JSON instead of a table.",
             );
         parser
             .refer(&mut arg_rotate_deprecated)
@@ -513,6 +1015,13 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
 
     let settings = Settings {
         export_wav: arg_wav_steps,
+        export_plots: arg_plot_steps,
+        export_float: arg_float_steps.map(|name| {
+            FloatFormat::from_name(&name).unwrap_or_else(|| {
+                println!("Invalid float steps format argument");
+                std::process::exit(0);
+            })
+        }),
         export_resample_filtered: arg_export_resample_filtered,
         work_rate: profile.work_rate as u32,
         resample_atten: profile.resample_atten as f32,
@@ -528,13 +1037,99 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
         default_states_color: de_settings.map_overlay.default_states_color,
         default_lakes_color: de_settings.map_overlay.default_lakes_color,
         default_palette_filename: res_path!("palettes", de_settings.false_color.default_palette_filename),
+        ground_station: match arg_station {
+            Some(s) => parse_ground_station(&s).unwrap_or_else(|| {
+                println!("Invalid ground station location, should be \"LAT,LON,ALT\"");
+                std::process::exit(0);
+            }),
+            None => de_settings.ground_station,
+        },
+        correct_sample_rate: arg_correct_rate,
+        threads: arg_threads.unwrap_or(de_settings.threads),
+        tle_max_epoch_days: arg_tle_max_days.unwrap_or(de_settings.tle_max_epoch_days),
     };
 
+    // Global, used by every function on the `parallel` module
+    parallel::set_threads(settings.threads);
+
+    let raw_format: Option<(RawFormat, u32)> = match arg_raw_format.as_deref() {
+        Some(name) => {
+            let format = RawFormat::from_name(name).unwrap_or_else(|| {
+                println!("Invalid raw format argument");
+                std::process::exit(0);
+            });
+            let rate = arg_raw_rate.unwrap_or_else(|| {
+                println!("The sample rate of raw samples should be given with --raw-rate");
+                std::process::exit(0);
+            });
+            Some((format, rate))
+        }
+        None => None,
+    };
+
+    // "watch FOLDER" is given as two input filenames
+    let watch_directory = match arg_input_filenames.as_slice() {
+        [command, directory] if command.as_os_str() == "watch" => Some(directory.clone()),
+        _ => None,
+    };
+
+    if let [command] = arg_input_filenames.as_slice() {
+        if command.as_os_str() == "passes" {
+            if arg_hours <= 0. {
+                println!("The number of hours should be positive");
+                std::process::exit(0);
+            }
+            return (
+                check_updates,
+                verbosity,
+                Mode::Passes {
+                    settings,
+                    custom_tle: arg_tle_filename.as_deref().map(load_custom_tle),
+                    hours: arg_hours,
+                    min_elevation: arg_min_elevation,
+                    json: arg_json,
+                },
+            );
+        }
+    }
+
+    if let [command, filenames @ ..] = arg_input_filenames.as_slice() {
+        if command.as_os_str() == "import-tle" {
+            if filenames.is_empty() {
+                println!("Give the TLE files to import");
+                std::process::exit(0);
+            }
+            return (
+                check_updates,
+                verbosity,
+                Mode::ImportTle {
+                    filenames: filenames.to_vec(),
+                },
+            );
+        }
+    }
+
+    if arg_input_filenames.len() > 1 && !arg_mosaic && watch_directory.is_none() {
+        println!("Only one input file can be given, unless using --mosaic");
+        std::process::exit(0);
+    }
+    let arg_input_filename = arg_input_filenames.first().cloned();
+
     // If set, then the program will be used as a command-line one, otherwise we
     // open the GUI
-    if let Some(input_filename) = arg_input_filename {
+    if arg_input_filename.is_some() || raw_format.is_some() {
+        // "-" means standard input
+        let input_filename = arg_input_filename.filter(|f| f.as_os_str() != "-");
+
         // If set, we are resampling, otherwise we are decoding
         if let Some(rate) = arg_resample_output {
+            let input_filename = match (input_filename, raw_format) {
+                (Some(f), None) => f,
+                _ => {
+                    println!("Resampling is only available for WAV files");
+                    std::process::exit(0);
+                }
+            };
             return (
                 check_updates,
                 verbosity,
@@ -575,13 +1170,38 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 }
             };
 
-            let color_settings = if arg_false_color {
+            let enhancement = arg_enhancement.as_deref().map(|name| {
+                Enhancement::from_name(name).unwrap_or_else(|| {
+                    println!("Invalid enhancement argument");
+                    std::process::exit(0);
+                })
+            });
+
+            let color_modes = [arg_false_color || arg_day_night, enhancement.is_some(), arg_mcir];
+            if color_modes.iter().filter(|m| **m).count() > 1 {
+                println!(
+                    "Only one of false color, a temperature enhancement or MCIR can be used at \
+                    the same time"
+                );
+                std::process::exit(0);
+            }
+
+            let night_palette_filename = if arg_day_night {
+                Some(arg_night_palette.unwrap_or_else(|| res_path!("palettes", "noaa-apt-night.png")))
+            } else {
+                None
+            };
+
+            let color_settings = if color_modes.contains(&true) {
                 Some(ColorSettings {
                     palette_filename: arg_palette.unwrap_or_else(|| settings.default_palette_filename.clone()),
                     ch_a_tune_start: 0.,
                     ch_a_tune_end: 0.,
                     ch_b_tune_start: 0.,
                     ch_b_tune_end: 0.,
+                    enhancement,
+                    night_palette_filename,
+                    mcir: arg_mcir,
                 })
             } else {
                 None
@@ -589,19 +1209,21 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
 
             let mut sat_name: Option<SatName> = None;
             let mut ref_time: Option<RefTime> = None;
-            match misc::infer_time_sat(&settings, &input_filename) {
-                Ok((time, sat)) => {
-                    sat_name = Some(sat);
-                    ref_time = Some(time);
+            if let Some(filename) = input_filename.as_ref().filter(|_| watch_directory.is_none()) {
+                match misc::infer_time_sat(&settings, filename) {
+                    Ok((time, sat)) => {
+                        sat_name = Some(sat);
+                        ref_time = Some(time);
+                    }
+                    Err(e) => println!(
+                        "Unable to determine satellite name and recording time \
+                        from filename: {}",
+                        e
+                    ),
                 }
-                Err(e) => println!(
-                    "Unable to determine satellite name and recording time \
-                    from filename: {}",
-                    e
-                ),
             }
 
-            sat_name = match arg_sat.as_deref() {
+            let arg_sat_name = match arg_sat.as_deref() {
                 Some("noaa_15") => Some(SatName::Noaa15),
                 Some("noaa_18") => Some(SatName::Noaa18),
                 Some("noaa_19") => Some(SatName::Noaa19),
@@ -609,27 +1231,13 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                     println!("Invalid provided satellite name");
                     std::process::exit(0);
                 }
-                None => sat_name, // Keep previous value
-            };
-
-            let custom_tle: Option<String> = match arg_tle_filename {
-                Some(s) => {
-                    let path = PathBuf::from(s);
-                    let mut file = File::open(path).unwrap_or_else(|e| {
-                        println!("Could not open custom TLE file: {}", e);
-                        std::process::exit(0);
-                    });
-                    let mut tle = String::new();
-                    if let Err(e) = file.read_to_string(&mut tle) {
-                        println!("Could not read custom TLE file: {}", e);
-                        std::process::exit(0);
-                    }
-
-                    Some(tle)
-                }
                 None => None,
             };
+            sat_name = arg_sat_name.clone().or(sat_name); // Otherwise keep previous value
+
+            let custom_tle: Option<String> = arg_tle_filename.as_deref().map(load_custom_tle);
 
+            let ref_time_given = arg_start_time.is_some();
             if let Some(s) = arg_start_time {
                 ref_time = Some(RefTime::Start(
                     chrono::DateTime::parse_from_rfc3339(&s)
@@ -658,6 +1266,154 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                 None => None,
             };
 
+            let channel = match arg_georef_channel.as_deref() {
+                Some("a") | None => Channel::A,
+                Some("b") => Channel::B,
+                Some(_) => {
+                    println!("Invalid georeference channel argument");
+                    std::process::exit(0);
+                }
+            };
+
+            let georef_settings = arg_georef.map(|filename| GeorefSettings {
+                filename,
+                channel,
+            });
+
+            let projection = match arg_projection.as_deref() {
+                Some(name) => Projection::from_name(name).unwrap_or_else(|| {
+                    println!("Invalid projection argument");
+                    std::process::exit(0);
+                }),
+                None => Projection::Equirectangular,
+            };
+
+            let bbox = arg_bbox.as_ref().map(|text| {
+                parse_bbox(text).unwrap_or_else(|| {
+                    println!("Invalid bounding box, should be \"WEST,SOUTH,EAST,NORTH\"");
+                    std::process::exit(0);
+                })
+            });
+
+            let projection_settings = arg_project.map(|filename| ProjectionSettings {
+                filename,
+                channel,
+                projection,
+                bbox,
+                resolution: arg_resolution,
+            });
+
+            if arg_auto_align && draw_map.is_none() {
+                println!("Can't align map if the map overlay is not enabled");
+                std::process::exit(0);
+            }
+
+            if let Some(directory) = watch_directory {
+                if ref_time_given || arg_sat_name.is_some() {
+                    println!("Can't give the satellite or recording time when watching a folder");
+                    std::process::exit(0);
+                }
+                if color_settings.is_some() {
+                    println!("Use --products to choose the images to generate when watching a folder");
+                    std::process::exit(0);
+                }
+                if georef_settings.is_some() || projection_settings.is_some() || arg_mosaic {
+                    println!("Can't use --georef, --project or --mosaic when watching a folder");
+                    std::process::exit(0);
+                }
+
+                let products = arg_products
+                    .split(',')
+                    .map(|name| {
+                        Product::from_name(name.trim()).unwrap_or_else(|| {
+                            println!("Invalid product \"{}\"", name);
+                            std::process::exit(0);
+                        })
+                    })
+                    .collect();
+
+                return (
+                    check_updates,
+                    verbosity,
+                    Mode::Watch {
+                        settings,
+                        watch_settings: WatchSettings {
+                            directory,
+                            products,
+                            template: arg_template,
+                            sync: arg_sync,
+                            contrast_adjustment,
+                            rotate,
+                            custom_tle,
+                            draw_map,
+                            auto_align: arg_auto_align,
+                        },
+                    },
+                );
+            }
+
+            if arg_mosaic {
+                if ref_time_given {
+                    println!("Can't give the recording time of several files");
+                    std::process::exit(0);
+                }
+                if georef_settings.is_some() || projection_settings.is_some() {
+                    println!("Can't use --georef or --project with --mosaic");
+                    std::process::exit(0);
+                }
+
+                let passes = arg_input_filenames
+                    .iter()
+                    .map(|filename| {
+                        let (time, sat) =
+                            misc::infer_time_sat(&settings, filename).unwrap_or_else(|e| {
+                                println!(
+                                    "Unable to determine satellite name and recording time \
+                                    of {}: {}",
+                                    filename.display(),
+                                    e
+                                );
+                                std::process::exit(0);
+                            });
+                        let orbit = OrbitSettings {
+                            sat_name: arg_sat_name.clone().unwrap_or(sat),
+                            custom_tle: custom_tle.clone(),
+                            ref_time: time,
+                            draw_map: Some(MapSettings {
+                                yaw: arg_yaw.unwrap_or(0.),
+                                hscale: arg_hscale.unwrap_or(1.),
+                                vscale: arg_vscale.unwrap_or(1.),
+                                countries_color: settings.default_countries_color,
+                                states_color: settings.default_states_color,
+                                lakes_color: settings.default_lakes_color,
+                            }),
+                            max_epoch_days: settings.tle_max_epoch_days,
+                            tle: Default::default(),
+                        };
+                        (filename.clone(), orbit)
+                    })
+                    .collect();
+
+                return (
+                    check_updates,
+                    verbosity,
+                    Mode::Mosaic {
+                        settings,
+                        passes,
+                        sync: arg_sync,
+                        contrast_adjustment,
+                        projection_settings: ProjectionSettings {
+                            filename: arg_output_filename
+                                .unwrap_or_else(|| PathBuf::from("./mosaic.png")),
+                            channel,
+                            projection,
+                            bbox,
+                            resolution: arg_resolution,
+                        },
+                    },
+                );
+            }
+
             let mut orbit_settings = None;
             if let Some(s_name) = &sat_name {
                 if let Some(r_time) = &ref_time {
@@ -666,6 +1422,8 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                         custom_tle,
                         ref_time: r_time.to_owned(),
                         draw_map: draw_map.to_owned(),
+                        max_epoch_days: settings.tle_max_epoch_days,
+                        tle: Default::default(),
                     });
                 };
             };
@@ -682,21 +1440,113 @@ pub fn get_config() -> (bool, log::LevelFilter, Mode) {
                     println!("Can't draw map if no satellite and time is provided");
                     std::process::exit(0);
                 }
+                if arg_day_night {
+                    println!("Can't blend day and night if no satellite and time is provided");
+                    std::process::exit(0);
+                }
+                if arg_mcir {
+                    println!("Can't generate MCIR image if no satellite and time is provided");
+                    std::process::exit(0);
+                }
+                if georef_settings.is_some() || projection_settings.is_some() {
+                    println!("Can't georeference if no satellite and time is provided");
+                    std::process::exit(0);
+                }
+                if arg_temperatures.is_some() || enhancement.is_some() {
+                    println!("Can't calibrate if no satellite and time is provided");
+                    std::process::exit(0);
+                }
+            }
+
+            let output_filename = arg_output_filename
+                .unwrap_or_else(|| PathBuf::from("./output.png"));
+
+            let doppler = if arg_doppler {
+                match &orbit_settings {
+                    Some(orbit) => Some(DopplerSettings {
+                        orbit: orbit.clone(),
+                        ground_station: settings.ground_station.clone(),
+                    }),
+                    None => {
+                        println!("Can't correct Doppler if no satellite and time is provided");
+                        std::process::exit(0);
+                    }
+                }
+            } else {
+                None
+            };
+
+            let iq_settings = arg_iq_format.map(|name| IqSettings {
+                format: IqFormat::from_name(&name).unwrap_or_else(|| {
+                    println!("Invalid IQ format argument");
+                    std::process::exit(0);
+                }),
+                rate: arg_iq_rate,
+                offset: arg_iq_offset,
+                doppler,
+            });
+
+            if arg_doppler && iq_settings.is_none() {
+                println!("Doppler correction is only available for IQ recordings");
+                std::process::exit(0);
+            }
+
+            if let Some((raw_format, input_rate)) = raw_format {
+                if iq_settings.is_some() {
+                    println!("Decoding IQ recordings from raw streams is not supported");
+                    std::process::exit(0);
+                }
+                if arg_auto_align {
+                    println!("Can't align map when decoding raw streams");
+                    std::process::exit(0);
+                }
+                if arg_correct_rate {
+                    println!("Can't correct the sample rate when decoding raw streams");
+                    std::process::exit(0);
+                }
+                return (
+                    check_updates,
+                    verbosity,
+                    Mode::Stream {
+                        settings,
+                        input_filename,
+                        raw_format,
+                        input_rate,
+                        output_filename,
+                        sync: arg_sync,
+                        contrast_adjustment,
+                        rotate,
+                        color_settings,
+                        orbit_settings,
+                    },
+                );
             }
 
+            let input_filename = input_filename.unwrap_or_else(|| {
+                println!("Reading from standard input is only available with --raw");
+                std::process::exit(0);
+            });
+
             return (
                 check_updates,
                 verbosity,
                 Mode::Decode {
                     settings,
                     input_filename,
-                    output_filename: arg_output_filename
-                        .unwrap_or_else(|| PathBuf::from("./output.png")),
+                    output_filename,
                     sync: arg_sync,
                     contrast_adjustment,
                     rotate,
                     color_settings,
                     orbit_settings,
+                    iq_settings,
+                    georef_settings,
+                    projection_settings,
+                    sync_report: arg_sync_report,
+                    quality_report: arg_quality_report,
+                    telemetry_report: arg_telemetry_report,
+                    temperatures_filename: arg_temperatures,
+                    auto_align: arg_auto_align,
                 },
             );
         }
@@ -1,17 +1,17 @@
 //! Contains the Context struct.
 
-use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use log::debug;
 
 use crate::decode::PX_PER_ROW;
 use crate::dsp::{Rate, Signal};
 use crate::err;
-use crate::wav;
 
 /// Different kinds of steps available.
-#[derive(Debug, PartialEq)]
-enum Variant {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
     Signal,
     Filter,
 }
@@ -60,9 +60,72 @@ impl<'a> Step<'a> {
     }
 }
 
+/// Receives the samples of each step from the `Context`.
+///
+/// The sinks on the `sinks` module save them as WAV, plots or NPY files, or
+/// keep them in memory.
+pub trait StepSink {
+    /// Receive a step. The samples are only valid during the call.
+    fn step(&mut self, info: &StepInfo, signal: &Signal) -> err::Result<()>;
+
+    /// If false the `Context` doesn't ask for intermediate signals that are
+    /// expensive to get.
+    fn enabled(&self) -> bool {
+        true
+    }
+}
+
+/// Information about a step given to a `StepSink`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepInfo {
+    pub id: &'static str,
+    pub description: &'static str,
+
+    /// Filename without extension to use when saving, starts with the step
+    /// number.
+    pub filename: &'static str,
+
+    pub variant: Variant,
+
+    /// Sample rate, always known for signals, None for filters.
+    pub rate: Option<Rate>,
+}
+
+/// Flag used to stop a running decode from another thread.
+///
+/// Clones share the same flag, so the GUI or a signal handler keeps a clone and
+/// the `Context` gets another one. Cancellation is cooperative: long loops call
+/// `Context::check_cancelled()` from time to time and return
+/// `err::Error::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask the running operation to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails with `err::Error::Cancelled` if the operation was cancelled.
+    pub fn check(&self) -> err::Result<()> {
+        if self.is_cancelled() {
+            Err(err::Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// Holds information about each step.
 struct StepMetadata {
-    #[allow(dead_code)] // For the description, maybe someday I'm going to use it
     description: &'static str,
     id: &'static str,
     filename: &'static str,
@@ -77,16 +140,16 @@ struct StepMetadata {
 ///     notifies the UI.
 ///
 /// - Manage results of each step of the decoding progress, because I want to
-///     debug every step of the decode by storing the samples as a WAV file.
+///     debug every step of the decode by saving the samples as WAV or plots.
 ///
-/// Meanwhile in the future maybe instead of saving the samples on WAV files I
-/// can plot them on the GUI. I did this because I don't want clutter every
-/// function on the `dsp` and `noaa_apt` modules with code for WAV export.
+/// I did this because I don't want clutter every function on the `dsp` and
+/// `noaa_apt` modules with code for WAV export or plotting.
 ///
 /// So every interesting function (on the `dsp`, `decode` or `noaa_apt` module)
 /// should get an instance of `Context`, and send to it results of each step
-/// (`Context.step()`). Then the `Context` will save them as WAV or do nothing
-/// depending on the user's settings.
+/// (`Context.step()`). Then the `Context` forwards them to every `StepSink`
+/// given, that can save them as WAV, as PNG plots with an HTML index, keep them
+/// on memory, etc. With no sinks nothing is done.
 ///
 /// Also the `Context` has information (`StepMetadata`) about each Step: like
 /// the filename and sample rate to use when saving to disk.
@@ -102,9 +165,7 @@ pub struct Context {
 
     /// If we are exporting something, functions like `noaa_apt::find_sync()`
     /// check this to decide if they should do things fast or they should do
-    /// extra work and save intermediate signals. Anyways, for now it's always
-    /// the same as `export_wav` because there is no implementation to export to
-    /// something else.
+    /// extra work and save intermediate signals. True if some sink is enabled.
     pub export_steps: bool,
 
     /// If we are exporting the filtered signal on resample. When using
@@ -112,12 +173,15 @@ pub struct Context {
     /// so that function checks if this variable is set before doing extra work.
     pub export_resample_filtered: bool,
 
-    /// Private field, if we are exporting to WAV.
-    export_wav: bool,
+    /// Where to send the steps.
+    sinks: Vec<Box<dyn StepSink>>,
 
     /// Current step index.
     index: usize,
 
+    /// Checked by long loops to stop early.
+    cancel_token: CancelToken,
+
     /// Callback to notify the UI
     ui_callback: Box<dyn FnMut(f32, String)>,
 }
@@ -128,9 +192,28 @@ impl Context {
         (self.ui_callback)(progress, description);
     }
 
+    /// Use the given token to know if the user cancelled the operation.
+    ///
+    /// By default the `Context` has its own token that is never cancelled.
+    pub fn set_cancel_token(&mut self, cancel_token: CancelToken) {
+        self.cancel_token = cancel_token;
+    }
+
+    /// Fails with `err::Error::Cancelled` if the user cancelled the operation.
+    ///
+    /// Cheap, can be called on every iteration of a loop.
+    pub fn check_cancelled(&self) -> err::Result<()> {
+        self.cancel_token.check()
+    }
+
+    /// Token to check from other threads, the `Context` can't be shared.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
     /// Export step.
     pub fn step(&mut self, step: Step<'_>) -> err::Result<()> {
-        if self.export_wav {
+        if self.export_steps {
             debug!("Got step: {}", step.id);
 
             // Metadata about the step we expect to receive
@@ -171,40 +254,27 @@ impl Context {
                 return Ok(());
             }
 
-            match step.variant {
-                Variant::Filter => {
-                    let writer_spec = hound::WavSpec {
-                        channels: 1,
-                        sample_rate: 1, // Could be anything
-                        bits_per_sample: 32,
-                        sample_format: hound::SampleFormat::Float,
-                    };
-
-                    let filename = PathBuf::from(metadata.filename).with_extension("wav");
-                    wav::write_wav(&filename, step.signal, writer_spec)?;
-                }
-                Variant::Signal => {
-                    let unpacked_rate = match step.rate.or(metadata.rate) {
-                        Some(r) => r,
-                        None => {
-                            return Err(err::Error::Internal(format!(
-                                "Unknown rate for step \"{}\"",
-                                step.id
-                            )))
-                        }
-                    };
-
-                    let writer_spec = hound::WavSpec {
-                        channels: 1,
-                        sample_rate: unpacked_rate.get_hz(),
-                        bits_per_sample: 32,
-                        sample_format: hound::SampleFormat::Float,
-                    };
-
-                    let filename = PathBuf::from(metadata.filename).with_extension("wav");
-                    wav::write_wav(&filename, step.signal, writer_spec)?;
+            let rate = match (step.variant, step.rate.or(metadata.rate)) {
+                (Variant::Signal, None) => {
+                    return Err(err::Error::Internal(format!(
+                        "Unknown rate for step \"{}\"",
+                        step.id
+                    )))
                 }
+                (_, rate) => rate,
             };
+
+            let info = StepInfo {
+                id: metadata.id,
+                description: metadata.description,
+                filename: metadata.filename,
+                variant: metadata.variant,
+                rate,
+            };
+
+            for sink in self.sinks.iter_mut() {
+                sink.step(&info, step.signal)?;
+            }
         }
 
         Ok(())
@@ -213,7 +283,7 @@ impl Context {
     /// Create `Context` for a resampling process.
     pub fn resample<F: FnMut(f32, String) + 'static>(
         ui_callback: F,
-        export_wav: bool,
+        sinks: Vec<Box<dyn StepSink>>,
         export_resample_filtered: bool,
     ) -> Self {
         Self {
@@ -247,10 +317,11 @@ impl Context {
                     rate: None,
                 },
             ],
-            export_steps: export_wav,
+            export_steps: sinks.iter().any(|sink| sink.enabled()),
             export_resample_filtered,
-            export_wav,
+            sinks,
             index: 0,
+            cancel_token: CancelToken::new(),
             ui_callback: Box::new(ui_callback),
         }
     }
@@ -260,7 +331,7 @@ impl Context {
         ui_callback: F,
         work_rate: Rate,
         final_rate: Rate,
-        export_wav: bool,
+        sinks: Vec<Box<dyn StepSink>>,
         export_resample_filtered: bool,
     ) -> Self {
         Self {
@@ -385,10 +456,11 @@ impl Context {
                     rate: Some(final_rate / PX_PER_ROW),
                 },
             ],
-            export_steps: export_wav,
+            export_steps: sinks.iter().any(|sink| sink.enabled()),
             export_resample_filtered,
-            export_wav,
+            sinks,
             index: 0,
+            cancel_token: CancelToken::new(),
             ui_callback: Box::new(ui_callback),
         }
     }
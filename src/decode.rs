@@ -1,12 +1,17 @@
 //! High-level function for decoding APT.
 
-use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use log::{info, warn};
 
 use crate::config;
 use crate::context::{Context, Step};
 use crate::dsp::{self, Freq, Rate, Signal};
 use crate::err;
 use crate::filters;
+use crate::quality::QualityReport;
 
 /// Final signal sample rate.
 ///
@@ -37,16 +42,129 @@ pub const PX_PER_ROW: u32 = 2080;
 /// AM carrier frequency in Hz.
 pub const CARRIER_FREQ: u32 = 2400;
 
+/// Minimum normalized correlation to consider that a sync frame was found.
+const SYNC_THRESHOLD: f32 = 0.4;
+
+/// Gain of the line tracker when correcting the row position.
+const TRACK_ALPHA: f64 = 0.2;
+
+/// Gain of the line tracker when correcting the row period.
+const TRACK_BETA: f64 = 0.02;
+
+/// Maximum relative error of the row period, given by the sample rate error of
+/// sound cards.
+const TRACK_MAX_PERIOD_ERROR: f64 = 0.01;
+
+/// Pixels around the expected position where the sync frame is searched.
+const TRACK_SEARCH_PX: usize = 20;
+
+/// Rows without sync frames before considering that the lock was lost.
+const TRACK_MAX_MISSES: usize = 40;
+
+/// Minimum number of sync frames needed to estimate the sample rate.
+const MIN_SYNC_FOR_RATE: usize = 20;
+
+/// How the position of a row was determined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RowStatus {
+    /// A sync frame was found.
+    Synced,
+
+    /// No sync frame was found near the expected position, the position was
+    /// predicted from the previous rows.
+    Interpolated,
+
+    /// There were not enough samples left to fill the row, so it is not on the
+    /// image.
+    Dropped,
+
+    /// Syncing was disabled, the position is given by the sample rate.
+    Unsynced,
+}
+
+/// Information about the sync frames of a row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowSync {
+    /// Position of the sync A frame, in samples at the work rate.
+    pub position: f64,
+
+    /// Normalized correlation of the sync A frame, from -1 to 1.
+    pub sync_a: f32,
+
+    /// Normalized correlation of the best sync B frame found near the
+    /// expected position, from -1 to 1.
+    pub sync_b: f32,
+
+    /// Distance from the expected position of the sync B frame, in samples at
+    /// the work rate. If zero, channel A and B are aligned.
+    pub sync_b_offset: i32,
+
+    /// True if the row looks like it starts with a sync B frame instead of a
+    /// sync A frame, so the channels are swapped.
+    pub swapped: bool,
+
+    pub status: RowStatus,
+}
+
+/// Per-row sync information returned by `decode()`.
+///
+/// Has one element for each row found, rows that are not dropped correspond to
+/// the rows of the image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub rows: Vec<RowSync>,
+
+    /// Error of the sample rate of the recording in ppm, estimated from the
+    /// spacing of the sync frames. Positive if the real sample rate is higher
+    /// than the nominal one.
+    pub sample_rate_error: Option<f64>,
+}
+
+impl SyncReport {
+    /// Number of rows with the given status.
+    pub fn count(&self, status: RowStatus) -> usize {
+        self.rows.iter().filter(|r| r.status == status).count()
+    }
+
+    /// Write report as CSV, one line per row.
+    pub fn write_csv(&self, filename: &Path) -> err::Result<()> {
+        let mut file = BufWriter::new(File::create(filename)?);
+
+        writeln!(file, "row,status,position,sync_a,sync_b,sync_b_offset,swapped")?;
+        for (i, row) in self.rows.iter().enumerate() {
+            writeln!(
+                file,
+                "{},{},{:.3},{:.4},{:.4},{},{}",
+                i,
+                match row.status {
+                    RowStatus::Synced => "synced",
+                    RowStatus::Interpolated => "interpolated",
+                    RowStatus::Dropped => "dropped",
+                    RowStatus::Unsynced => "unsynced",
+                },
+                row.position,
+                row.sync_a,
+                row.sync_b,
+                row.sync_b_offset,
+                row.swapped,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Decode APT image.
 ///
-/// Returns raw image data, line by line.
+/// Returns raw image data, line by line, information about the sync frames of
+/// each row and the quality of each line.
 pub fn decode(
     context: &mut Context,
     settings: &config::Settings,
     signal: &Signal,
     input_rate: Rate,
     sync: bool,
-) -> err::Result<Signal> {
+) -> err::Result<(Signal, SyncReport, QualityReport)> {
     // --------------------
 
     let final_rate = Rate::hz(FINAL_RATE);
@@ -103,13 +221,54 @@ pub fn decode(
 
     // --------------------
 
-    if sync {
+    let mut report: SyncReport;
+
+    // Get list of sync frames positions, also needed to estimate the sample
+    // rate
+    let mut sync_pos = None;
+    let mut sample_rate_error = None;
+
+    if sync || settings.correct_sample_rate {
         context.status(0.5, "Syncing".to_string());
 
-        // Get list of sync frames positions
-        let sync_pos = find_sync(context, &signal, work_rate)?;
+        let mut positions = find_sync(context, &signal, work_rate)?;
+
+        let max_residual = (TRACK_SEARCH_PX * pixel_width(work_rate)?) as f64;
+        sample_rate_error = estimate_period(&positions, max_residual)
+            .map(|period| (period / samples_per_work_row as f64 - 1.) * 1e6);
+
+        match sample_rate_error {
+            Some(ppm) => info!(
+                "Estimated sample rate: {:.2}Hz, {:+.1} ppm from nominal",
+                input_rate.get_hz() as f64 * (1. + ppm * 1e-6),
+                ppm
+            ),
+            None => info!("Not enough sync frames to estimate the sample rate"),
+        }
+
+        if settings.correct_sample_rate {
+            match sample_rate_error {
+                Some(ppm) => {
+                    context.status(0.6, format!("Correcting sample rate by {:+.1} ppm", ppm));
+
+                    // Resample so the signal is exactly at work_rate and
+                    // decode again
+                    signal = dsp::stretch(&signal, 1. + ppm * 1e-6);
+                    if sync {
+                        positions = find_sync(context, &signal, work_rate)?;
+                    }
+                }
+                None => warn!("Could not estimate the sample rate, not correcting it"),
+            }
+        }
 
-        if sync_pos.len() < 5 {
+        if sync {
+            sync_pos = Some(positions);
+        }
+    }
+
+    if let Some(sync_pos) = sync_pos {
+        if sync_pos.iter().filter(|(_, interpolated)| !interpolated).count() < 5 {
             return Err(err::Error::Internal(
                 "Found less than 5 sync frames, audio file is too short or too \
                 noisy"
@@ -117,20 +276,41 @@ pub fn decode(
             ));
         }
 
+        let mut positions: Vec<(f64, RowStatus)> = sync_pos
+            .iter()
+            .map(|&(pos, interpolated)| {
+                if interpolated {
+                    (pos, RowStatus::Interpolated)
+                } else {
+                    (pos, RowStatus::Synced)
+                }
+            })
+            .collect();
+
         // Create new "aligned" vector to samples_per_work_row. Each row starts on
-        // a found sync frame position
+        // a sync frame position, resampled by a fractional delay
         let mut aligned: Signal = Vec::new();
 
         // For each sync position
-        for i in 0..sync_pos.len() - 1 {
-            // Check if there are enough samples left to fill an image row
-            if (sync_pos[i] + samples_per_work_row as usize) < signal.len() {
-                aligned.extend_from_slice(
-                    &signal[sync_pos[i]..sync_pos[i] + samples_per_work_row as usize],
-                );
+        for (pos, status) in positions.iter_mut() {
+            // Check if there are enough samples left to fill an image row,
+            // interpolation needs two more
+            if (*pos as usize + samples_per_work_row as usize + 2) < signal.len() {
+                aligned.extend(dsp::fractional_slice(&signal, *pos, samples_per_work_row as usize));
+            } else {
+                *status = RowStatus::Dropped;
             }
         }
 
+        report = sync_report(&signal, &positions, work_rate)?;
+        info!(
+            "Rows: {} synced, {} interpolated, {} dropped, {} with channels swapped",
+            report.count(RowStatus::Synced),
+            report.count(RowStatus::Interpolated),
+            report.count(RowStatus::Dropped),
+            report.rows.iter().filter(|r| r.swapped).count(),
+        );
+
         signal = aligned;
     } else {
         context.status(0.5, "Skipping Syncing".to_string());
@@ -145,8 +325,17 @@ pub fn decode(
             / samples_per_work_row as usize // Integer division
             * samples_per_work_row as usize,
         );
+
+        let positions: Vec<(f64, RowStatus)> = (0..signal.len()
+            / samples_per_work_row as usize)
+            .map(|i| ((i * samples_per_work_row as usize) as f64, RowStatus::Unsynced))
+            .collect();
+
+        report = sync_report(&signal, &positions, work_rate)?;
     }
 
+    report.sample_rate_error = sample_rate_error;
+
     context.step(Step::signal("sync_result", &signal, Some(work_rate)))?;
 
     // --------------------
@@ -158,7 +347,16 @@ pub fn decode(
     let signal =
         dsp::resample_with_filter(context, &signal, work_rate, final_rate, filters::NoFilter)?;
 
-    Ok(signal)
+    let quality = QualityReport::new(&signal, &report)?;
+    info!(
+        "Quality score: {:.0}, {} dropouts on {} lines, median SNR: {} dB",
+        quality.summary.score,
+        quality.summary.dropouts,
+        quality.summary.lines,
+        quality.summary.snr_a.map_or("unknown".to_string(), |snr| format!("{:.1}", snr)),
+    );
+
+    Ok((signal, report, quality))
 }
 
 /// Generate sample sync frame.
@@ -168,15 +366,9 @@ pub fn decode(
 ///
 /// Used for cross correlation against the received signal to find the sync
 /// frames positions.
-fn generate_sync_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
-    if work_rate.get_hz() % FINAL_RATE != 0 {
-        return Err(err::Error::Internal(
-            "work_rate is not multiple of FINAL_RATE".to_string(),
-        ));
-    }
-
+pub fn generate_sync_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
     // Width of pixels at the work_rate.
-    let pixel_width = (work_rate.get_hz() / FINAL_RATE) as usize;
+    let pixel_width = pixel_width(work_rate)?;
 
     // Width of pulses at work_rate
     let sync_pulse_width = pixel_width * 2;
@@ -198,68 +390,368 @@ fn generate_sync_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
         .collect())
 }
 
-/// Find sync frame positions.
+/// Width of pixels in samples at the work_rate.
+fn pixel_width(work_rate: Rate) -> err::Result<usize> {
+    if work_rate.get_hz() % FINAL_RATE != 0 {
+        return Err(err::Error::Internal(
+            "work_rate is not multiple of FINAL_RATE".to_string(),
+        ));
+    }
+
+    Ok((work_rate.get_hz() / FINAL_RATE) as usize)
+}
+
+/// Generate sample sync B frame.
 ///
-/// Returns list of found sync frames positions.
-fn find_sync(context: &mut Context, signal: &Signal, work_rate: Rate) -> err::Result<Vec<usize>> {
-    let guard = generate_sync_frame(work_rate)?;
+/// Sync B is seven pulses of 832 pps after 4 pixels of black: a square wave
+/// with a pulse width of 3 pixels and period of 5 pixels. Only has values -1
+/// and 1.
+pub fn generate_sync_b_frame(work_rate: Rate) -> err::Result<Vec<i8>> {
+    let pixel_width = pixel_width(work_rate)?;
+
+    Ok((0..PX_SYNC_FRAME as usize * pixel_width)
+        .map(|i| {
+            let px = i / pixel_width;
+            if px >= 4 && (px - 4) % 5 < 3 {
+                1
+            } else {
+                -1
+            }
+        })
+        .collect())
+}
 
-    // list of maximum correlations found: (index, value)
-    let mut peaks: Vec<(usize, f32)> = Vec::new();
-    peaks.push((0, 0.));
+/// Correlation between a sync frame and the signal at some position,
+/// normalized between -1 and 1.
+pub fn normalized_correlation(signal: &Signal, pos: usize, guard: &[i8]) -> f32 {
+    let end = pos + guard.len();
+    if end > signal.len() {
+        return 0.;
+    }
+    let window = &signal[pos..end];
+
+    let mean: f32 = window.iter().sum::<f32>() / window.len() as f32;
+    let guard_mean: f32 = guard.iter().map(|&g| g as f32).sum::<f32>() / guard.len() as f32;
+
+    let mut corr: f32 = 0.;
+    let mut signal_energy: f32 = 0.;
+    let mut guard_energy: f32 = 0.;
+    for (&x, &g) in window.iter().zip(guard.iter()) {
+        let x = x - mean;
+        let g = g as f32 - guard_mean;
+        corr += x * g;
+        signal_energy += x * x;
+        guard_energy += g * g;
+    }
 
-    // Samples on each image row when at `WORK_RATE`.
-    let samples_per_work_row: u32 = PX_PER_ROW * work_rate.get_hz() / FINAL_RATE;
+    if signal_energy == 0. {
+        return 0.;
+    }
 
-    // Minimum distance between peaks, some arbitrary number smaller but close
-    // to the number of samples by line
-    let min_distance: usize = samples_per_work_row as usize * 8 / 10;
+    corr / (signal_energy * guard_energy).sqrt()
+}
 
-    // Save cross-correlation if exporting steps
-    let mut correlation = if context.export_steps {
-        Vec::with_capacity(signal.len() - guard.len())
-    } else {
-        Vec::with_capacity(0) // Not going to be used
+/// Build the sync report of each row.
+///
+/// Takes the signal at `work_rate` before aligning the rows and the row
+/// positions.
+pub fn sync_report(
+    signal: &Signal,
+    positions: &[(f64, RowStatus)],
+    work_rate: Rate,
+) -> err::Result<SyncReport> {
+    let sync_a = generate_sync_frame(work_rate)?;
+    let sync_b = generate_sync_b_frame(work_rate)?;
+
+    let pixel_width = (work_rate.get_hz() / FINAL_RATE) as i32;
+    let samples_per_channel = (PX_PER_CHANNEL * work_rate.get_hz() / FINAL_RATE) as usize;
+
+    // Look for sync B a few pixels around the expected position
+    let search = 4 * pixel_width;
+
+    let rows = positions
+        .iter()
+        .map(|&(position, status)| {
+            let start = position.round() as usize;
+            let expected_b = start + samples_per_channel;
+
+            let (sync_b_offset, sync_b_corr) = (-search..=search)
+                .filter(|&offset| expected_b as i32 + offset >= 0)
+                .map(|offset| {
+                    let pos = (expected_b as i32 + offset) as usize;
+                    (offset, normalized_correlation(signal, pos, &sync_b))
+                })
+                .fold((0, f32::NEG_INFINITY), |best, candidate| {
+                    if candidate.1 > best.1 {
+                        candidate
+                    } else {
+                        best
+                    }
+                });
+
+            let sync_a_corr = normalized_correlation(signal, start, &sync_a);
+
+            // If the frames match better when swapping the patterns, the row
+            // starts on channel B
+            let swapped = normalized_correlation(signal, start, &sync_b) > sync_a_corr
+                && normalized_correlation(signal, expected_b, &sync_a) > sync_b_corr;
+
+            RowSync {
+                position,
+                sync_a: sync_a_corr,
+                sync_b: sync_b_corr,
+                sync_b_offset,
+                swapped,
+                status,
+            }
+        })
+        .collect();
+
+    Ok(SyncReport {
+        rows,
+        sample_rate_error: None,
+    })
+}
+
+/// Estimate the samples per row from the sync frames found.
+///
+/// First I get a rough line from the medians of the distance between
+/// consecutive sync frames and of the intercepts, so rows after losing lock
+/// don't affect it. Then I fit a line by least squares to the sync frames
+/// closer than `max_residual` samples to the rough one.
+fn estimate_period(positions: &[(f64, bool)], max_residual: f64) -> Option<f64> {
+    let median = |mut values: Vec<f64>| -> Option<f64> {
+        values.sort_by(|a, b| a.total_cmp(b));
+        values.get(values.len() / 2).cloned()
     };
 
-    for i in 0..signal.len() - guard.len() {
-        let mut corr: f32 = 0.;
-        for j in 0..guard.len() {
-            match guard[j] {
-                1 => corr += signal[i + j],
-                -1 => corr -= signal[i + j],
-                _ => unreachable!(),
-            }
-        }
+    // (row, position) of each sync frame found
+    let points: Vec<(f64, f64)> = positions
+        .iter()
+        .enumerate()
+        .filter(|(_, &(_, interpolated))| !interpolated)
+        .map(|(row, &(position, _))| (row as f64, position))
+        .collect();
+
+    if points.len() < MIN_SYNC_FOR_RATE {
+        return None;
+    }
+
+    let slope = median(points.windows(2).map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0)).collect())?;
+    let intercept = median(points.iter().map(|&(row, position)| position - slope * row).collect())?;
+
+    let inliers: Vec<&(f64, f64)> = points
+        .iter()
+        .filter(|&&(row, position)| (position - (slope * row + intercept)).abs() <= max_residual)
+        .collect();
+
+    if inliers.len() < MIN_SYNC_FOR_RATE {
+        return None;
+    }
 
-        if context.export_steps {
-            correlation.push(corr);
+    let n = inliers.len() as f64;
+    let mean_row = inliers.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_position = inliers.iter().map(|p| p.1).sum::<f64>() / n;
+    let covariance: f64 = inliers.iter().map(|p| (p.0 - mean_row) * (p.1 - mean_position)).sum();
+    let variance: f64 = inliers.iter().map(|p| (p.0 - mean_row).powi(2)).sum();
+
+    Some(covariance / variance)
+}
+
+/// Tracks the position and period of the image rows.
+///
+/// Works like a second order phase locked loop (an alpha-beta filter): the
+/// position of every sync frame found corrects the estimated phase and period,
+/// when the sync frame is missing I keep going with the current period, so a
+/// fade doesn't make us lose lock.
+///
+/// Just after acquiring lock the gains are the ones of a least squares fit of
+/// the sync frames found so far, like a Kalman filter would do. So the period
+/// converges quickly and then the gains settle on `TRACK_ALPHA` and
+/// `TRACK_BETA`.
+#[derive(Clone, Debug)]
+pub struct LineTracker {
+    /// Start position of the last row, in samples.
+    phase: f64,
+
+    /// Estimated samples per row.
+    period: f64,
+
+    /// Samples per row if the sample rate were exact.
+    nominal_period: f64,
+
+    /// Number of consecutive rows without a sync frame.
+    misses: usize,
+
+    /// Number of sync frames found since acquiring lock.
+    found: usize,
+}
+
+impl LineTracker {
+    /// Start unlocked, so the first sync frame is searched on the whole first
+    /// row.
+    pub fn new(nominal_period: f64) -> LineTracker {
+        LineTracker {
+            phase: -nominal_period / 2.,
+            period: nominal_period,
+            nominal_period,
+            misses: TRACK_MAX_MISSES,
+            found: 0,
         }
+    }
+
+    /// Expected start position of the next row.
+    pub fn predict(&self) -> f64 {
+        self.phase + self.period
+    }
 
-        // If previous peak is too far, keep it and add this value to the
-        // list as a new peak
-        if i - peaks.last().unwrap().0 > min_distance {
-            // If it looks that we have too few sync frames considering the
-            // length of the signal so far
-            while i / samples_per_work_row as usize > peaks.len() {
-                peaks.push((i, corr));
+    /// Start position of the last row.
+    pub fn phase(&self) -> f64 {
+        self.phase
+    }
+
+    /// Estimated samples per row.
+    pub fn period(&self) -> f64 {
+        self.period
+    }
+
+    /// False if there were too many rows without sync frames, in that case the
+    /// next sync frame should be searched on a whole row.
+    pub fn locked(&self) -> bool {
+        self.misses < TRACK_MAX_MISSES
+    }
+
+    /// Positions where the next sync frame should be searched, from `from` to
+    /// `to` not included. Near the predicted position when locked, otherwise
+    /// on a whole row.
+    pub fn search_range(&self, pixel_width: usize) -> (usize, usize) {
+        let predicted = self.predict();
+        let search = if self.locked() {
+            (TRACK_SEARCH_PX * pixel_width) as f64
+        } else {
+            self.period / 2.
+        };
+
+        (
+            (predicted - search).max(0.).round() as usize,
+            (predicted + search).round() as usize + 1,
+        )
+    }
+
+    /// Go to the next row, given the position of the sync frame found.
+    pub fn update(&mut self, measured: Option<f64>) {
+        let predicted = self.predict();
+
+        match measured {
+            Some(measured) if self.locked() => {
+                self.found += 1;
+                let n = self.found as f64;
+                let alpha = (2. * (2. * n - 1.) / (n * (n + 1.))).max(TRACK_ALPHA);
+                let beta = (6. / (n * (n + 1.))).max(TRACK_BETA);
+
+                let error = measured - predicted;
+                self.phase = predicted + alpha * error;
+                self.period = (self.period + beta * error).clamp(
+                    self.nominal_period * (1. - TRACK_MAX_PERIOD_ERROR),
+                    self.nominal_period * (1. + TRACK_MAX_PERIOD_ERROR),
+                );
+                self.misses = 0;
+            }
+            Some(measured) => {
+                // Acquiring lock, jump directly to the sync frame
+                self.phase = measured;
+                self.misses = 0;
+                self.found = 1;
+            }
+            None => {
+                self.phase = predicted;
+                self.misses += 1;
             }
-        }
-        // Else if this value is bigger than the previous maximum, set this
-        // one
-        else if corr > peaks.last().unwrap().1 {
-            peaks.pop();
-            peaks.push((i, corr));
         }
     }
+}
+
+/// Position of the maximum of the correlation against a sync frame, searching
+/// from `from` to `to` not included.
+///
+/// If the normalized correlation given by `normalized` there looks like a sync
+/// frame, returns the fractional position fitting a parabola.
+pub fn sync_peak(
+    correlation: &[f32],
+    from: usize,
+    to: usize,
+    normalized: impl Fn(usize) -> f32,
+) -> Option<f64> {
+    (from..to.min(correlation.len()))
+        .max_by(|&a, &b| correlation[a].total_cmp(&correlation[b]))
+        .filter(|&i| normalized(i) >= SYNC_THRESHOLD)
+        .map(|i| {
+            if i == 0 || i + 1 >= correlation.len() {
+                return i as f64;
+            }
+            let (l, c, r) = (correlation[i - 1], correlation[i], correlation[i + 1]);
+            let denominator = l - 2. * c + r;
+            if denominator < 0. {
+                i as f64 + (0.5 * (l - r) / denominator).clamp(-0.5, 0.5) as f64
+            } else {
+                i as f64
+            }
+        })
+}
 
-    if context.export_steps {
-        context.step(Step::signal("sync_correlation", &correlation, None))?;
+/// Find sync frame positions.
+///
+/// The cross correlation against a sync frame is searched near the position
+/// expected by a `LineTracker`, so the positions can have a fractional part.
+///
+/// Returns the start position of each row, and if it was interpolated because
+/// no sync frame was found near there.
+pub fn find_sync(
+    context: &mut Context,
+    signal: &Signal,
+    work_rate: Rate,
+) -> err::Result<Vec<(f64, bool)>> {
+    let guard = generate_sync_frame(work_rate)?;
+    let pixel_width = pixel_width(work_rate)?;
+
+    // Samples on each image row when at `WORK_RATE`.
+    let samples_per_work_row = PX_PER_ROW as usize * pixel_width;
+
+    if signal.len() < guard.len() {
+        return Ok(Vec::new());
+    }
+
+    let template: Signal = guard.iter().map(|&g| g as f32).collect();
+    let correlation = dsp::correlate(context, signal, &template)?;
+
+    context.step(Step::signal("sync_correlation", &correlation, None))?;
+
+    let mut tracker = LineTracker::new(samples_per_work_row as f64);
+    let mut positions: Vec<(f64, bool)> = Vec::new();
+
+    loop {
+        context.check_cancelled()?;
+
+        if tracker.predict() >= correlation.len() as f64 {
+            break;
+        }
+
+        let (from, to) = tracker.search_range(pixel_width);
+        let measured = sync_peak(&correlation, from, to, |i| {
+            normalized_correlation(signal, i, &guard)
+        });
+
+        tracker.update(measured);
+        positions.push((tracker.phase().max(0.), measured.is_none()));
     }
 
-    info!("Found {} sync frames", peaks.len());
+    info!(
+        "Found {} sync frames, {:.3} samples per row",
+        positions.iter().filter(|(_, interpolated)| !interpolated).count(),
+        tracker.period(),
+    );
 
-    Ok(peaks.iter().map(|(index, _value)| *index).collect())
+    Ok(positions)
 }
 
 #[cfg(test)]
@@ -317,4 +809,105 @@ mod tests {
             generate_sync_frame(Rate::hz(FINAL_RATE * 2)).unwrap()
         );
     }
+
+    #[test]
+    fn test_sample_sync_b_frame() {
+        let frame = generate_sync_b_frame(Rate::hz(FINAL_RATE)).unwrap();
+        assert_eq!(frame.len(), PX_SYNC_FRAME as usize);
+        assert_eq!(&frame[..12], &[-1, -1, -1, -1, 1, 1, 1, -1, -1, 1, 1, 1]);
+        assert_eq!(frame.iter().filter(|&&x| x == 1).count(), 7 * 3);
+    }
+
+    /// Check sync frames detection on a synthetic row.
+    #[test]
+    fn test_sync_report() {
+        let work_rate = Rate::hz(FINAL_RATE * 2);
+        let samples_per_channel = PX_PER_CHANNEL as usize * 2;
+
+        // Some values that don't look like sync frames
+        let background = |i: usize| ((i * 7919) % 13) as f32 / 13.;
+
+        let make_row = |first: &[i8], second: &[i8], second_offset: usize| -> Signal {
+            let mut row: Signal = (0..2 * samples_per_channel).map(background).collect();
+            for (i, &v) in first.iter().enumerate() {
+                row[i] = v as f32;
+            }
+            for (i, &v) in second.iter().enumerate() {
+                row[samples_per_channel + second_offset + i] = v as f32;
+            }
+            row
+        };
+
+        let sync_a = generate_sync_frame(work_rate).unwrap();
+        let sync_b = generate_sync_b_frame(work_rate).unwrap();
+
+        let mut signal = make_row(&sync_a, &sync_b, 4);
+        signal.extend(make_row(&sync_b, &sync_a, 0));
+
+        let report = sync_report(
+            &signal,
+            &[
+                (0., RowStatus::Synced),
+                ((2 * samples_per_channel) as f64, RowStatus::Synced),
+            ],
+            work_rate,
+        )
+        .unwrap();
+
+        assert!(report.rows[0].sync_a > 0.99);
+        assert!(report.rows[0].sync_b > 0.99);
+        assert_eq!(report.rows[0].sync_b_offset, 4);
+        assert!(!report.rows[0].swapped);
+
+        assert!(report.rows[1].swapped);
+        assert_eq!(report.count(RowStatus::Synced), 2);
+    }
+
+    /// Check that the row positions follow a sample rate error and don't get
+    /// lost on a fade.
+    #[test]
+    fn test_find_sync_tracking() {
+        let work_rate = Rate::hz(FINAL_RATE * 2);
+        let period = PX_PER_ROW as f64 * 2. * 1.004;
+        let offset = 100.;
+        let rows = 60;
+        let fade = 25..35;
+
+        // Some values that don't look like sync frames
+        let mut seed: u32 = 1;
+        let mut random = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as f32 / 65536.
+        };
+
+        let mut signal: Signal = (0..(rows as f64 * period) as usize).map(|_| random()).collect();
+        let sync_a = generate_sync_frame(work_rate).unwrap();
+        for row in 0..rows {
+            if fade.contains(&row) {
+                continue;
+            }
+            let start = (offset + row as f64 * period).round() as usize;
+            for (i, &v) in sync_a.iter().enumerate() {
+                if start + i < signal.len() {
+                    signal[start + i] = v as f32 + random() * 0.6;
+                }
+            }
+        }
+
+        let positions = find_sync(
+            &mut Context::resample(|_, _| {}, Vec::new(), false), // Dummy context, not important
+            &signal,
+            work_rate,
+        )
+        .unwrap();
+
+        assert!(positions.len() >= rows - 1);
+        let estimated = estimate_period(&positions, 40.).unwrap();
+        assert!((estimated - period).abs() < 0.05, "{}", estimated);
+        for (row, &(position, interpolated)) in positions.iter().enumerate().skip(10) {
+            let error = (position - (offset + row as f64 * period)).abs();
+            assert_eq!(interpolated, fade.contains(&row));
+            assert!(error < if interpolated { 3. } else { 1.5 }, "row {}: {}", row, error);
+        }
+    }
 }
@@ -4,6 +4,8 @@ use std::convert::TryFrom;
 
 use gcd::Gcd;
 use log::{debug, error};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
 
 pub use crate::frequency::Freq;
 pub use crate::frequency::Rate;
@@ -11,10 +13,18 @@ pub use crate::frequency::Rate;
 use crate::context::{Context, Step};
 use crate::err;
 use crate::filters;
+use crate::parallel;
 
 /// Represents a signal, it's just a `Vec<f32>`.
 pub type Signal = Vec<f32>;
 
+/// Filters and templates with at least this many coefficients are convolved
+/// using FFTs, shorter ones are faster with a direct loop.
+const FFT_CONVOLUTION_MIN_LEN: usize = 64;
+
+/// Loops over samples check for cancellation once every this many samples.
+const CANCEL_CHECK_LEN: usize = 4096;
+
 /// Get biggest sample in signal.
 #[allow(dead_code)]
 pub fn get_max(vector: &Signal) -> err::Result<&f32> {
@@ -70,27 +80,14 @@ pub fn resample_with_filter(
         return Err(err::Error::Internal("Can't resample to 0Hz".to_string()));
     }
 
-    let gcd = input_rate.get_hz().gcd(output_rate.get_hz());
-    let l = output_rate.get_hz() / gcd; // interpolation factor
-    let m = input_rate.get_hz() / gcd; // decimation factor
+    let (l, m) = resampling_factors(input_rate, output_rate)?;
 
     let result;
 
     if l > 1 {
         // If we need interpolation
         // Reference the frequencies to the rate we have after interpolation
-        let interpolated_rate = input_rate.checked_mul(l).ok_or_else(|| {
-            err::Error::RateOverflow(format!(
-                "Can't resample, looks like the sample rates do not have a big
-                divisor in common. input_rate: {}, output_rate: {}, l: {}, m: {}",
-                input_rate.get_hz(),
-                output_rate.get_hz(),
-                l,
-                m
-            ))
-        })?;
-
-        filt.resample(input_rate, interpolated_rate);
+        filt.resample(input_rate, input_rate * l);
         let coeff = filt.design();
 
         context.step(Step::filter("resample_filter", &coeff))?;
@@ -125,6 +122,29 @@ pub fn resample_with_filter(
     Ok(result)
 }
 
+/// Get interpolation and decimation factors needed to resample.
+///
+/// Returns `(l, m)`. Fails if the interpolated rate (`input_rate * l`) can't
+/// be represented.
+fn resampling_factors(input_rate: Rate, output_rate: Rate) -> err::Result<(u32, u32)> {
+    let gcd = input_rate.get_hz().gcd(output_rate.get_hz());
+    let l = output_rate.get_hz() / gcd; // interpolation factor
+    let m = input_rate.get_hz() / gcd; // decimation factor
+
+    if input_rate.checked_mul(l).is_none() {
+        return Err(err::Error::RateOverflow(format!(
+            "Can't resample, looks like the sample rates do not have a big
+            divisor in common. input_rate: {}, output_rate: {}, l: {}, m: {}",
+            input_rate.get_hz(),
+            output_rate.get_hz(),
+            l,
+            m
+        )));
+    }
+
+    Ok((l, m))
+}
+
 /// Resample signal.
 ///
 /// `delta_w` is the transition band of the lowpass filter to use. `atten` is
@@ -137,6 +157,24 @@ pub fn resample(
     atten: f32,
     delta_w: Freq,
 ) -> err::Result<Signal> {
+    resample_with_filter(
+        context,
+        signal,
+        input_rate,
+        output_rate,
+        resample_lowpass(input_rate, output_rate, atten, delta_w),
+    )
+}
+
+/// Lowpass filter used by `resample()`.
+///
+/// The frequencies are referenced to the `input_rate`.
+pub fn resample_lowpass(
+    input_rate: Rate,
+    output_rate: Rate,
+    atten: f32,
+    delta_w: Freq,
+) -> filters::Lowpass {
     let cutout = if output_rate > input_rate {
         // Filter everything outside the original spectrum, so everything higher
         // than input_rate/2.
@@ -148,17 +186,11 @@ pub fn resample(
         Freq::hz(output_rate.get_hz() as f32 / 2., input_rate)
     };
 
-    resample_with_filter(
-        context,
-        signal,
-        input_rate,
-        output_rate,
-        filters::Lowpass {
-            cutout,
-            atten,
-            delta_w,
-        },
-    )
+    filters::Lowpass {
+        cutout,
+        atten,
+        delta_w,
+    }
 }
 
 /// Resample a signal using a given filter.
@@ -232,6 +264,10 @@ fn fast_resampling(
 
     // Iterate over each output sample
     while t < interpolated_len {
+        if output.len() % CANCEL_CHECK_LEN == 0 {
+            context.check_cancelled()?;
+        }
+
         // Find first n inside the window that has a input sample that I
         // should multiply with a filter coefficient
         if t > offset {
@@ -306,6 +342,69 @@ fn decimate(signal: &Signal, m: u32) -> Signal {
     decimated
 }
 
+/// Coefficients of a cubic Lagrange fractional delay filter.
+///
+/// To interpolate at `n + d`, the coefficients are for the samples `n - 1`,
+/// `n`, `n + 1` and `n + 2`.
+fn lagrange_coefficients(d: f32) -> [f32; 4] {
+    [
+        -d * (d - 1.) * (d - 2.) / 6.,
+        (d + 1.) * (d - 1.) * (d - 2.) / 2.,
+        -(d + 1.) * d * (d - 2.) / 2.,
+        (d + 1.) * d * (d - 1.) / 6.,
+    ]
+}
+
+/// Interpolate the signal at sample `n + d` given the coefficients of `d`.
+/// Samples outside the signal are taken as zero.
+fn interpolate(signal: &Signal, n: i64, coeff: &[f32; 4]) -> f32 {
+    coeff
+        .iter()
+        .enumerate()
+        .map(|(j, c)| {
+            let i = n + j as i64 - 1;
+            if i < 0 || i >= signal.len() as i64 {
+                0.
+            } else {
+                c * signal[i as usize]
+            }
+        })
+        .sum()
+}
+
+/// Take `len` samples starting from a fractional position.
+///
+/// Works as a fractional delay filter, interpolating with a cubic Lagrange
+/// polynomial. The signal should be oversampled, otherwise high frequencies
+/// get attenuated. Samples outside the signal are taken as zero.
+pub fn fractional_slice(signal: &Signal, start: f64, len: usize) -> Signal {
+    let n = start.floor();
+    // The same coefficients for every output sample because the delay is the
+    // same
+    let coeff = lagrange_coefficients((start - n) as f32);
+
+    (0..len as i64)
+        .map(|k| interpolate(signal, n as i64 + k, &coeff))
+        .collect()
+}
+
+/// Resample by a non-integer ratio, without filtering.
+///
+/// Sample `i` of the output is the input interpolated at `i * ratio`, so a
+/// ratio bigger than one gives a shorter signal. Used to correct small sample
+/// rate errors, the signal should be oversampled as in `fractional_slice()`.
+pub fn stretch(signal: &Signal, ratio: f64) -> Signal {
+    let len = ((signal.len() as f64 - 1.) / ratio).floor() as usize + 1;
+
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let n = position.floor();
+            interpolate(signal, n as i64, &lagrange_coefficients((position - n) as f32))
+        })
+        .collect()
+}
+
 /// Demodulate AM signal.
 ///
 /// Demodulate from two consecutive samples, by the calculation of:
@@ -391,28 +490,424 @@ pub fn filter(
     debug!("Filtering signal");
 
     let coeff = filter.design();
+
+    // The first sample of the signal is never used, so `output[i]` is the sum
+    // of `signal[i - j] * coeff[j]` for `j < i`
+    let mut output: Signal = vec![0.; signal.len().min(1)];
+    output.extend(convolve(context, signal.get(1..).unwrap_or(&[]), &coeff)?);
+
+    debug!("Filtering finished");
+
+    context.step(Step::filter("filter_filter", &coeff))?;
+    context.step(Step::signal("filter_result", &output, None))?;
+    Ok(output)
+}
+
+/// Convolve signal with filter coefficients.
+///
+/// The output has the same length as the signal, the first output samples are
+/// calculated as if there were zeros before the signal.
+///
+/// Uses FFTs when the filter is long, otherwise a direct loop. Both give the
+/// same result except for rounding errors.
+pub fn convolve(context: &Context, signal: &[f32], coeff: &[f32]) -> err::Result<Signal> {
+    if coeff.len() < FFT_CONVOLUTION_MIN_LEN {
+        convolve_direct(context, signal, coeff, parallel::threads())
+    } else {
+        convolve_fft(context, signal, coeff, parallel::threads())
+    }
+}
+
+/// Convolution by definition, O(N*M).
+///
+/// Each one of the `threads` calculates a range of output samples.
+fn convolve_direct(
+    context: &Context,
+    signal: &[f32],
+    coeff: &[f32],
+    threads: usize,
+) -> err::Result<Signal> {
+    let cancel_token = context.cancel_token();
+
+    let chunks = parallel::map_ranges_with_threads(
+        threads,
+        signal.len(),
+        1,
+        parallel::MIN_CHUNK,
+        |range| -> err::Result<Signal> {
+            let mut output: Signal = Vec::with_capacity(range.len());
+
+            for (k, i) in range.enumerate() {
+                if k % CANCEL_CHECK_LEN == 0 {
+                    cancel_token.check()?;
+                }
+
+                let mut sum: f32 = 0_f32;
+                for j in 0..coeff.len().min(i + 1) {
+                    sum += signal[i - j] * coeff[j];
+                }
+                output.push(sum);
+            }
+
+            Ok(output)
+        },
+    );
+
+    let mut output: Signal = Vec::with_capacity(signal.len());
+    for chunk in chunks {
+        output.extend(chunk?);
+    }
+
+    Ok(output)
+}
+
+/// Convolution using the overlap-add method, O(N*log(M)).
+///
+/// The signal is split in blocks, each block is convolved with the filter by
+/// multiplying their FFTs, and the results are added where they overlap.
+///
+/// The blocks are convolved on `threads` threads, but always added in the
+/// same order so the result doesn't depend on the number of threads.
+fn convolve_fft(
+    context: &Context,
+    signal: &[f32],
+    coeff: &[f32],
+    threads: usize,
+) -> err::Result<Signal> {
     let mut output: Signal = vec![0_f32; signal.len()];
+    if signal.is_empty() || coeff.is_empty() {
+        return Ok(output);
+    }
+
+    // Each block of the signal gives `block + coeff.len() - 1` samples, that
+    // must fit on the FFT without wrapping around
+    let fft_len = (4 * coeff.len()).next_power_of_two();
+    let block = fft_len - coeff.len() + 1;
+
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(fft_len);
+    let ifft = planner.plan_fft_inverse(fft_len);
+
+    // Spectrum of the filter, includes the 1/N normalization of the inverse
+    // FFT
+    let mut coeff_fft: Vec<Complex<f32>> = (0..fft_len)
+        .map(|i| Complex::new(coeff.get(i).cloned().unwrap_or(0.) / fft_len as f32, 0.))
+        .collect();
+    fft.process(&mut coeff_fft);
+
+    // Convolution of the block starting at `start`
+    let convolve_block = |start: usize| -> Signal {
+        let end = (start + block).min(signal.len());
+        let mut buffer: Vec<Complex<f32>> = (0..fft_len)
+            .map(|i| Complex::new(signal[start..end].get(i).cloned().unwrap_or(0.), 0.))
+            .collect();
+
+        fft.process(&mut buffer);
+        for (b, c) in buffer.iter_mut().zip(coeff_fft.iter()) {
+            *b *= c;
+        }
+        ifft.process(&mut buffer);
 
-    for i in 0..signal.len() {
-        let mut sum: f32 = 0_f32;
-        for j in 0..coeff.len() {
-            if i > j {
-                sum += signal[i - j] * coeff[j];
+        buffer.iter().map(|b| b.re).collect()
+    };
+
+    // Some blocks for every thread at a time, to keep the memory usage low
+    let starts: Vec<usize> = (0..signal.len()).step_by(block).collect();
+    for batch in starts.chunks(threads.max(1) * 4) {
+        context.check_cancelled()?;
+
+        let results = parallel::map_ranges_with_threads(threads, batch.len(), 1, 1, |range| {
+            batch[range].iter().map(|&start| convolve_block(start)).collect::<Vec<Signal>>()
+        });
+
+        for (&start, result) in batch.iter().zip(results.into_iter().flatten()) {
+            for (o, r) in output[start..].iter_mut().zip(result.iter()) {
+                *o += r;
             }
         }
-        output[i] = sum;
     }
-    debug!("Filtering finished");
 
-    context.step(Step::filter("filter_filter", &coeff))?;
-    context.step(Step::signal("filter_result", &output, None))?;
     Ok(output)
 }
 
+/// Cross correlation of a signal with a shorter template.
+///
+/// Calculated only where the template fits entirely on the signal, so
+/// `output[i]` is the sum of `template[j] * signal[i + j]`. Empty if the
+/// template is longer than the signal.
+///
+/// Uses `convolve()`, so it's fast with long templates.
+pub fn correlate(context: &Context, signal: &Signal, template: &Signal) -> err::Result<Signal> {
+    if template.is_empty() || signal.len() < template.len() {
+        return Ok(Vec::new());
+    }
+
+    let reversed: Signal = template.iter().rev().cloned().collect();
+    let mut output = convolve(context, signal, &reversed)?;
+
+    // Discard the samples where the template doesn't fit
+    output.drain(..template.len() - 1);
+
+    Ok(output)
+}
+
+/// Resampler that works on consecutive chunks of a signal.
+///
+/// Gives the same result as `resample_with_filter()` when interpolating, but
+/// the input is given by parts with `process()` and keeps only the samples
+/// needed for the next outputs. Call `finish()` when there are no more input
+/// samples to get the last outputs.
+///
+/// Uses the same algorithm as `fast_resampling()`, so take a look there to
+/// know what the letters mean. When there is no interpolation needed (L = 1)
+/// I filter like `filter()` and keep every M-th sample like `decimate()`, so
+/// the result is also the same as `resample_with_filter()`.
+pub struct StreamResampler {
+    l: u64,
+    m: u64,
+    coeff: Signal,
+
+    /// Filter delay in the n axis, half of filter width.
+    offset: u64,
+
+    /// Position of the next output sample in the n axis.
+    t: u64,
+
+    /// Input samples we still need.
+    buffer: Signal,
+
+    /// Index of the first sample in `buffer`.
+    buffer_start: u64,
+}
+
+impl StreamResampler {
+    /// Create resampler.
+    ///
+    /// The filter should have the frequencies referenced to the `input_rate`.
+    pub fn new(
+        input_rate: Rate,
+        output_rate: Rate,
+        mut filt: impl filters::Filter,
+    ) -> err::Result<StreamResampler> {
+        if output_rate.get_hz() == 0 {
+            return Err(err::Error::Internal("Can't resample to 0Hz".to_string()));
+        }
+
+        let (l, m) = resampling_factors(input_rate, output_rate)?;
+        if l > 1 {
+            filt.resample(input_rate, input_rate * l);
+        }
+        let coeff = filt.design();
+        let offset = (coeff.len() as u64 - 1) / 2;
+
+        debug!("Stream resampling by L/M: {}/{}", l, m);
+
+        Ok(StreamResampler {
+            l: l as u64,
+            m: m as u64,
+            coeff,
+            offset,
+            t: if l > 1 { offset } else { 0 },
+            buffer: Vec::new(),
+            buffer_start: 0,
+        })
+    }
+
+    /// Resample a new chunk, returns the output samples that can be
+    /// calculated so far.
+    pub fn process(&mut self, chunk: &[f32]) -> Signal {
+        self.buffer.extend_from_slice(chunk);
+        let received = self.buffer_start + self.buffer.len() as u64;
+
+        let mut output = Vec::new();
+        let first_needed = if self.l > 1 {
+            // Last input sample needed is (t + offset) / l
+            while (self.t + self.offset) / self.l < received {
+                output.push(self.calculate());
+                self.t += self.m;
+            }
+            self.t.saturating_sub(self.offset) / self.l
+        } else {
+            // `decimate()` drops the last samples if they are less than M
+            while self.t + self.m <= received {
+                output.push(self.calculate_decimation());
+                self.t += self.m;
+            }
+            self.t.saturating_sub(self.coeff.len() as u64 - 1)
+        };
+
+        // Drop samples that are not going to be used again
+        if first_needed > self.buffer_start {
+            let drop = (first_needed - self.buffer_start) as usize;
+            self.buffer.drain(..drop.min(self.buffer.len()));
+            self.buffer_start += drop as u64;
+        }
+
+        output
+    }
+
+    /// Get the last output samples, considering that there are no more input
+    /// samples.
+    pub fn finish(&mut self) -> Signal {
+        if self.l == 1 {
+            // Every output sample was calculated on `process()`
+            return Vec::new();
+        }
+
+        let interpolated_len = (self.buffer_start + self.buffer.len() as u64) * self.l;
+
+        let mut output = Vec::new();
+        while self.t < interpolated_len {
+            output.push(self.calculate());
+            self.t += self.m;
+        }
+
+        output
+    }
+
+    /// Calculate output sample at `t`, like the inner loop of
+    /// `fast_resampling()`.
+    fn calculate(&self) -> f32 {
+        let (l, offset, t) = (self.l, self.offset, self.t);
+
+        let mut n = if t > offset {
+            match (t - offset) % l {
+                0 => t - offset,
+                rem => t - offset + l - rem,
+            }
+        } else {
+            0
+        };
+
+        let mut sum = 0.;
+        let mut x = n / l;
+        while n <= t + offset {
+            if x >= self.buffer_start {
+                if let Some(sample) = self.buffer.get((x - self.buffer_start) as usize) {
+                    sum += self.coeff[(n + offset - t) as usize] * sample;
+                }
+            }
+            x += 1;
+            n += l;
+        }
+
+        sum
+    }
+
+    /// Calculate output sample at `t` when L = 1, like `filter()`.
+    fn calculate_decimation(&self) -> f32 {
+        let mut sum = 0.;
+        for j in 0..self.coeff.len().min(self.t as usize) {
+            let x = self.t - j as u64;
+            if let Some(sample) = self.buffer.get((x - self.buffer_start) as usize) {
+                sum += self.coeff[j] * sample;
+            }
+        }
+
+        sum
+    }
+}
+
+/// Filter that works on consecutive chunks of a signal.
+///
+/// Gives the same result as `filter()` but the input is given by parts.
+pub struct StreamFilter {
+    coeff: Signal,
+
+    /// Last input samples, as many as coefficients.
+    history: Signal,
+
+    /// Number of input samples received so far.
+    received: usize,
+}
+
+impl StreamFilter {
+    /// Create filter.
+    pub fn new(filter: impl filters::Filter) -> StreamFilter {
+        let coeff = filter.design();
+        StreamFilter {
+            history: Vec::with_capacity(coeff.len() * 2),
+            coeff,
+            received: 0,
+        }
+    }
+
+    /// Filter a new chunk, returns the same number of samples.
+    pub fn process(&mut self, chunk: &[f32]) -> Signal {
+        let mut output = Vec::with_capacity(chunk.len());
+
+        for sample in chunk {
+            // Like `filter()`, the first sample is never used
+            let sample = if self.received == 0 { 0. } else { *sample };
+            self.history.push(sample);
+            if self.history.len() > self.coeff.len() * 2 {
+                let drop = self.history.len() - self.coeff.len();
+                self.history.drain(..drop);
+            }
+
+            // Index of the current sample in the whole signal and in history
+            let i = self.received;
+            let last = self.history.len() - 1;
+
+            let mut sum: f32 = 0_f32;
+            for j in 0..self.coeff.len().min(i + 1) {
+                sum += self.history[last - j] * self.coeff[j];
+            }
+            output.push(sum);
+            self.received += 1;
+        }
+
+        output
+    }
+}
+
+/// AM demodulator that works on consecutive chunks of a signal.
+///
+/// Gives the same result as `demodulate()` but the input is given by parts.
+pub struct StreamDemodulator {
+    cosphi2: f32,
+    sinphi: f32,
+
+    /// Last sample of the previous chunk.
+    prev: Option<f32>,
+}
+
+impl StreamDemodulator {
+    /// Create demodulator.
+    pub fn new(carrier_freq: Freq) -> StreamDemodulator {
+        let phi = 2. * carrier_freq.get_rad();
+        StreamDemodulator {
+            cosphi2: phi.cos() * 2.,
+            sinphi: phi.sin(),
+            prev: None,
+        }
+    }
+
+    /// Demodulate a new chunk, returns the same number of samples.
+    pub fn process(&mut self, chunk: &[f32]) -> Signal {
+        let mut output = Vec::with_capacity(chunk.len());
+
+        for curr in chunk {
+            match self.prev {
+                Some(prev) => output.push(
+                    (prev.powi(2) + curr.powi(2) - (prev * curr * self.cosphi2)).sqrt()
+                        / self.sinphi,
+                ),
+                None => output.push(0.),
+            }
+            self.prev = Some(*curr);
+        }
+
+        output
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::context::CancelToken;
+    use crate::filters::Filter;
 
     /// Check that when we use strange resampling rates, the greatest common
     /// divisor between them can be too small and the calculated interpolated
@@ -420,7 +915,7 @@ mod tests {
     #[test]
     fn test_rate_overflow() {
         let result = resample_with_filter(
-            &mut Context::resample(|_, _| {}, false, false), // Dummy context, not important
+            &mut Context::resample(|_, _| {}, Vec::new(), false), // Dummy context, not important
             &vec![0.0; 1000],
             Rate::hz(99371), // Two primes as sample rates
             Rate::hz(93911),
@@ -440,7 +935,7 @@ mod tests {
     #[test]
     fn test_fast_resampling() {
         let result = fast_resampling(
-            &mut Context::resample(|_, _| {}, false, false), // Dummy context, not important
+            &mut Context::resample(|_, _| {}, Vec::new(), false), // Dummy context, not important
             &vec![0.0; 1000],                                // signal
             3,                                               // l
             2,                                               // m
@@ -457,7 +952,7 @@ mod tests {
     #[test]
     fn test_fast_resampling_short() {
         let result = fast_resampling(
-            &mut Context::resample(|_, _| {}, false, false), // Dummy context, not important
+            &mut Context::resample(|_, _| {}, Vec::new(), false), // Dummy context, not important
             &vec![0.0; 100],                                 // signal
             3,                                               // l
             2,                                               // m
@@ -466,4 +961,246 @@ mod tests {
         );
         assert!(result.is_ok());
     }
+
+    /// Check that resampling and filtering stop when the token is cancelled.
+    #[test]
+    fn test_cancel() {
+        let cancel_token = CancelToken::new();
+        let mut context = Context::resample(|_, _| {}, Vec::new(), false);
+        context.set_cancel_token(cancel_token.clone());
+
+        let signal = vec![0.0; 1000];
+        let coeff = vec![0.0; 100];
+        assert!(fast_resampling(&mut context, &signal, 3, 2, &coeff, Rate::hz(1000)).is_ok());
+
+        cancel_token.cancel();
+        assert!(matches!(
+            fast_resampling(&mut context, &signal, 3, 2, &coeff, Rate::hz(1000)),
+            Err(err::Error::Cancelled)
+        ));
+        assert!(matches!(
+            filter(&mut context, &signal, filters::NoFilter),
+            Err(err::Error::Cancelled)
+        ));
+    }
+
+    /// Check that the FFT convolution gives the same result as the direct one,
+    /// including filters longer than the signal.
+    #[test]
+    fn test_convolve() {
+        let context = Context::resample(|_, _| {}, Vec::new(), false);
+        let noise = |i: usize| ((i * 7919) % 101) as f32 / 101. - 0.5;
+
+        for &(signal_len, coeff_len) in &[(10000, 64), (10000, 301), (5000, 2000), (100, 500)] {
+            let signal: Signal = (0..signal_len).map(noise).collect();
+            let coeff: Signal = (0..coeff_len).map(|i| noise(i * 3 + 1)).collect();
+
+            let direct = convolve_direct(&context, &signal, &coeff, 4).unwrap();
+            let fft = convolve_fft(&context, &signal, &coeff, 4).unwrap();
+
+            assert_eq!(direct.len(), signal_len);
+            assert_eq!(fft.len(), signal_len);
+            for (a, b) in direct.iter().zip(fft.iter()) {
+                assert!((a - b).abs() < 1e-5 * (1. + a.abs()));
+            }
+        }
+    }
+
+    /// Check that the convolutions are bit-identical with any number of
+    /// threads.
+    #[test]
+    fn test_convolve_threads() {
+        let context = Context::resample(|_, _| {}, Vec::new(), false);
+        let signal: Signal = (0..100_000).map(|i| (i as f32 * 0.37).sin()).collect();
+        let short: Signal = (0..10).map(|i| i as f32 * 0.1).collect();
+        let long: Signal = (0..500).map(|i| (i as f32 * 0.05).cos()).collect();
+
+        let convolve_both = |threads| {
+            (
+                convolve_direct(&context, &signal, &short, threads).unwrap(),
+                convolve_fft(&context, &signal, &long, threads).unwrap(),
+            )
+        };
+
+        assert_eq!(convolve_both(1), convolve_both(7));
+    }
+
+    /// Check the cross correlation against its definition.
+    #[test]
+    fn test_correlate() {
+        let context = Context::resample(|_, _| {}, Vec::new(), false);
+        let signal: Signal = (0..3000).map(|i| (i as f32 * 0.37).sin()).collect();
+
+        for &template_len in &[10, 200] {
+            let template: Signal = (0..template_len).map(|i| (i % 7) as f32 - 3.).collect();
+
+            let expected: Signal = (0..=signal.len() - template_len)
+                .map(|i| (0..template_len).map(|j| template[j] * signal[i + j]).sum())
+                .collect();
+            let result = correlate(&context, &signal, &template).unwrap();
+
+            assert_eq!(result.len(), expected.len());
+            for (a, b) in expected.iter().zip(result.iter()) {
+                assert!((a - b).abs() < 1e-5 * (1. + a.abs()));
+            }
+        }
+
+        assert!(correlate(&context, &vec![1.; 5], &vec![1.; 10]).unwrap().is_empty());
+    }
+
+    /// Check that `fractional_slice()` interpolates cubic polynomials exactly.
+    #[test]
+    fn test_fractional_slice() {
+        let poly = |x: f64| (0.001 * x * x * x - 0.05 * x * x + x - 3.) as f32;
+        let signal: Signal = (0..50).map(|i| poly(i as f64)).collect();
+
+        assert_eq!(fractional_slice(&signal, 10., 20), signal[10..30].to_vec());
+
+        let slice = fractional_slice(&signal, 10.3, 20);
+        for (k, &y) in slice.iter().enumerate() {
+            assert!((y - poly(10.3 + k as f64)).abs() < 1e-3);
+        }
+
+        // Outside of the signal
+        assert_eq!(fractional_slice(&signal, 60.5, 3), vec![0.; 3]);
+    }
+
+    /// Check the length and values of a stretched signal.
+    #[test]
+    fn test_stretch() {
+        let signal: Signal = (0..1000).map(|i| (i as f32 * 0.01).sin()).collect();
+
+        let stretched = stretch(&signal, 1.0001);
+        assert_eq!(stretched.len(), 999);
+        for (i, &y) in stretched.iter().enumerate().skip(1).take(990) {
+            assert!((y - (i as f32 * 1.0001 * 0.01).sin()).abs() < 1e-4);
+        }
+
+        assert_eq!(stretch(&signal, 1.).len(), signal.len());
+        assert_eq!(stretch(&signal, 0.5).len(), 1999);
+    }
+
+    /// Generate some signal to test with.
+    fn test_signal(len: usize) -> Signal {
+        (0..len)
+            .map(|i| (i as f32 * 0.3).sin() + (i as f32 * 0.051).cos() * 0.5)
+            .collect()
+    }
+
+    /// Check that resampling by chunks gives the same result as resampling
+    /// the whole signal.
+    #[test]
+    fn test_stream_resampler() {
+        let signal = test_signal(5000);
+        let filt = filters::Lowpass {
+            cutout: Freq::pi_rad(0.4),
+            atten: 40.,
+            delta_w: Freq::pi_rad(0.1),
+        };
+
+        // Interpolating and decimating, only decimating by an even and by an
+        // odd factor
+        for &(input_rate, output_rate) in &[(11025, 20800), (41600, 20800), (62400, 20800)] {
+            let (input_rate, output_rate) = (Rate::hz(input_rate), Rate::hz(output_rate));
+
+            let mut resampler = StreamResampler::new(input_rate, output_rate, filt.clone()).unwrap();
+            let mut streamed = Vec::new();
+            for chunk in signal.chunks(333) {
+                streamed.extend(resampler.process(chunk));
+            }
+            streamed.extend(resampler.finish());
+
+            let expected = resample_with_filter(
+                &mut Context::resample(|_, _| {}, Vec::new(), false), // Dummy context, not important
+                &signal,
+                input_rate,
+                output_rate,
+                filt.clone(),
+            )
+            .unwrap();
+
+            assert_eq!(expected.len(), streamed.len());
+            for (a, b) in expected.iter().zip(streamed.iter()) {
+                assert!((a - b).abs() < 1e-5 * (1. + a.abs()));
+            }
+        }
+    }
+
+    /// Check that filtering and demodulating by chunks gives the same result
+    /// as working with the whole signal.
+    #[test]
+    fn test_stream_filter_demodulator() {
+        let signal = test_signal(3000);
+        let filt = filters::Lowpass {
+            cutout: Freq::pi_rad(0.2),
+            atten: 30.,
+            delta_w: Freq::pi_rad(0.05),
+        };
+        let carrier = Freq::pi_rad(0.23);
+
+        let mut stream_filter = StreamFilter::new(filt.clone());
+        let mut stream_demodulator = StreamDemodulator::new(carrier);
+        let mut streamed = Vec::new();
+        for chunk in signal.chunks(77) {
+            streamed.extend(stream_filter.process(&stream_demodulator.process(chunk)));
+        }
+
+        let mut context = Context::resample(|_, _| {}, Vec::new(), false); // Dummy context, not important
+        let demodulated = demodulate(&mut context, &signal, carrier).unwrap();
+        let expected = filter(&mut context, &demodulated, filt).unwrap();
+
+        assert_eq!(expected, streamed);
+    }
+
+    /// Filter as it was done before using `convolve()`.
+    fn filter_by_definition(signal: &Signal, coeff: &Signal) -> Signal {
+        let mut output: Signal = vec![0_f32; signal.len()];
+
+        for i in 0..signal.len() {
+            let mut sum: f32 = 0_f32;
+            for j in 0..coeff.len() {
+                if i > j {
+                    sum += signal[i - j] * coeff[j];
+                }
+            }
+            output[i] = sum;
+        }
+
+        output
+    }
+
+    /// Check that `filter()` and `StreamFilter` give the same result as the
+    /// filter loop used before, with short filters and with filters long
+    /// enough to use FFTs.
+    #[test]
+    fn test_filter_by_definition() {
+        let signal = test_signal(3000);
+
+        for &(delta_w, uses_fft) in &[(0.2, false), (0.02, true)] {
+            let filt = filters::Lowpass {
+                cutout: Freq::pi_rad(0.1),
+                atten: 40.,
+                delta_w: Freq::pi_rad(delta_w),
+            };
+            let coeff = filt.design();
+            assert_eq!(coeff.len() >= FFT_CONVOLUTION_MIN_LEN, uses_fft);
+            let expected = filter_by_definition(&signal, &coeff);
+
+            let mut stream_filter = StreamFilter::new(filt.clone());
+            let mut streamed = Vec::new();
+            for chunk in signal.chunks(77) {
+                streamed.extend(stream_filter.process(chunk));
+            }
+
+            let mut context = Context::resample(|_, _| {}, Vec::new(), false); // Dummy context, not important
+            let filtered = filter(&mut context, &signal, filt).unwrap();
+
+            assert_eq!(filtered.len(), expected.len());
+            assert_eq!(streamed.len(), expected.len());
+            for ((a, b), c) in expected.iter().zip(filtered.iter()).zip(streamed.iter()) {
+                assert!((a - b).abs() < 1e-5 * (1. + a.abs()));
+                assert!((a - c).abs() < 1e-5 * (1. + a.abs()));
+            }
+        }
+    }
 }
@@ -41,6 +41,9 @@ pub enum Error {
 
     /// Related to semantic versioning used for update checks
     SemanticVersion(String),
+
+    /// The user cancelled the operation, see `context::CancelToken`.
+    Cancelled,
 }
 
 impl std::fmt::Display for Error {
@@ -57,6 +60,7 @@ impl std::fmt::Display for Error {
             Error::InvalidInput(ref msg) => f.write_str(msg.as_str()),
             Error::FeatureNotAvailable(ref msg) => f.write_str(msg.as_str()),
             Error::SemanticVersion(ref msg) => f.write_str(msg.as_str()),
+            Error::Cancelled => f.write_str("Cancelled by user"),
         }
     }
 }
@@ -0,0 +1,629 @@
+//! Relation between pixels and geographic coordinates.
+//!
+//! Used to draw the map overlay and to export georeferenced images.
+//!
+//! The image is modeled as a swath: the rows follow the satellite track and the
+//! columns are perpendicular to it. To understand this, you should look at the
+//! illustrations on my how it works page.
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use log::info;
+
+use crate::decode::{PX_CHANNEL_IMAGE_DATA, PX_PER_CHANNEL, PX_SPACE_DATA, PX_SYNC_FRAME};
+use crate::err;
+use crate::geo;
+use crate::noaa_apt::{Image, RefTime, SatName};
+use crate::orbit;
+
+/// Column of the satellite nadir on channel A, relative to the image start.
+pub const NADIR_A: u32 = 539;
+
+/// Column of the satellite nadir on channel B, relative to the image start.
+pub const NADIR_B: u32 = NADIR_A + PX_PER_CHANNEL;
+
+/// Time between rows.
+pub fn line_duration() -> chrono::Duration {
+    chrono::Duration::milliseconds(500) // Two lines per sec
+}
+
+/// Time of the first row of an image of `height` rows.
+pub fn start_time(ref_time: &RefTime, height: u32) -> chrono::DateTime<chrono::Utc> {
+    match ref_time {
+        RefTime::Start(time) => *time,
+        RefTime::End(time) => *time - line_duration() * height as i32,
+    }
+}
+
+/// (latitude, longitude) of the satellite for `rows` rows starting at
+/// `start_time`, in radians.
+pub fn track(
+    tle: &str,
+    sat_name: &SatName,
+    start_time: chrono::DateTime<chrono::Utc>,
+    rows: u32,
+) -> err::Result<Vec<(f64, f64)>> {
+    let sat = orbit::find_sat(tle, sat_name)?;
+
+    let mut sat_positions: Vec<(f64, f64)> = Vec::with_capacity(rows as usize);
+
+    for i in 0..rows {
+        let t = start_time + line_duration() * i as i32;
+        let result = satellite::propogation::propogate_datetime(&sat, t)
+            .map_err(|_| err::Error::Internal("Could not propagate orbit".to_string()))?;
+        let gmst = satellite::propogation::gstime::gstime_datetime(t);
+        let sat_pos = satellite::transforms::eci_to_geodedic(&result.position, gmst);
+        sat_positions.push((sat_pos.latitude, sat_pos.longitude));
+    }
+
+    Ok(sat_positions)
+}
+
+/// Relation between pixels and (latitude, longitude) of an image.
+///
+/// Pixel positions are relative: `x` is the distance in pixels to the nadir
+/// column (`NADIR_A` or `NADIR_B`) and `y` is the row. Coordinates are in
+/// radians.
+pub struct Georef {
+    /// (latitude, longitude) of the satellite for each row.
+    sat_positions: Vec<(f64, f64)>,
+
+    /// Azimuth of the satellite track.
+    ref_az: f64,
+
+    /// Radians per pixel, horizontally.
+    x_res: f64,
+
+    /// Radians per pixel, vertically.
+    y_res: f64,
+
+    /// Yaw correction.
+    yaw: f64,
+}
+
+impl Georef {
+    /// Calculate satellite track for an image of `height` rows.
+    ///
+    /// `yaw`, `hscale` and `vscale` are the corrections from `MapSettings`.
+    pub fn new(
+        tle: &str,
+        sat_name: &SatName,
+        ref_time: &RefTime,
+        height: u32,
+        yaw: f64,
+        hscale: f64,
+        vscale: f64,
+    ) -> err::Result<Georef> {
+        let sat_positions = track(tle, sat_name, start_time(ref_time, height), height)?;
+
+        Georef::from_track(sat_positions, yaw, hscale, vscale)
+    }
+
+    /// Create from a known satellite track, one position per row.
+    pub fn from_track(
+        sat_positions: Vec<(f64, f64)>,
+        yaw: f64,
+        hscale: f64,
+        vscale: f64,
+    ) -> err::Result<Georef> {
+        if sat_positions.len() < 2 {
+            return Err(err::Error::Internal(
+                "Need at least two rows to georeference".to_string(),
+            ));
+        }
+
+        let start_latlon = sat_positions[0];
+        let end_latlon = *sat_positions.last().unwrap();
+        let height = sat_positions.len();
+
+        // Get image resolution (radians per pixel)
+        let y_res = geo::distance(start_latlon, end_latlon) / height as f64 / vscale;
+        let x_res = 0.0005 / hscale;
+
+        Ok(Georef {
+            ref_az: geo::azimuth(start_latlon, end_latlon),
+            sat_positions,
+            x_res,
+            y_res,
+            yaw,
+        })
+    }
+
+    /// Number of rows.
+    pub fn height(&self) -> u32 {
+        self.sat_positions.len() as u32
+    }
+
+    /// Map (latitude, longitude) to pixel coordinates, without correcting the
+    /// offset of the track.
+    fn latlon_to_track_px(&self, latlon: (f64, f64)) -> (f64, f64) {
+        // Set maximum, otherwise we get wrapping problems I do not fully
+        // understand: opposite parts of the world are mapped to the same
+        // position because of the cyclic nature of sin(), cos(), etc.
+        self.latlon_to_track_px_max(latlon, PI / 3.)
+    }
+
+    /// Like `latlon_to_track_px()`, points further than `max_distance` from
+    /// the start of the track are moved closer.
+    #[allow(non_snake_case)]
+    fn latlon_to_track_px_max(&self, latlon: (f64, f64), max_distance: f64) -> (f64, f64) {
+        let start_latlon = self.sat_positions[0];
+
+        let az = geo::azimuth(start_latlon, latlon);
+        let B = az - self.ref_az;
+
+        let c = geo::distance(latlon, start_latlon).min(max_distance);
+
+        let a = (B.cos() * c.tan()).atan();
+        let b = (B.sin() * c.sin()).asin();
+
+        let x = -b / self.x_res;
+
+        // Add the yaw correction value. I should be calculating sin(yaw) * x
+        // but yaw is always a small value.
+        let y = a / self.y_res + self.yaw * x;
+
+        (x, y)
+    }
+
+    /// Horizontal offset of the track at some row, the satellite track is not
+    /// a great circle.
+    fn x_offset(&self, y: f64) -> f64 {
+        let row = (y.max(0.) as usize).min(self.sat_positions.len() - 1);
+        self.latlon_to_track_px(self.sat_positions[row]).0
+    }
+
+    /// Map (latitude, longitude) to relative pixel coordinates.
+    pub fn latlon_to_rel_px(&self, latlon: (f64, f64)) -> (f64, f64) {
+        let (x, y) = self.latlon_to_track_px(latlon);
+        (x - self.x_offset(y), y)
+    }
+
+    /// Like `latlon_to_rel_px()`, but without moving far away points closer to
+    /// the start of the track.
+    ///
+    /// Correct only for points closer than 90 degrees to the start of the
+    /// track, like the ones inside the swath.
+    pub fn latlon_to_rel_px_unclamped(&self, latlon: (f64, f64)) -> (f64, f64) {
+        let (x, y) = self.latlon_to_track_px_max(latlon, PI / 2.);
+        (x - self.x_offset(y), y)
+    }
+
+    /// Map relative pixel coordinates to (latitude, longitude).
+    ///
+    /// Inverse of `latlon_to_rel_px()`. Solves the same right spherical
+    /// triangle where `a` and `b` are the legs and `c` the hypotenuse.
+    #[allow(non_snake_case)]
+    pub fn rel_px_to_latlon(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let x = x + self.x_offset(y);
+
+        let a = (y - self.yaw * x) * self.y_res;
+        let b = -x * self.x_res;
+
+        let c = (a.cos() * b.cos()).clamp(-1., 1.).acos();
+        if c == 0. {
+            return self.sat_positions[0];
+        }
+
+        let B = (b.sin() / c.sin()).atan2(a.tan() / c.tan());
+
+        geo::reckon(self.sat_positions[0], c, self.ref_az + B)
+    }
+}
+
+/// Ground control point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gcp {
+    /// Column, from the left of the image.
+    pub pixel: f64,
+
+    /// Row, from the top of the image.
+    pub line: f64,
+
+    /// Latitude in degrees.
+    pub lat: f64,
+
+    /// Longitude in degrees.
+    pub lon: f64,
+}
+
+/// Available APT channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    A,
+    B,
+}
+
+/// Get ground control points for a single channel image.
+///
+/// Uses a grid of about `step` pixels, including the borders. If `rotated` is
+/// true, the image was rotated 180 degrees.
+pub fn channel_gcps(georef: &Georef, rotated: bool, step: u32) -> Vec<Gcp> {
+    let width = PX_CHANNEL_IMAGE_DATA;
+    let height = georef.height();
+
+    // Nadir column relative to the channel image data
+    let nadir = (NADIR_A - PX_SYNC_FRAME - PX_SPACE_DATA) as f64;
+
+    let positions = |len: u32| -> Vec<u32> {
+        let mut p: Vec<u32> = (0..len).step_by(step as usize).collect();
+        if *p.last().unwrap() != len - 1 {
+            p.push(len - 1);
+        }
+        p
+    };
+
+    let mut gcps = Vec::new();
+    for row in positions(height) {
+        for col in positions(width) {
+            let (lat, lon) = georef.rel_px_to_latlon((col as f64 - nadir, row as f64));
+            let (pixel, line) = if rotated {
+                (width - 1 - col, height - 1 - row)
+            } else {
+                (col, row)
+            };
+            gcps.push(Gcp {
+                // GDAL uses the corner of the pixel as the origin, I want the
+                // center
+                pixel: pixel as f64 + 0.5,
+                line: line as f64 + 0.5,
+                lat: lat.to_degrees(),
+                lon: lon.to_degrees(),
+            });
+        }
+    }
+
+    gcps
+}
+
+/// Get only the image data of a channel.
+///
+/// Works also on rotated images, `processing::rotate()` keeps the channels in
+/// place.
+pub fn crop_channel(img: &Image, channel: Channel) -> Image {
+    let start = match channel {
+        Channel::A => PX_SYNC_FRAME + PX_SPACE_DATA,
+        Channel::B => PX_PER_CHANNEL + PX_SYNC_FRAME + PX_SPACE_DATA,
+    };
+
+    image::imageops::crop_imm(img, start, 0, PX_CHANNEL_IMAGE_DATA, img.height()).to_image()
+}
+
+/// Write a georeferenced image.
+///
+/// If the filename ends in `.tif` or `.tiff` a GeoTIFF with the ground control
+/// points as tiepoints is written. Otherwise the image is saved with the
+/// `image` crate and the ground control points are written to a `.aux.xml`
+/// sidecar that GDAL (and QGIS) read automatically.
+pub fn write_georeferenced(img: &Image, gcps: &[Gcp], filename: &Path) -> err::Result<()> {
+    let extension = filename
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match extension.as_deref() {
+        Some("tif") | Some("tiff") => {
+            info!("Writing GeoTIFF to {}", filename.display());
+            write_geotiff(img, gcps, filename)
+        }
+        _ => {
+            img.save(filename)?;
+
+            let mut aux_filename = filename.as_os_str().to_owned();
+            aux_filename.push(".aux.xml");
+            info!("Writing ground control points to {:?}", aux_filename);
+            write_aux_xml(gcps, Path::new(&aux_filename))
+        }
+    }
+}
+
+/// Write GDAL PAM sidecar file with a GCP list.
+fn write_aux_xml(gcps: &[Gcp], filename: &Path) -> err::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+
+    writeln!(file, "<PAMDataset>")?;
+    writeln!(file, "  <GCPList Projection=\"EPSG:4326\">")?;
+    for (i, gcp) in gcps.iter().enumerate() {
+        writeln!(
+            file,
+            "    <GCP Id=\"{}\" Pixel=\"{:.3}\" Line=\"{:.3}\" X=\"{:.6}\" Y=\"{:.6}\" Z=\"0\" />",
+            i + 1,
+            gcp.pixel,
+            gcp.line,
+            gcp.lon,
+            gcp.lat
+        )?;
+    }
+    writeln!(file, "  </GCPList>")?;
+    writeln!(file, "</PAMDataset>")?;
+
+    Ok(())
+}
+
+/// TIFF field types.
+const TIFF_SHORT: u16 = 3;
+const TIFF_LONG: u16 = 4;
+const TIFF_DOUBLE: u16 = 12;
+
+/// GeoKeyDirectory for WGS84 latitude and longitude.
+#[rustfmt::skip]
+pub const GEOKEYS_WGS84: [u16; 16] = [
+    1, 1, 0, 3,       // Version, revision, minor revision, number of keys
+    1024, 0, 1, 2,    // GTModelTypeGeoKey: Geographic
+    1025, 0, 1, 1,    // GTRasterTypeGeoKey: PixelIsArea
+    2048, 0, 1, 4326, // GeographicTypeGeoKey: WGS84
+];
+
+/// Tiepoints of the ground control points, as expected by
+/// `write_geotiff_tags()`.
+pub fn gcp_tiepoints(gcps: &[Gcp]) -> Vec<f64> {
+    gcps.iter()
+        .flat_map(|g| vec![g.pixel, g.line, 0., g.lon, g.lat, 0.])
+        .collect()
+}
+
+/// Write GeoTIFF with ground control points as tiepoints.
+///
+/// When a GeoTIFF has several tiepoints and no pixel scale, GDAL reads them as
+/// ground control points.
+fn write_geotiff(img: &Image, gcps: &[Gcp], filename: &Path) -> err::Result<()> {
+    write_geotiff_tags(
+        Raster::Rgb(img),
+        &gcp_tiepoints(gcps),
+        None,
+        &GEOKEYS_WGS84,
+        filename,
+    )
+}
+
+/// Pixel data to save on a TIFF.
+pub enum Raster<'a> {
+    Rgb(&'a Image),
+
+    /// Single band of floats, row by row.
+    Float {
+        data: &'a [f32],
+        width: u32,
+        height: u32,
+    },
+}
+
+/// Round a file offset up to the next word boundary.
+fn word_align(offset: u32) -> u32 {
+    offset + offset % 2
+}
+
+/// Write the zero needed to reach `word_align(offset)`, if any.
+fn write_padding<W: Write>(file: &mut W, offset: u32) -> err::Result<()> {
+    if offset % 2 == 1 {
+        file.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// Write GeoTIFF, without compression.
+///
+/// The `image` crate can't write the GeoTIFF tags, so I write the whole file.
+/// `tiepoints` has six values per tiepoint (I, J, K, X, Y, Z) and
+/// `pixel_scale` is the optional ModelPixelScale tag.
+pub fn write_geotiff_tags(
+    raster: Raster<'_>,
+    tiepoints: &[f64],
+    pixel_scale: Option<[f64; 3]>,
+    geokeys: &[u16],
+    filename: &Path,
+) -> err::Result<()> {
+    let (data, width, height, samples): (Vec<u8>, u32, u32, u32) = match raster {
+        Raster::Rgb(img) => {
+            let rgb = image::DynamicImage::ImageRgba8(img.clone()).into_rgb8();
+            (rgb.as_raw().clone(), rgb.width(), rgb.height(), 3)
+        }
+        Raster::Float {
+            data,
+            width,
+            height,
+        } => (
+            data.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect(),
+            width,
+            height,
+            1,
+        ),
+    };
+
+    // Layout: header, image data, values that don't fit in the IFD entries,
+    // IFD. Every offset should be on a word boundary.
+    let data_offset: u32 = 8;
+    let data_end = data_offset + data.len() as u32;
+    let bits_offset = word_align(data_end);
+    let bits_end = bits_offset + 3 * 2;
+    let scale_offset = word_align(bits_end);
+    let scale: Vec<f64> = pixel_scale.iter().flatten().cloned().collect();
+    let scale_end = scale_offset + scale.len() as u32 * 8;
+    let tiepoints_offset = word_align(scale_end);
+    let tiepoints_end = tiepoints_offset + tiepoints.len() as u32 * 8;
+    let geokeys_offset = word_align(tiepoints_end);
+    let geokeys_end = geokeys_offset + geokeys.len() as u32 * 2;
+    let ifd_offset = word_align(geokeys_end);
+
+    // (tag, type, count, value or offset), sorted by tag
+    #[rustfmt::skip]
+    let mut entries: Vec<(u16, u16, u32, u32)> = if samples == 3 {
+        vec![
+            (256, TIFF_LONG, 1, width),                  // ImageWidth
+            (257, TIFF_LONG, 1, height),                 // ImageLength
+            (258, TIFF_SHORT, 3, bits_offset),           // BitsPerSample
+            (259, TIFF_SHORT, 1, 1),                     // Compression: None
+            (262, TIFF_SHORT, 1, 2),                     // Photometric: RGB
+            (273, TIFF_LONG, 1, data_offset),            // StripOffsets
+            (277, TIFF_SHORT, 1, 3),                     // SamplesPerPixel
+            (278, TIFF_LONG, 1, height),                 // RowsPerStrip
+            (279, TIFF_LONG, 1, data.len() as u32),      // StripByteCounts
+            (284, TIFF_SHORT, 1, 1),                     // PlanarConfiguration
+        ]
+    } else {
+        vec![
+            (256, TIFF_LONG, 1, width),                  // ImageWidth
+            (257, TIFF_LONG, 1, height),                 // ImageLength
+            (258, TIFF_SHORT, 1, 32),                    // BitsPerSample
+            (259, TIFF_SHORT, 1, 1),                     // Compression: None
+            (262, TIFF_SHORT, 1, 1),                     // Photometric: BlackIsZero
+            (273, TIFF_LONG, 1, data_offset),            // StripOffsets
+            (277, TIFF_SHORT, 1, 1),                     // SamplesPerPixel
+            (278, TIFF_LONG, 1, height),                 // RowsPerStrip
+            (279, TIFF_LONG, 1, data.len() as u32),      // StripByteCounts
+            (284, TIFF_SHORT, 1, 1),                     // PlanarConfiguration
+            (339, TIFF_SHORT, 1, 3),                     // SampleFormat: IEEE float
+        ]
+    };
+    if !scale.is_empty() {
+        entries.push((33550, TIFF_DOUBLE, 3, scale_offset)); // ModelPixelScale
+    }
+    entries.push((33922, TIFF_DOUBLE, tiepoints.len() as u32, tiepoints_offset)); // ModelTiepoint
+    entries.push((34735, TIFF_SHORT, geokeys.len() as u32, geokeys_offset)); // GeoKeyDirectory
+
+    let mut file = BufWriter::new(File::create(filename)?);
+
+    file.write_all(b"II")?;
+    file.write_all(&42_u16.to_le_bytes())?;
+    file.write_all(&ifd_offset.to_le_bytes())?;
+    file.write_all(&data)?;
+    write_padding(&mut file, data_end)?;
+    for _ in 0..3 {
+        file.write_all(&8_u16.to_le_bytes())?;
+    }
+    write_padding(&mut file, bits_end)?;
+    for value in &scale {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    write_padding(&mut file, scale_end)?;
+    for value in tiepoints {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    write_padding(&mut file, tiepoints_end)?;
+    for value in geokeys {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    write_padding(&mut file, geokeys_end)?;
+
+    file.write_all(&(entries.len() as u16).to_le_bytes())?;
+    for (tag, field_type, count, value) in entries {
+        file.write_all(&tag.to_le_bytes())?;
+        file.write_all(&field_type.to_le_bytes())?;
+        file.write_all(&count.to_le_bytes())?;
+        if field_type == TIFF_SHORT && count == 1 {
+            // Short values are left justified
+            file.write_all(&(value as u16).to_le_bytes())?;
+            file.write_all(&0_u16.to_le_bytes())?;
+        } else {
+            file.write_all(&value.to_le_bytes())?;
+        }
+    }
+    file.write_all(&0_u32.to_le_bytes())?; // No more IFDs
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::convert::TryInto;
+
+    /// Track going North along a meridian.
+    fn test_georef() -> Georef {
+        let track = (0..200)
+            .map(|i| {
+                (
+                    (-30. + i as f64 * 0.03).to_radians(),
+                    (-60_f64).to_radians(),
+                )
+            })
+            .collect();
+        Georef::from_track(track, 0.01, 1.1, 0.9).unwrap()
+    }
+
+    /// Check that `rel_px_to_latlon()` is the inverse of `latlon_to_rel_px()`.
+    #[test]
+    fn test_px_latlon_roundtrip() {
+        let georef = test_georef();
+
+        for &(x, y) in &[
+            (0., 0.),
+            (-400., 10.),
+            (300., 150.),
+            (450., 199.),
+            (-20., 77.),
+        ] {
+            let latlon = georef.rel_px_to_latlon((x, y));
+            let (x2, y2) = georef.latlon_to_rel_px(latlon);
+            assert!((x - x2).abs() < 1e-3, "x: {} != {}", x, x2);
+            assert!((y - y2).abs() < 1e-3, "y: {} != {}", y, y2);
+        }
+    }
+
+    /// Check the nadir of the first rows.
+    #[test]
+    fn test_nadir() {
+        let georef = test_georef();
+        let (lat, lon) = georef.rel_px_to_latlon((0., 0.));
+        assert!((lat.to_degrees() + 30.).abs() < 1e-6);
+        assert!((lon.to_degrees() + 60.).abs() < 1e-6);
+    }
+
+    /// The RGB strip of a 1x1 image is 3 bytes long, check that every offset
+    /// after it is still on a word boundary and that the values can be read
+    /// back.
+    #[test]
+    fn test_geotiff_word_aligned() {
+        let filename =
+            std::env::temp_dir().join(format!("noaa-apt-{}-aligned.tif", std::process::id()));
+        let img = Image::from_pixel(1, 1, image::Rgba([10, 20, 30, 255]));
+        let tiepoints = [0., 0., 0., -60., -30., 0.];
+        let pixel_scale = [0.1, 0.1, 0.];
+        let geokeys = [1, 1, 0, 1, 1024, 0, 1, 2];
+        write_geotiff_tags(
+            Raster::Rgb(&img),
+            &tiepoints,
+            Some(pixel_scale),
+            &geokeys,
+            &filename,
+        )
+        .unwrap();
+        let bytes = std::fs::read(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let f64_at = |i: usize| f64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        let ifd_offset = u32_at(4) as usize;
+        assert_eq!(ifd_offset % 2, 0);
+        assert_eq!(&bytes[8..11], &[10, 20, 30]);
+
+        for entry in 0..u16_at(ifd_offset) as usize {
+            let entry_offset = ifd_offset + 2 + entry * 12;
+            let (tag, field_type) = (u16_at(entry_offset), u16_at(entry_offset + 2));
+            let count = u32_at(entry_offset + 4) as usize;
+            let value = u32_at(entry_offset + 8) as usize;
+            let size = match field_type {
+                TIFF_SHORT => 2,
+                TIFF_LONG => 4,
+                TIFF_DOUBLE => 8,
+                _ => panic!("Unexpected field type {}", field_type),
+            };
+            if size * count > 4 {
+                assert_eq!(value % 2, 0, "Tag {} at odd offset {}", tag, value);
+            }
+            match tag {
+                258 => assert_eq!(u16_at(value), 8),
+                33550 => assert_eq!(f64_at(value), pixel_scale[0]),
+                33922 => assert_eq!(f64_at(value + 3 * 8), tiepoints[3]),
+                34735 => assert_eq!(u16_at(value + 4 * 2), geokeys[4]),
+                _ => {}
+            }
+        }
+    }
+}
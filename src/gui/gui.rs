@@ -113,6 +113,7 @@ fn create_window(check_updates: bool, settings: config::Settings, application: &
         settings,
         decoded_signal: None,
         processed_image: None,
+        cancel_token: None,
     });
 
     // Connect close button
@@ -343,6 +344,9 @@ fn init_widgets(widgets: &Widgets) {
     widgets
         .dec_decode_button
         .connect_clicked(|_| work::decode());
+    widgets
+        .dec_cancel_button
+        .connect_clicked(|_| work::cancel_decode());
     widgets
         .p_process_button
         .connect_clicked(|_| work::process());
@@ -356,6 +360,9 @@ fn init_widgets(widgets: &Widgets) {
     widgets
         .ts_write_button
         .connect_clicked(|_| work::write_timestamp());
+    widgets
+        .pas_predict_button
+        .connect_clicked(|_| work::predict_passes());
 
     // Connect all widgets to process if the auto-update flag is set
 
@@ -391,6 +398,8 @@ fn init_widgets(widgets: &Widgets) {
         .connect_value_changed(|_| work::process_if_auto_update_enabled());
     widgets.p_overlay_check
         .connect_toggled(|_| work::process_if_auto_update_enabled());
+    widgets.p_align_check
+        .connect_toggled(|_| work::process_if_auto_update_enabled());
     widgets.p_countries_color
         .connect_color_set(|_| work::process_if_auto_update_enabled());
     widgets.p_states_color
@@ -426,6 +435,7 @@ fn dec_ready() {
         widgets.dec_action.set_enabled(false);
         widgets.res_action.set_enabled(true);
         widgets.ts_action.set_enabled(true);
+        widgets.pas_action.set_enabled(true);
 
         // Show widgets
         widgets
@@ -448,6 +458,7 @@ fn res_ready() {
         widgets.dec_action.set_enabled(true);
         widgets.res_action.set_enabled(false);
         widgets.ts_action.set_enabled(true);
+        widgets.pas_action.set_enabled(true);
 
         // Show widgets
         widgets
@@ -469,6 +480,7 @@ fn ts_ready() {
         widgets.dec_action.set_enabled(true);
         widgets.res_action.set_enabled(true);
         widgets.ts_action.set_enabled(false);
+        widgets.pas_action.set_enabled(true);
 
         // Show widgets
         widgets
@@ -481,6 +493,28 @@ fn ts_ready() {
     });
 }
 
+/// Show widgets as ready for predicting passes.
+///
+/// Called every time the user selects the passes action on the menu bar.
+fn pas_ready() {
+    borrow_widgets(|widgets| {
+        // Set enabled actions on the menu bar
+        widgets.dec_action.set_enabled(true);
+        widgets.res_action.set_enabled(true);
+        widgets.ts_action.set_enabled(true);
+        widgets.pas_action.set_enabled(false);
+
+        // Show widgets
+        widgets
+            .main_stack
+            .set_visible_child(&widgets.pas_stack_child);
+
+        // Configure widgets
+        misc::set_progress(0., "Ready");
+        misc::update_image();
+    });
+}
+
 /// Build menu bar
 fn build_system_menu(widgets: &Widgets) {
     // Create menu bar
@@ -492,6 +526,7 @@ fn build_system_menu(widgets: &Widgets) {
     tools_menu.append(Some("_Decode"), Some("app.decode"));
     tools_menu.append(Some("_Resample WAV"), Some("app.resample"));
     tools_menu.append(Some("_Timestamp WAV"), Some("app.timestamp"));
+    tools_menu.append(Some("_Passes"), Some("app.passes"));
     menu_bar.append_submenu(Some("_Tools"), &tools_menu);
 
     help_menu.append(Some("_Usage"), Some("app.usage"));
@@ -506,10 +541,12 @@ fn build_system_menu(widgets: &Widgets) {
     widgets.dec_action.connect_activate(move |_, _| dec_ready());
     widgets.res_action.connect_activate(move |_, _| res_ready());
     widgets.ts_action.connect_activate(move |_, _| ts_ready());
+    widgets.pas_action.connect_activate(move |_, _| pas_ready());
 
     widgets.application.add_action(&widgets.dec_action);
     widgets.application.add_action(&widgets.res_action);
     widgets.application.add_action(&widgets.ts_action);
+    widgets.application.add_action(&widgets.pas_action);
 
     let usage = gio::SimpleAction::new("usage", None);
     let w = widgets.window.clone();
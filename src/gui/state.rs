@@ -16,6 +16,7 @@ use std::cell::RefCell;
 use gtk::prelude::*;
 
 use crate::config::Settings;
+use crate::context::CancelToken;
 use crate::dsp::Signal;
 use crate::noaa_apt::Image;
 
@@ -119,6 +120,9 @@ pub struct GuiState {
     pub settings:                  Settings,
     pub decoded_signal:            Option<Signal>,
     pub processed_image:           Option<Image>,
+
+    /// Token of the decode running, if any.
+    pub cancel_token:              Option<CancelToken>,
 }
 
 /// Contains references to widgets and some fixed objects.
@@ -142,6 +146,7 @@ pub struct Widgets {
     pub dec_action:                gio::SimpleAction,
     pub res_action:                gio::SimpleAction,
     pub ts_action:                 gio::SimpleAction,
+    pub pas_action:                gio::SimpleAction,
 
     pub info_bar:                  gtk::InfoBar,
     pub info_label:                gtk::Label,
@@ -159,12 +164,14 @@ pub struct Widgets {
     pub dec_stack_child:           gtk::Notebook,
     pub res_stack_child:           gtk::Box,
     pub ts_stack_child:            gtk::Box,
+    pub pas_stack_child:           gtk::Box,
 
     pub dec_input_chooser:         gtk::FileChooserButton,
     pub dec_sync_check:            gtk::CheckButton,
     pub dec_wav_steps_check:       gtk::CheckButton,
     pub dec_resample_step_check:   gtk::CheckButton,
     pub dec_decode_button:         gtk::Button,
+    pub dec_cancel_button:         gtk::Button,
 
     pub p_contrast_combo:          gtk::ComboBoxText,
     pub p_rotate_combo:            gtk::ComboBoxText,
@@ -184,6 +191,7 @@ pub struct Widgets {
     pub p_timezone_label:          gtk::Label,
     pub p_calendar:                gtk::Calendar,
     pub p_overlay_check:           gtk::CheckButton,
+    pub p_align_check:             gtk::CheckButton,
     pub p_countries_color:         gtk::ColorButton,
     pub p_states_color:            gtk::ColorButton,
     pub p_lakes_color:             gtk::ColorButton,
@@ -220,6 +228,11 @@ pub struct Widgets {
     pub ts_sec_spinner:            gtk::SpinButton,
     pub ts_timezone_label:         gtk::Label,
     pub ts_calendar:               gtk::Calendar,
+
+    pub pas_hours_spinner:         gtk::SpinButton,
+    pub pas_min_elevation_spinner: gtk::SpinButton,
+    pub pas_predict_button:        gtk::Button,
+    pub pas_text_view:             gtk::TextView,
 }
 
 #[rustfmt::skip]
@@ -239,6 +252,7 @@ impl Widgets {
             dec_action:               gio::SimpleAction::new("decode", None),
             res_action:               gio::SimpleAction::new("resample", None),
             ts_action:                gio::SimpleAction::new("timestamp", None),
+            pas_action:               gio::SimpleAction::new("passes", None),
 
             info_bar:                 gtk::InfoBar::new(),
             info_label:               gtk::Label::new(None),
@@ -257,12 +271,14 @@ impl Widgets {
             dec_stack_child:          builder.object("dec_stack_child"         ).expect("Couldn't get dec_stack_child"         ),
             res_stack_child:          builder.object("res_stack_child"         ).expect("Couldn't get res_stack_child"         ),
             ts_stack_child:           builder.object("ts_stack_child"          ).expect("Couldn't get ts_stack_child"          ),
+            pas_stack_child:          builder.object("pas_stack_child"         ).expect("Couldn't get pas_stack_child"         ),
 
             dec_input_chooser:        builder.object("dec_input_chooser"       ).expect("Couldn't get dec_input_chooser"       ),
             dec_sync_check:           builder.object("dec_sync_check"          ).expect("Couldn't get dec_sync_check"          ),
             dec_wav_steps_check:      builder.object("dec_wav_steps_check"     ).expect("Couldn't get dec_wav_steps_check"     ),
             dec_resample_step_check:  builder.object("dec_resample_step_check" ).expect("Couldn't get dec_resample_step_check" ),
             dec_decode_button:        builder.object("dec_decode_button"       ).expect("Couldn't get dec_decode_button"       ),
+            dec_cancel_button:        builder.object("dec_cancel_button"       ).expect("Couldn't get dec_cancel_button"       ),
 
             p_contrast_combo:         builder.object("p_contrast_combo"        ).expect("Couldn't get p_contrast_combo"        ),
             p_false_color_check:      builder.object("p_false_color_check"     ).expect("Couldn't get p_false_color_check"     ),
@@ -282,6 +298,7 @@ impl Widgets {
             p_timezone_label:         builder.object("p_timezone_label"        ).expect("Couldn't get p_timezone_label"        ),
             p_calendar:               builder.object("p_calendar"              ).expect("Couldn't get p_calendar"              ),
             p_overlay_check:          builder.object("p_overlay_check"         ).expect("Couldn't get p_overlay_check"         ),
+            p_align_check:            builder.object("p_align_check"           ).expect("Couldn't get p_align_check"           ),
             p_countries_color:        builder.object("p_countries_color"       ).expect("Couldn't get p_countries_color"       ),
             p_states_color:           builder.object("p_states_color"          ).expect("Couldn't get p_states_color"          ),
             p_lakes_color:            builder.object("p_lakes_color"           ).expect("Couldn't get p_lakes_color"           ),
@@ -318,6 +335,11 @@ impl Widgets {
             ts_sec_spinner:           builder.object("ts_sec_spinner"          ).expect("Couldn't get ts_sec_spinner"          ),
             ts_timezone_label:        builder.object("ts_timezone_label"       ).expect("Couldn't get ts_timezone_label"       ),
             ts_calendar:              builder.object("ts_calendar"             ).expect("Couldn't get ts_calendar"             ),
+
+            pas_hours_spinner:        builder.object("pas_hours_spinner"       ).expect("Couldn't get pas_hours_spinner"       ),
+            pas_min_elevation_spinner: builder.object("pas_min_elevation_spinner").expect("Couldn't get pas_min_elevation_spinner"),
+            pas_predict_button:       builder.object("pas_predict_button"      ).expect("Couldn't get pas_predict_button"      ),
+            pas_text_view:            builder.object("pas_text_view"           ).expect("Couldn't get pas_text_view"           ),
         }
 
     }
@@ -15,12 +15,22 @@ use log::error;
 
 use super::misc;
 use super::state::{borrow_state, borrow_state_mut, borrow_widgets};
-use crate::context::Context;
+use crate::context::{CancelToken, Context, StepSink};
 use crate::dsp::{Rate, Signal};
 use crate::err;
 use crate::noaa_apt::{
     self, ColorSettings, Contrast, Image, MapSettings, OrbitSettings, RefTime, Rotate, SatName,
 };
+use crate::sinks::WavSink;
+
+/// Sinks for the steps, the GUI only has a checkbox for WAV steps.
+fn wav_sinks(wav_steps: bool) -> Vec<Box<dyn StepSink>> {
+    if wav_steps {
+        vec![Box::new(WavSink)]
+    } else {
+        Vec::new()
+    }
+}
 
 /// Get values from widgets, decode and update widgets.
 ///
@@ -30,8 +40,10 @@ pub fn decode() {
     // Called when decoding finishes
     let callback = |result: err::Result<Signal>| {
         glib::idle_add(move || {
+            borrow_state_mut(|state| state.cancel_token = None);
             borrow_widgets(|widgets| {
                 widgets.dec_decode_button.set_sensitive(true);
+                widgets.dec_cancel_button.set_sensitive(false);
                 match &result {
                     Ok(signal) => {
                         misc::set_progress(1., "Decoded");
@@ -125,9 +137,13 @@ pub fn decode() {
                         };
                     }
                     Err(e) => {
-                        misc::set_progress(1., "Error");
-                        misc::show_info(gtk::MessageType::Error, &e.to_string());
-                        error!("{}", e);
+                        if let err::Error::Cancelled = e {
+                            misc::set_progress(0., "Cancelled");
+                        } else {
+                            misc::set_progress(1., "Error");
+                            misc::show_info(gtk::MessageType::Error, &e.to_string());
+                            error!("{}", e);
+                        }
                         borrow_state_mut(|state| {
                             state.decoded_signal = None;
                             state.processed_image = None;
@@ -171,6 +187,10 @@ pub fn decode() {
 
         let settings = borrow_state(|state| state.settings.clone());
 
+        let cancel_token = CancelToken::new();
+        borrow_state_mut(|state| state.cancel_token = Some(cancel_token.clone()));
+        widgets.dec_cancel_button.set_sensitive(true);
+
         std::thread::spawn(move || {
             let (signal, rate) = match noaa_apt::load(&input_filename) {
                 Ok(result) => result,
@@ -184,20 +204,35 @@ pub fn decode() {
                 progress_callback,
                 Rate::hz(settings.work_rate),
                 Rate::hz(noaa_apt::FINAL_RATE),
-                wav_steps,
+                wav_sinks(wav_steps),
                 resample_step,
             );
+            context.set_cancel_token(cancel_token);
             callback(noaa_apt::decode(
                 &mut context,
                 &settings,
                 &signal,
                 rate,
                 sync,
-            ));
+            ).map(|(signal, _sync_report, _quality)| signal));
         });
     });
 }
 
+/// Stop the decode running on the working thread.
+///
+/// The decode finishes on its own a moment later with `err::Error::Cancelled`.
+pub fn cancel_decode() {
+    borrow_state(|state| {
+        if let Some(cancel_token) = &state.cancel_token {
+            cancel_token.cancel();
+        }
+    });
+    borrow_widgets(|widgets| {
+        widgets.dec_cancel_button.set_sensitive(false);
+        misc::set_progress(0., "Cancelling");
+    });
+}
 
 /// Calls process() if auto update is enabled.
 ///
@@ -331,6 +366,9 @@ pub fn process() {
                 ch_a_tune_end: widgets.p_channel_a_end_scale.value() as f32,
                 ch_b_tune_start: widgets.p_channel_b_start_scale.value() as f32,
                 ch_b_tune_end: widgets.p_channel_b_end_scale.value() as f32,
+                enhancement: None,
+                night_palette_filename: None,
+                mcir: false,
             })
         } else {
             None
@@ -465,6 +503,12 @@ pub fn process() {
             }
         };
 
+        let align = draw_map.is_some() && widgets.p_align_check.is_active();
+
+        // Get settings from state
+
+        let settings = borrow_state(|state| state.settings.clone());
+
         // Compose OrbitSettings
 
         let orbit = OrbitSettings {
@@ -472,11 +516,11 @@ pub fn process() {
             custom_tle,
             ref_time,
             draw_map,
+            max_epoch_days: settings.tle_max_epoch_days,
+            tle: Default::default(),
         };
 
-        // Get settings and signal from state
-
-        let settings = borrow_state(|state| state.settings.clone());
+        // Get signal from state
 
         let signal = match borrow_state(|state| state.decoded_signal.clone()) {
             Some(s) => s,
@@ -491,9 +535,20 @@ pub fn process() {
                 progress_callback,
                 Rate::hz(settings.work_rate),
                 Rate::hz(noaa_apt::FINAL_RATE),
-                wav_steps,
+                wav_sinks(wav_steps),
                 resample_step,
             );
+            let orbit = if align {
+                match noaa_apt::align_map(&mut context, &signal, &orbit) {
+                    Ok(orbit) => orbit,
+                    Err(e) => {
+                        callback(Err(e));
+                        return;
+                    }
+                }
+            } else {
+                orbit
+            };
             callback(noaa_apt::process(
                 &mut context,
                 &signal,
@@ -599,7 +654,8 @@ pub fn resample() {
         let settings = borrow_state(|state| state.settings.clone());
 
         std::thread::spawn(move || {
-            let mut context = Context::resample(progress_callback, wav_steps, resample_step);
+            let mut context =
+                Context::resample(progress_callback, wav_sinks(wav_steps), resample_step);
             callback(noaa_apt::resample(
                 &mut context,
                 settings,
@@ -699,3 +755,46 @@ pub fn read_timestamp() {
         misc::show_info(gtk::MessageType::Info, "Loaded timestamp from file");
     });
 }
+
+/// Get values from widgets and list upcoming passes on the text view.
+///
+/// Starts another working thread because the TLE may need to be downloaded.
+pub fn predict_passes() {
+    // Called when prediction finishes
+    let callback = |result: err::Result<Vec<crate::orbit::Pass>>| {
+        glib::idle_add(move || {
+            borrow_widgets(|widgets| {
+                widgets.pas_predict_button.set_sensitive(true);
+                match &result {
+                    Ok(passes) => {
+                        misc::set_progress(1., &format!("Found {} passes", passes.len()));
+                        if let Some(buffer) = widgets.pas_text_view.buffer() {
+                            buffer.set_text(&crate::orbit::passes_table(passes));
+                        }
+                    }
+                    Err(e) => {
+                        misc::set_progress(1., "Error");
+                        misc::show_info(gtk::MessageType::Error, &e.to_string());
+                        error!("{}", e);
+                    }
+                }
+            });
+            ControlFlow::Break
+        });
+    };
+
+    borrow_widgets(|widgets| {
+        misc::set_progress(0., "Predicting passes");
+        widgets.info_revealer.set_reveal_child(false);
+        widgets.pas_predict_button.set_sensitive(false);
+
+        let hours = widgets.pas_hours_spinner.value();
+        let min_elevation = widgets.pas_min_elevation_spinner.value();
+
+        let settings = borrow_state(|state| state.settings.clone());
+
+        std::thread::spawn(move || {
+            callback(noaa_apt::predict_passes(&settings, None, hours, min_elevation));
+        });
+    });
+}
@@ -0,0 +1,483 @@
+//! Functions for loading IQ recordings and FM demodulating them.
+//!
+//! The result is the same kind of audio we get from a WAV recording made with
+//! an FM receiver, containing the 2400Hz AM subcarrier, so it can be decoded
+//! with `decode::decode()`.
+//!
+//! IQ recordings are usually huge, so they are processed by chunks while
+//! reading them. The file is read twice: the first time to estimate the
+//! frequency offset of the signal and the second time to demodulate.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use log::{debug, info};
+use rustfft::num_complex::Complex;
+
+use crate::context::Context;
+use crate::dsp::{Freq, Rate, Signal, StreamResampler};
+use crate::err;
+use crate::filters;
+use crate::noaa_apt::{OrbitSettings, RefTime};
+use crate::orbit::{self, GroundStation};
+use crate::stream::{RawFormat, RawReader};
+use crate::tle;
+
+/// Sample rate of the FM demodulated signal.
+///
+/// Multiple of `FINAL_RATE`, big enough to contain the channel.
+pub const IQ_WORK_RATE: u32 = 62400;
+
+/// Half of the width of the channel filter in Hz.
+///
+/// APT signals have a deviation of 17kHz, so the Carson bandwidth is around
+/// 2 * (17kHz + 2.4kHz). I add some margin to tolerate the Doppler shift
+/// (around 3kHz at 137MHz) and errors on the frequency offset.
+pub const CHANNEL_CUTOUT: f32 = 24000.;
+
+/// Width of the transition band of the channel filter in Hz.
+const CHANNEL_DELTA_FREQ: f32 = 6000.;
+
+/// Attenuation in positive dB of the channel filter.
+const CHANNEL_ATTEN: f32 = 40.;
+
+/// Complex sample.
+pub type Complex32 = Complex<f32>;
+
+/// Available IQ file formats.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IqFormat {
+    /// Unsigned 8 bit interleaved IQ, as recorded by `rtl_sdr`.
+    Cu8,
+
+    /// Signed 16 bit little endian interleaved IQ.
+    Cs16,
+
+    /// 32 bit float little endian interleaved IQ.
+    Cf32,
+
+    /// WAV with two channels, I on the first one and Q on the second one.
+    Wav,
+}
+
+impl IqFormat {
+    /// Parse format name as given on the commandline.
+    pub fn from_name(name: &str) -> Option<IqFormat> {
+        match name {
+            "cu8" => Some(IqFormat::Cu8),
+            "cs16" => Some(IqFormat::Cs16),
+            "cf32" => Some(IqFormat::Cf32),
+            "wav" => Some(IqFormat::Wav),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for IQ recordings.
+#[derive(Clone, Debug)]
+pub struct IqSettings {
+    pub format: IqFormat,
+
+    /// Sample rate in Hz, needed for raw formats. WAV files have it on the
+    /// header.
+    pub rate: Option<u32>,
+
+    /// Frequency of the signal relative to the center of the recording, in
+    /// Hz. If `None` it is estimated from the recording.
+    pub offset: Option<f32>,
+
+    /// Doppler correction, if enabled.
+    pub doppler: Option<DopplerSettings>,
+}
+
+/// Settings needed for Doppler correction.
+#[derive(Clone, Debug)]
+pub struct DopplerSettings {
+    pub orbit: OrbitSettings,
+    pub ground_station: GroundStation,
+}
+
+/// Doppler shift during the recording.
+///
+/// Calculated once per second and interpolated, it changes slowly.
+struct DopplerTable {
+    /// Doppler shift in Hz for each second since the start of the recording.
+    values: Vec<f32>,
+}
+
+impl DopplerTable {
+    fn new(settings: &DopplerSettings, duration: f64) -> err::Result<DopplerTable> {
+        let tle = tle::for_orbit(&settings.orbit)?;
+        let sat = orbit::find_sat(&tle, &settings.orbit.sat_name)?;
+        let freq = orbit::downlink_frequency(&settings.orbit.sat_name);
+
+        let duration_ms = (duration * 1000.) as i64;
+        let start_time = match settings.orbit.ref_time {
+            RefTime::Start(time) => time,
+            RefTime::End(time) => time - chrono::Duration::milliseconds(duration_ms),
+        };
+
+        let mut values = Vec::new();
+        for second in 0..(duration.ceil() as i64 + 2) {
+            let time = start_time + chrono::Duration::seconds(second);
+            values.push(orbit::doppler_shift(&sat, &settings.ground_station, freq, time)? as f32);
+        }
+
+        info!(
+            "Doppler shift from {}Hz to {}Hz",
+            values.first().unwrap(),
+            values.last().unwrap()
+        );
+
+        Ok(DopplerTable { values })
+    }
+
+    /// Doppler shift in Hz, `seconds` since the start of the recording.
+    fn at(&self, seconds: f64) -> f32 {
+        let i = (seconds.max(0.) as usize).min(self.values.len() - 2);
+        let frac = (seconds - i as f64).clamp(0., 1.) as f32;
+        self.values[i] * (1. - frac) + self.values[i + 1] * frac
+    }
+}
+
+/// Get the Doppler table for a recording, if Doppler correction is enabled.
+fn doppler_table(
+    settings: &IqSettings,
+    rate: Rate,
+    len: Option<u64>,
+) -> err::Result<Option<DopplerTable>> {
+    match &settings.doppler {
+        Some(doppler) => {
+            let len = len.ok_or_else(|| {
+                err::Error::InvalidInput(
+                    "Unknown recording length, can't correct Doppler".to_string(),
+                )
+            })?;
+            Ok(Some(DopplerTable::new(
+                doppler,
+                len as f64 / rate.get_hz() as f64,
+            )?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads complex samples by chunks from any of the supported formats.
+enum IqReader {
+    Raw(RawReader<BufReader<File>>, Option<f32>),
+    Wav(hound::WavReader<BufReader<File>>),
+}
+
+impl IqReader {
+    /// Open file, returns also the sample rate and the number of complex
+    /// samples if known.
+    fn open(settings: &IqSettings, filename: &Path) -> err::Result<(IqReader, Rate, Option<u64>)> {
+        let raw_format = match settings.format {
+            IqFormat::Cu8 => RawFormat::U8,
+            IqFormat::Cs16 => RawFormat::S16Le,
+            IqFormat::Cf32 => RawFormat::F32Le,
+            IqFormat::Wav => {
+                let reader = hound::WavReader::open(filename)?;
+                let spec = reader.spec();
+                if spec.channels != 2 {
+                    return Err(err::Error::InvalidInput(format!(
+                        "IQ WAV files should have 2 channels, got {}",
+                        spec.channels
+                    )));
+                }
+                let len = reader.duration() as u64;
+                return Ok((IqReader::Wav(reader), Rate::hz(spec.sample_rate), Some(len)));
+            }
+        };
+
+        let rate = settings.rate.ok_or_else(|| {
+            err::Error::InvalidInput("Sample rate needed for raw IQ recordings".to_string())
+        })?;
+
+        let file = File::open(filename)?;
+        let bytes = file.metadata()?.len();
+        let len = bytes / 2 / raw_format.sample_size() as u64;
+
+        Ok((
+            IqReader::Raw(RawReader::new(BufReader::new(file), raw_format), None),
+            Rate::hz(rate),
+            Some(len),
+        ))
+    }
+
+    /// Read next complex samples. Returns `None` at the end of the file.
+    fn read(&mut self) -> err::Result<Option<Vec<Complex32>>> {
+        match self {
+            IqReader::Raw(reader, leftover) => {
+                let samples = match reader.read()? {
+                    Some(s) => s,
+                    None => return Ok(None),
+                };
+
+                // A read can end between the I and the Q of a sample
+                let mut values = leftover.take().into_iter().chain(samples);
+                let mut output = Vec::new();
+                while let Some(re) = values.next() {
+                    match values.next() {
+                        Some(im) => output.push(Complex::new(re, im)),
+                        None => *leftover = Some(re),
+                    }
+                }
+
+                Ok(Some(output))
+            }
+            IqReader::Wav(reader) => {
+                let chunk = 8192;
+                let output: Vec<Complex32> = match reader.spec().sample_format {
+                    hound::SampleFormat::Int => {
+                        let values = reader
+                            .samples::<i32>()
+                            .take(chunk * 2)
+                            .map(|s| s.map(|x| x as f32))
+                            .collect::<Result<Vec<f32>, hound::Error>>()?;
+                        values
+                            .chunks_exact(2)
+                            .map(|v| Complex::new(v[0], v[1]))
+                            .collect()
+                    }
+                    hound::SampleFormat::Float => {
+                        let values = reader.samples::<f32>().take(chunk * 2).collect::<Result<
+                            Vec<f32>,
+                            hound::Error,
+                        >>(
+                        )?;
+                        values
+                            .chunks_exact(2)
+                            .map(|v| Complex::new(v[0], v[1]))
+                            .collect()
+                    }
+                };
+
+                if output.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(output))
+                }
+            }
+        }
+    }
+}
+
+/// Frequency shifter.
+///
+/// Multiplies the signal by a complex exponential, keeping the phase between
+/// chunks. The frequency can be changed between chunks.
+#[derive(Default)]
+pub struct Derotator {
+    /// Current phase in radians.
+    phase: f64,
+}
+
+impl Derotator {
+    pub fn new() -> Derotator {
+        Derotator { phase: 0. }
+    }
+
+    /// Move the spectrum of the chunk by `-freq`, so a signal at `freq` goes
+    /// to zero.
+    pub fn process(&mut self, chunk: &mut [Complex32], freq: Freq) {
+        let step = -freq.get_pi_rad() as f64 * std::f64::consts::PI;
+        for sample in chunk.iter_mut() {
+            *sample *= Complex::from_polar(1., self.phase as f32);
+            self.phase = (self.phase + step) % (2. * std::f64::consts::PI);
+        }
+    }
+}
+
+/// FM discriminator.
+///
+/// Calculates the phase difference between consecutive samples, so the output
+/// is the instantaneous frequency in radians per sample.
+#[derive(Default)]
+pub struct Discriminator {
+    prev: Complex32,
+}
+
+impl Discriminator {
+    pub fn new() -> Discriminator {
+        Discriminator {
+            prev: Complex::new(0., 0.),
+        }
+    }
+
+    /// Demodulate chunk, returns the same number of samples.
+    pub fn process(&mut self, chunk: &[Complex32]) -> Signal {
+        let mut output = Vec::with_capacity(chunk.len());
+        for sample in chunk {
+            output.push((sample * self.prev.conj()).arg());
+            self.prev = *sample;
+        }
+        output
+    }
+}
+
+/// Estimate frequency offset of the signal on a recording.
+///
+/// Uses the angle of the autocorrelation with lag 1, that is the mean
+/// instantaneous frequency weighted by power, so noise before and after the
+/// pass has little effect. The Doppler shift is approximately symmetric during
+/// the pass so the result is close to the frequency of the transmitter.
+///
+/// If a Doppler table is given, the Doppler shift is removed first so we get
+/// only the error of the receiver oscillator.
+fn estimate_offset(
+    context: &mut Context,
+    settings: &IqSettings,
+    filename: &Path,
+    doppler: Option<&DopplerTable>,
+) -> err::Result<Freq> {
+    let (mut reader, rate, _len) = IqReader::open(settings, filename)?;
+
+    context.status(0., "Estimating frequency offset".to_string());
+
+    let mut sum: Complex<f64> = Complex::new(0., 0.);
+    let mut prev: Complex32 = Complex::new(0., 0.);
+    let mut derotator = Derotator::new();
+    let mut read: u64 = 0;
+
+    while let Some(mut chunk) = reader.read()? {
+        if let Some(doppler) = doppler {
+            let seconds = read as f64 / rate.get_hz() as f64;
+            derotator.process(&mut chunk, Freq::hz(doppler.at(seconds), rate));
+        }
+        read += chunk.len() as u64;
+
+        for sample in &chunk {
+            let product = sample * prev.conj();
+            sum += Complex::new(product.re as f64, product.im as f64);
+            prev = *sample;
+        }
+    }
+
+    let offset = Freq::rad(sum.arg() as f32);
+    info!("Estimated frequency offset: {}Hz", offset.get_hz(rate));
+
+    Ok(offset)
+}
+
+/// Load IQ recording and FM demodulate it.
+///
+/// Returns the demodulated signal and its sample rate, `IQ_WORK_RATE`.
+pub fn load_iq(
+    context: &mut Context,
+    settings: &IqSettings,
+    filename: &Path,
+) -> err::Result<(Signal, Rate)> {
+    let (mut reader, input_rate, len) = IqReader::open(settings, filename)?;
+    let work_rate = Rate::hz(IQ_WORK_RATE);
+
+    if (input_rate.get_hz() as f32) < 2. * (CHANNEL_CUTOUT + CHANNEL_DELTA_FREQ / 2.) {
+        return Err(err::Error::InvalidInput(format!(
+            "IQ sample rate too low, should be at least {}Hz",
+            2. * (CHANNEL_CUTOUT + CHANNEL_DELTA_FREQ / 2.)
+        )));
+    }
+
+    let doppler = doppler_table(settings, input_rate, len)?;
+
+    let offset = match settings.offset {
+        Some(hz) => Freq::hz(hz, input_rate),
+        None => estimate_offset(context, settings, filename, doppler.as_ref())?,
+    };
+    debug!("Using frequency offset: {}Hz", offset.get_hz(input_rate));
+
+    let channel_filter = filters::Lowpass {
+        cutout: Freq::hz(CHANNEL_CUTOUT, input_rate),
+        atten: CHANNEL_ATTEN,
+        delta_w: Freq::hz(CHANNEL_DELTA_FREQ, input_rate),
+    };
+
+    // Channel filter and resample the real and imaginary parts separately
+    let mut resampler_re = StreamResampler::new(input_rate, work_rate, channel_filter.clone())?;
+    let mut resampler_im = StreamResampler::new(input_rate, work_rate, channel_filter)?;
+    let mut derotator = Derotator::new();
+    let mut discriminator = Discriminator::new();
+
+    context.status(0.05, "FM demodulating".to_string());
+
+    let mut output: Signal = match len {
+        Some(len) => {
+            Vec::with_capacity((len * IQ_WORK_RATE as u64 / input_rate.get_hz() as u64) as usize)
+        }
+        None => Vec::new(),
+    };
+
+    let mut read: u64 = 0;
+
+    while let Some(mut chunk) = reader.read()? {
+        match &doppler {
+            Some(doppler) => {
+                // Use the Doppler shift at the middle of the chunk
+                let seconds = (read as f64 + chunk.len() as f64 / 2.) / input_rate.get_hz() as f64;
+                let freq = offset.get_hz(input_rate) + doppler.at(seconds);
+                derotator.process(&mut chunk, Freq::hz(freq, input_rate));
+            }
+            None => derotator.process(&mut chunk, offset),
+        }
+        read += chunk.len() as u64;
+
+        let re: Signal = chunk.iter().map(|c| c.re).collect();
+        let im: Signal = chunk.iter().map(|c| c.im).collect();
+        let filtered: Vec<Complex32> = resampler_re
+            .process(&re)
+            .into_iter()
+            .zip(resampler_im.process(&im))
+            .map(|(re, im)| Complex::new(re, im))
+            .collect();
+
+        output.extend(discriminator.process(&filtered));
+    }
+
+    let filtered: Vec<Complex32> = resampler_re
+        .finish()
+        .into_iter()
+        .zip(resampler_im.finish())
+        .map(|(re, im)| Complex::new(re, im))
+        .collect();
+    output.extend(discriminator.process(&filtered));
+
+    Ok((output, work_rate))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check that a FM modulated tone is demodulated after shifting it to
+    /// zero.
+    #[test]
+    fn test_derotate_discriminate() {
+        let rate = Rate::hz(100000);
+        let offset = Freq::hz(12000., rate);
+        let deviation = 2. * std::f32::consts::PI * 5000. / 100000.; // rad/sample
+        let tone = |i: usize| (i as f32 * 0.01).sin();
+
+        // Integrate frequency to get the phase of the FM signal
+        let mut phase = 0.;
+        let mut signal: Vec<Complex32> = Vec::new();
+        for i in 0..2000 {
+            phase += offset.get_rad() + deviation * tone(i);
+            signal.push(Complex::from_polar(1., phase));
+        }
+
+        let mut derotator = Derotator::new();
+        let mut discriminator = Discriminator::new();
+        let mut demodulated = Vec::new();
+        for chunk in signal.chunks_mut(300) {
+            derotator.process(chunk, offset);
+            demodulated.extend(discriminator.process(chunk));
+        }
+
+        // Skip first sample, there is no previous sample there
+        for (i, value) in demodulated.iter().enumerate().skip(1) {
+            assert!((value - deviation * tone(i)).abs() < 1e-3);
+        }
+    }
+}
@@ -11,6 +11,8 @@
 // https://doc.rust-lang.org/edition-guide/rust-2018/ownership-and-lifetimes/the-anonymous-lifetime.html
 #![warn(elided_lifetimes_in_paths)]
 
+mod align;
+mod calibration;
 #[macro_use]
 mod config;
 mod context;
@@ -20,25 +22,60 @@ mod err;
 mod filters;
 mod frequency;
 mod geo;
+mod georef;
 #[cfg(feature = "gui")]
 mod gui;
 mod imageext;
+mod iq;
 mod map;
 mod misc;
+mod mosaic;
 mod noaa_apt;
+mod orbit;
+mod parallel;
+mod plot;
 mod processing;
+mod projection;
+mod quality;
 mod resample;
+mod sinks;
+mod stream;
+mod sun;
 mod telemetry;
+mod tle;
+mod watch;
 mod wav;
 
 use log::{debug, error, info, warn};
 
-use context::Context;
+use context::{CancelToken, Context};
 use dsp::Rate;
 
 /// Defined by Cargo.toml
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Cancel the running operation when the user presses Ctrl+C.
+///
+/// Pressing it again exits immediately, in case we are stuck somewhere that
+/// doesn't check the `CancelToken`.
+fn cancel_on_ctrlc() -> CancelToken {
+    let cancel_token = CancelToken::new();
+    let handler_token = cancel_token.clone();
+
+    let result = ctrlc::set_handler(move || {
+        if handler_token.is_cancelled() {
+            std::process::exit(130);
+        }
+        warn!("Cancelling, press Ctrl+C again to exit immediately");
+        handler_token.cancel();
+    });
+    if let Err(e) = result {
+        warn!("Could not handle Ctrl+C: {}", e);
+    }
+
+    cancel_token
+}
+
 /// Main function that returns err::Result
 fn inner_main() -> err::Result<()> {
     let (check_updates, verbosity, mode) = config::get_config();
@@ -79,6 +116,14 @@ fn inner_main() -> err::Result<()> {
             rotate,
             color_settings,
             orbit_settings,
+            iq_settings,
+            georef_settings,
+            projection_settings,
+            sync_report,
+            quality_report,
+            telemetry_report,
+            temperatures_filename,
+            auto_align,
         } => {
             println!("noaa-apt image decoder version {}", VERSION);
 
@@ -95,24 +140,198 @@ fn inner_main() -> err::Result<()> {
                 |_progress, description| info!("{}", description),
                 Rate::hz(settings.work_rate),
                 Rate::hz(noaa_apt::FINAL_RATE),
-                settings.export_wav,
+                sinks::from_settings(&settings),
                 settings.export_resample_filtered,
             );
+            context.set_cancel_token(cancel_on_ctrlc());
+
+            let (signal, rate) = match &iq_settings {
+                Some(iq_settings) => noaa_apt::load_iq(&mut context, iq_settings, &input_filename)?,
+                None => noaa_apt::load(&input_filename)?,
+            };
+
+            let (raw_data, report, quality) =
+                noaa_apt::decode(&mut context, &settings, &signal, rate, sync)?;
+
+            if let Some(filename) = &sync_report {
+                info!("Writing sync report to {}", filename.display());
+                report.write_csv(filename)?;
+            }
 
-            let (signal, rate) = noaa_apt::load(&input_filename)?;
+            if let Some(filename) = &quality_report {
+                info!("Writing quality report to {}", filename.display());
+                quality.write(filename)?;
+            }
+
+            let orbit_settings = match &orbit_settings {
+                Some(orbit) if auto_align => {
+                    Some(noaa_apt::align_map(&mut context, &raw_data, orbit)?)
+                }
+                _ => orbit_settings,
+            };
+
+            if let Some(filename) = &telemetry_report {
+                info!("Writing telemetry report to {}", filename.display());
+                noaa_apt::save_telemetry_report(
+                    &mut context,
+                    &raw_data,
+                    orbit_settings.as_ref(),
+                    filename,
+                )?;
+            }
 
-            let raw_data = noaa_apt::decode(&mut context, &settings, &signal, rate, sync)?;
+            if let (Some(filename), Some(orbit_settings)) = (&temperatures_filename, &orbit_settings) {
+                noaa_apt::save_temperatures(
+                    &mut context,
+                    &raw_data,
+                    rotate.clone(),
+                    orbit_settings,
+                    filename,
+                )?;
+            }
+
+            let enhancement = color_settings.as_ref().and_then(|c| c.enhancement);
 
             let img = noaa_apt::process(
                 &mut context,
                 &raw_data,
                 contrast_adjustment,
+                rotate.clone(),
+                color_settings,
+                orbit_settings.clone(),
+            )?;
+
+            // The legend is only added to the saved image, the exports below
+            // need the original height
+            match enhancement {
+                Some(enhancement) => noaa_apt::draw_legend(&img, enhancement)?.save(&output_filename)?,
+                None => img.save(&output_filename)?,
+            }
+
+            if let Some(orbit_settings) = orbit_settings {
+                if let Some(georef_settings) = georef_settings {
+                    noaa_apt::save_georeferenced(
+                        &mut context,
+                        &img,
+                        rotate.clone(),
+                        &orbit_settings,
+                        georef_settings.channel,
+                        &georef_settings.filename,
+                    )?;
+                }
+                if let Some(projection_settings) = projection_settings {
+                    noaa_apt::save_projected(
+                        &mut context,
+                        &img,
+                        rotate,
+                        &orbit_settings,
+                        &projection_settings,
+                    )?;
+                }
+            }
+        }
+        config::Mode::Stream {
+            settings,
+            input_filename,
+            raw_format,
+            input_rate,
+            output_filename,
+            sync,
+            contrast_adjustment,
+            rotate,
+            color_settings,
+            orbit_settings,
+        } => {
+            println!("noaa-apt image decoder version {}", VERSION);
+
+            let mut context = Context::decode(
+                |_progress, description| info!("{}", description),
+                Rate::hz(settings.work_rate),
+                Rate::hz(noaa_apt::FINAL_RATE),
+                Vec::new(), // Steps are not available when streaming
+                false,
+            );
+            context.set_cancel_token(cancel_on_ctrlc());
+
+            let reader: Box<dyn std::io::Read> = match &input_filename {
+                Some(filename) => Box::new(std::fs::File::open(filename)?),
+                None => Box::new(std::io::stdin()),
+            };
+
+            noaa_apt::decode_stream(
+                &mut context,
+                &settings,
+                reader,
+                raw_format,
+                Rate::hz(input_rate),
+                sync,
+                &output_filename,
+                contrast_adjustment,
                 rotate,
                 color_settings,
                 orbit_settings,
             )?;
+        }
+        config::Mode::Mosaic {
+            settings,
+            passes,
+            sync,
+            contrast_adjustment,
+            projection_settings,
+        } => {
+            println!("noaa-apt image decoder version {}", VERSION);
+
+            let mut context = Context::decode(
+                |_progress, description| info!("{}", description),
+                Rate::hz(settings.work_rate),
+                Rate::hz(noaa_apt::FINAL_RATE),
+                sinks::from_settings(&settings),
+                settings.export_resample_filtered,
+            );
+            context.set_cancel_token(cancel_on_ctrlc());
 
-            img.save(&output_filename)?;
+            noaa_apt::mosaic(
+                &mut context,
+                &settings,
+                &passes,
+                sync,
+                contrast_adjustment,
+                &projection_settings,
+            )?;
+        }
+        config::Mode::Watch {
+            settings,
+            watch_settings,
+        } => {
+            println!("noaa-apt image decoder version {}", VERSION);
+
+            watch::watch(&settings, &watch_settings)?;
+        }
+        config::Mode::Passes {
+            settings,
+            custom_tle,
+            hours,
+            min_elevation,
+            json,
+        } => {
+            let passes = noaa_apt::predict_passes(
+                &settings,
+                custom_tle.as_deref(),
+                hours,
+                min_elevation,
+            )?;
+
+            if json {
+                println!("{}", orbit::passes_json(&passes)?);
+            } else {
+                print!("{}", orbit::passes_table(&passes));
+            }
+        }
+        config::Mode::ImportTle { filenames } => {
+            for filename in filenames {
+                let count = tle::store(&std::fs::read_to_string(&filename)?)?;
+                info!("Added {} element sets from {}", count, filename.display());
+            }
         }
         config::Mode::Resample {
             settings,
@@ -124,9 +343,10 @@ fn inner_main() -> err::Result<()> {
 
             let mut context = Context::resample(
                 |_progress, description| info!("{}", description),
-                settings.export_wav,
+                sinks::from_settings(&settings),
                 settings.export_resample_filtered,
             );
+            context.set_cancel_token(cancel_on_ctrlc());
 
             noaa_apt::resample(
                 &mut context,
@@ -148,6 +368,13 @@ fn main() {
     std::process::exit(match inner_main() {
         Ok(_) => 0,
 
+        // Same exit code as if we were killed by SIGINT
+        Err(err::Error::Cancelled) => {
+            error!("{}", err::Error::Cancelled);
+
+            130
+        }
+
         Err(err) => {
             error!("{}", err);
 
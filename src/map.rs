@@ -1,17 +1,21 @@
-//! Code to read shapefiles and draw the map overlay.
+//! Code to read shapefiles, draw the map overlay and rasterize land masks.
 
+use std::collections::{HashMap, HashSet};
 use std::f64::consts::PI;
+use std::path::Path;
 
 use image::Pixel;
 use line_drawing::XiaolinWu;
 use log::info;
 
+use crate::decode::PX_CHANNEL_IMAGE_DATA;
 use crate::err;
-use crate::geo;
+use crate::georef::{Georef, NADIR_A, NADIR_B};
 use crate::noaa_apt::{Image, MapSettings, RefTime, SatName};
+use crate::parallel;
+use crate::projection::{self, BoundingBox};
 
 /// Draws the map overlay mutating the image.
-#[allow(non_snake_case)]
 #[allow(clippy::many_single_char_names)]
 pub fn draw_map(
     img: &mut Image,
@@ -22,95 +26,75 @@ pub fn draw_map(
 ) -> err::Result<()> {
     info!("Drawing map overlay");
 
-    let height = img.height();
-    let line_duration = chrono::Duration::milliseconds(500); // Two lines per sec
+    let georef = Georef::new(
+        &tle,
+        &sat_name,
+        &ref_time,
+        img.height(),
+        settings.yaw,
+        settings.hscale,
+        settings.vscale,
+    )?;
 
-    // Load satellite from TLE
+    // Read the lines of every shapefile, points as (latitude, longitude) in
+    // radians
 
-    let (sats, _errors) = satellite::io::parse_multiple(&tle);
-    let sat_string = sat_name.to_string();
+    /// Points and RGBA color.
+    type Line = (Vec<(f64, f64)>, (u8, u8, u8, u8));
 
-    let sat = sats
-        .iter()
-        .find(|&sat| sat.name.as_ref() == Some(&sat_string))
-        .ok_or_else(|| {
-            err::Error::Internal(format!("Satellite \"{}\" not found in TLE", sat_string))
-        })?
-        .clone();
-
-    // Calculate satellite trajectory
-
-    let start_time = match ref_time {
-        RefTime::Start(time) => time,
-        RefTime::End(time) => time - line_duration * height as i32,
-    };
+    let to_latlon = |pt: &shapefile::Point| (pt.y / 180. * PI, pt.x / 180. * PI);
+    let mut lines: Vec<Line> = Vec::new();
 
-    // (latitude, longitude) of the satellite for each line
-    let mut sat_positions: Vec<(f64, f64)> = Vec::with_capacity(height as usize);
-
-    for i in 0..height {
-        let t = start_time + line_duration * i as i32;
-        // TODO: Remove unwrap()
-        let result = satellite::propogation::propogate_datetime(&sat, t).unwrap();
-        let gmst = satellite::propogation::gstime::gstime_datetime(t);
-        let sat_pos = satellite::transforms::eci_to_geodedic(&result.position, gmst);
-        sat_positions.push((sat_pos.latitude, sat_pos.longitude));
+    let filename = res_path!("shapefiles", "states.shp");
+    let mut reader = shapefile::ShapeReader::from_path(&filename)
+        .map_err(|_| err::Error::Internal(format!("Could not load {:?}", filename)))?;
+    for result in reader.iter_shapes_as::<shapefile::Polyline>() {
+        let polyline = result?;
+        for points in polyline.parts() {
+            lines.push((points.iter().map(to_latlon).collect(), settings.states_color));
+        }
     }
-    let start_latlon = sat_positions[0];
-    let end_latlon = *sat_positions.last().unwrap();
-
-    // Get image resolution (radians per pixel)
-
-    let y_res = geo::distance(start_latlon, end_latlon) / height as f64 / settings.vscale;
-    let x_res = 0.0005 / settings.hscale;
-
-    // Map (latitude, longitude) to pixel coordinates
 
-    let ref_az = geo::azimuth(start_latlon, end_latlon);
-
-    let latlon_to_rel_px = |latlon: (f64, f64)| -> (f64, f64) {
-        // To understand this, you should look at the illustrations on my how
-        // it works page.
-
-        let az = geo::azimuth(start_latlon, latlon);
-        let B = az - ref_az;
-
-        // Set maximum, otherwise we get wrapping problems I do not fully
-        // understand: opposite parts of the world are mapped to the same
-        // position because of the cyclic nature of sin(), cos(), etc.
-        let c = geo::distance(latlon, start_latlon)
-            .max(-PI / 3.)
-            .min(PI / 3.);
-
-        let a = (B.cos() * c.tan()).atan();
-        let b = (B.sin() * c.sin()).asin();
-
-        let x = -b / x_res;
-
-        // Add the yaw correction value. I should be calculating sin(yaw) * x
-        // but yaw is always a small value.
-        let y = a / y_res + settings.yaw * x;
+    for (name, color) in [
+        ("countries.shp", settings.countries_color),
+        ("lakes.shp", settings.lakes_color),
+    ] {
+        let filename = res_path!("shapefiles", name);
+        let mut reader = shapefile::ShapeReader::from_path(&filename)
+            .map_err(|_| err::Error::Internal(format!("Could not load {:?}", filename)))?;
+        for result in reader.iter_shapes_as::<shapefile::Polygon>() {
+            let polygon = result?;
+            for ring in polygon.rings() {
+                use shapefile::record::polygon::PolygonRing;
+                let points = match ring {
+                    PolygonRing::Outer(p) | PolygonRing::Inner(p) => p,
+                };
+                lines.push((points.iter().map(to_latlon).collect(), color));
+            }
+        }
+    }
 
-        (x, y)
-    };
+    // Convert every point to (x, y), that's the slow part so each thread
+    // converts some lines
 
-    // Draw line function
+    let projected: Vec<Vec<(f64, f64)>> = parallel::map_ranges(lines.len(), 1, 1, |range| {
+        lines[range]
+            .iter()
+            .map(|(points, _)| points.iter().map(|&p| georef.latlon_to_rel_px(p)).collect())
+            .collect::<Vec<Vec<(f64, f64)>>>()
+    })
+    .concat();
 
-    let mut draw_line =
-        |latlon1: (f64, f64), latlon2: (f64, f64), (r, g, b, a): (u8, u8, u8, u8)| {
-            // Convert latlon to (x, y)
-            let (mut x1, y1) = latlon_to_rel_px(latlon1);
-            let (mut x2, y2) = latlon_to_rel_px(latlon2);
+    // Draw lines, on a single thread so they are blended always in the same
+    // order
 
-            // Offset correction on x
-            let est_y1 = (y1.max(0.) as usize).min(height as usize - 1);
-            let est_y2 = (y2.max(0.) as usize).min(height as usize - 1);
-            let (x1_offset, _) = latlon_to_rel_px(sat_positions[est_y1]);
-            let (x2_offset, _) = latlon_to_rel_px(sat_positions[est_y2]);
-            x1 -= x1_offset;
-            x2 -= x2_offset;
+    let h = img.height() as i32;
 
-            let h = img.height() as i32;
+    for (points, &(_, (r, g, b, a))) in projected.iter().zip(lines.iter()) {
+        let mut prev_pt = points[0];
+        for &pt in points {
+            let ((x1, y1), (x2, y2)) = (pt, prev_pt);
+            prev_pt = pt;
 
             // See if at least one point is inside
             if (x1 > -456. && x1 < 456. && y1 > 0. && y1 < h as f64)
@@ -119,82 +103,385 @@ pub fn draw_map(
                 for ((x, y), value) in XiaolinWu::<f64, i32>::new((x1, y1), (x2, y2)) {
                     // Draw A channel
                     if x > -456 && x < 456 && y > 0 && y < h {
-                        img.get_pixel_mut((x + 539) as u32, y as u32).blend(
+                        img.get_pixel_mut((x + NADIR_A as i32) as u32, y as u32).blend(
                             //value is between 0 and 1. a is between 0 and 255
                             &image::Rgba([r, g, b, (value * a as f64) as u8]),
                         );
-                        img.get_pixel_mut((x + 1579) as u32, y as u32)
+                        img.get_pixel_mut((x + NADIR_B as i32) as u32, y as u32)
                             .blend(&image::Rgba([r, g, b, (value * a as f64) as u8]));
                     }
                 }
             }
-        };
+        }
+    }
 
-    // Draw shapefiles
+    Ok(())
+}
 
-    let filename = res_path!("shapefiles", "states.shp");
-    let mut reader = shapefile::ShapeReader::from_path(&filename)
+/// Land mask of channel A, true on land.
+///
+/// Rasterizes the countries and lakes polygons onto the swath. The image should
+/// not be rotated, the result has `PX_CHANNEL_IMAGE_DATA` values per row.
+pub fn land_mask(georef: &Georef) -> err::Result<Vec<bool>> {
+    info!("Rasterizing land mask");
+
+    let swath = projection::swath_bbox(georef);
+
+    let land = rasterize(georef, &swath, &res_path!("shapefiles", "countries.shp"))?;
+    let lakes = rasterize(georef, &swath, &res_path!("shapefiles", "lakes.shp"))?;
+
+    Ok(land.iter().zip(lakes.iter()).map(|(&l, &w)| l && !w).collect())
+}
+
+/// Fill every polygon on a shapefile, returns true for pixels inside any of
+/// them.
+fn rasterize(georef: &Georef, swath: &BoundingBox, filename: &Path) -> err::Result<Vec<bool>> {
+    let mut rings: Vec<Vec<(f64, f64)>> = Vec::new();
+
+    let mut reader = shapefile::ShapeReader::from_path(filename)
         .map_err(|_| err::Error::Internal(format!("Could not load {:?}", filename)))?;
-    for result in reader.iter_shapes_as::<shapefile::Polyline>() {
-        let polyline = result?;
-        for points in polyline.parts() {
-            let mut prev_pt = &points[0];
-            for pt in points {
-                draw_line(
-                    (pt.y / 180. * PI, pt.x / 180. * PI),
-                    (prev_pt.y / 180. * PI, prev_pt.x / 180. * PI),
-                    settings.states_color,
-                );
-                prev_pt = pt;
+    for result in reader.iter_shapes_as::<shapefile::Polygon>() {
+        let polygon = result?;
+
+        // Skip polygons that can't reach the swath
+        if !overlaps(swath, polygon.bbox()) {
+            continue;
+        }
+
+        for ring in polygon.rings() {
+            use shapefile::record::polygon::PolygonRing;
+            let points = match ring {
+                PolygonRing::Outer(p) | PolygonRing::Inner(p) => p,
+            };
+            rings.push(points.iter().map(|pt| (pt.x, pt.y)).collect());
+        }
+    }
+
+    Ok(fill_rings(georef, swath, &rings))
+}
+
+/// Fill polygon rings given as closed lists of (longitude, latitude) in
+/// degrees, returns true for pixels inside any of them.
+///
+/// Uses a scanline fill with the even-odd rule, that way holes are not filled.
+/// Polygons can share borders but should not overlap.
+fn fill_rings(georef: &Georef, swath: &BoundingBox, rings: &[Vec<(f64, f64)>]) -> Vec<bool> {
+    let width = PX_CHANNEL_IMAGE_DATA as usize;
+    let height = georef.height() as usize;
+    let nadir = projection::nadir_column(false);
+
+    // Clip with some margin, the bounding box is calculated from the borders of
+    // the pixels, not their centers
+    let margin = 1.;
+    let (west, east) = (swath.west - margin, swath.east + margin);
+    let (south, north) = (
+        (swath.south - margin).max(-90.),
+        (swath.north + margin).min(90.),
+    );
+
+    // The swath can cross the antimeridian, the shapefiles don't
+    let regions = if swath.west > swath.east {
+        vec![(west, 180.), (-180., east)]
+    } else {
+        vec![(west, east)]
+    };
+
+    // Horizontal positions where the border of some polygon crosses the
+    // center of each row
+    let mut crossings: Vec<Vec<f64>> = vec![Vec::new(); height];
+
+    for ring in rings {
+        for &(west, east) in &regions {
+            // Far away points are not mapped correctly by the Georef, so keep
+            // only the part of the polygon near the swath
+            let clipped = clip_ring(ring, west, south, east, north);
+            if clipped.is_empty() {
+                continue;
+            }
+
+            let px: Vec<(f64, f64)> = densify(&clipped, 0.5)
+                .iter()
+                .map(|&(lon, lat)| {
+                    let (x, y) =
+                        georef.latlon_to_rel_px_unclamped((lat / 180. * PI, lon / 180. * PI));
+                    (x + nadir, y)
+                })
+                .collect();
+
+            // Rings are closed, the last point is the same as the first
+            for edge in px.windows(2) {
+                let ((x1, y1), (x2, y2)) = (edge[0], edge[1]);
+                if y1 == y2 {
+                    continue;
+                }
+
+                // Rows whose center is in [min(y1, y2), max(y1, y2))
+                let first = (y1.min(y2) - 0.5).ceil().max(0.);
+                let last = (y1.max(y2) - 0.5).ceil().min(height as f64);
+                let mut row = first;
+                while row < last {
+                    let y = row + 0.5;
+                    crossings[row as usize].push(x1 + (y - y1) * (x2 - x1) / (y2 - y1));
+                    row += 1.;
+                }
             }
         }
     }
 
+    let mut mask = vec![false; width * height];
+
+    for (row, xs) in crossings.iter_mut().enumerate() {
+        xs.sort_by(|a, b| a.total_cmp(b));
+
+        for pair in xs.chunks_exact(2) {
+            // Fill pixels whose center is between both crossings
+            let start = (pair[0] - 0.5).ceil().max(0.) as usize;
+            let end = ((pair[1] - 0.5).ceil().max(0.) as usize).min(width);
+            for value in mask[row * width..].iter_mut().take(end).skip(start) {
+                *value = true;
+            }
+        }
+    }
+
+    mask
+}
+
+/// Clip a closed ring of (longitude, latitude) points to a region, using the
+/// Sutherland-Hodgman algorithm.
+///
+/// The result is also closed, or empty if the ring is outside. Where the ring
+/// goes out of the region it's replaced by a path along the border.
+fn clip_ring(ring: &[(f64, f64)], west: f64, south: f64, east: f64, north: f64) -> Vec<(f64, f64)> {
+    // Point where the edge a-b crosses the line where `coord` is `value`
+    let lerp = |a: (f64, f64), b: (f64, f64), coord: fn((f64, f64)) -> f64, value: f64| {
+        let t = (value - coord(a)) / (coord(b) - coord(a));
+        (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+    };
+    let lon: fn((f64, f64)) -> f64 = |p| p.0;
+    let lat: fn((f64, f64)) -> f64 = |p| p.1;
+
+    // Open ring, without the repeated last point
+    let mut points: Vec<(f64, f64)> = ring[..ring.len().saturating_sub(1)].to_vec();
+
+    // Keep the side of each border where `coord * sign >= value * sign`
+    for &(coord, value, sign) in &[
+        (lon, west, 1.),
+        (lon, east, -1.),
+        (lat, south, 1.),
+        (lat, north, -1.),
+    ] {
+        let inside = |p: (f64, f64)| coord(p) * sign >= value * sign;
+
+        let mut result = Vec::with_capacity(points.len());
+        for (i, &current) in points.iter().enumerate() {
+            let previous = points[(i + points.len() - 1) % points.len()];
+            match (inside(previous), inside(current)) {
+                (true, true) => result.push(current),
+                (true, false) => result.push(lerp(previous, current, coord, value)),
+                (false, true) => {
+                    result.push(lerp(previous, current, coord, value));
+                    result.push(current);
+                }
+                (false, false) => {}
+            }
+        }
+        points = result;
+    }
+
+    if let Some(&first) = points.first() {
+        points.push(first);
+    }
+    points
+}
+
+/// Add points to a path so there is no more than `step` degrees between them.
+///
+/// Straight edges on (longitude, latitude) are not straight on the image, so
+/// long ones, like the ones added by `clip_ring()`, need more points.
+fn densify(points: &[(f64, f64)], step: f64) -> Vec<(f64, f64)> {
+    let mut result = Vec::with_capacity(points.len());
+
+    for edge in points.windows(2) {
+        let ((lon1, lat1), (lon2, lat2)) = (edge[0], edge[1]);
+        let parts = ((lon2 - lon1).abs().max((lat2 - lat1).abs()) / step)
+            .ceil()
+            .max(1.) as usize;
+        for i in 0..parts {
+            let t = i as f64 / parts as f64;
+            result.push((lon1 + (lon2 - lon1) * t, lat1 + (lat2 - lat1) * t));
+        }
+    }
+    result.extend(points.last());
+
+    result
+}
+
+/// Points along coastlines and lake shores inside a bounding box, as
+/// (latitude, longitude) in radians.
+///
+/// Borders between countries are skipped because they are not visible on the
+/// images, those are the edges shared by two polygons. Points are decimated to
+/// about one every `spacing` degrees.
+pub fn coastline(bbox: &BoundingBox, spacing: f64) -> err::Result<Vec<(f64, f64)>> {
+    type Key = (u64, u64);
+    let key = |pt: &shapefile::Point| (pt.x.to_bits(), pt.y.to_bits());
+
+    // Times each edge appears, the vertices are stored in order
+    let mut edges: HashMap<(Key, Key), usize> = HashMap::new();
+    let mut vertices: Vec<(Key, Key, shapefile::Point)> = Vec::new();
+
     let filename = res_path!("shapefiles", "countries.shp");
     let mut reader = shapefile::ShapeReader::from_path(&filename)
         .map_err(|_| err::Error::Internal(format!("Could not load {:?}", filename)))?;
     for result in reader.iter_shapes_as::<shapefile::Polygon>() {
         let polygon = result?;
+
+        // Skip polygons that can't reach the swath
+        if !overlaps(bbox, polygon.bbox()) {
+            continue;
+        }
+
         for ring in polygon.rings() {
             use shapefile::record::polygon::PolygonRing;
             let points = match ring {
                 PolygonRing::Outer(p) | PolygonRing::Inner(p) => p,
             };
 
-            let mut prev_pt = &points[0];
-            for pt in points {
-                draw_line(
-                    (pt.y / 180. * PI, pt.x / 180. * PI),
-                    (prev_pt.y / 180. * PI, prev_pt.x / 180. * PI),
-                    settings.countries_color,
-                );
-                prev_pt = pt;
+            for edge in points.windows(2) {
+                let (a, b) = (key(&edge[0]), key(&edge[1]));
+                *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+                vertices.push((a, b, edge[0]));
             }
         }
     }
 
+    let mut coast: Vec<shapefile::Point> = vertices
+        .into_iter()
+        .filter(|(a, b, _)| edges[&(*a.min(b), *a.max(b))] == 1)
+        .map(|(_, _, pt)| pt)
+        .collect();
+
     let filename = res_path!("shapefiles", "lakes.shp");
     let mut reader = shapefile::ShapeReader::from_path(&filename)
         .map_err(|_| err::Error::Internal(format!("Could not load {:?}", filename)))?;
     for result in reader.iter_shapes_as::<shapefile::Polygon>() {
         let polygon = result?;
+
+        // Skip polygons that can't reach the swath
+        if !overlaps(bbox, polygon.bbox()) {
+            continue;
+        }
+
         for ring in polygon.rings() {
             use shapefile::record::polygon::PolygonRing;
             let points = match ring {
                 PolygonRing::Outer(p) | PolygonRing::Inner(p) => p,
             };
-
-            let mut prev_pt = &points[0];
-            for pt in points {
-                draw_line(
-                    (pt.y / 180. * PI, pt.x / 180. * PI),
-                    (prev_pt.y / 180. * PI, prev_pt.x / 180. * PI),
-                    settings.lakes_color,
-                );
-                prev_pt = pt;
-            }
+            coast.extend_from_slice(points);
         }
     }
 
-    Ok(())
+    // Keep one point on each cell of a grid
+    let mut cells: HashSet<(i64, i64)> = HashSet::new();
+    let inside = |pt: &shapefile::Point| {
+        let lon_inside = if bbox.west > bbox.east {
+            pt.x >= bbox.west || pt.x <= bbox.east
+        } else {
+            pt.x >= bbox.west && pt.x <= bbox.east
+        };
+        lon_inside && pt.y >= bbox.south && pt.y <= bbox.north
+    };
+
+    Ok(coast
+        .iter()
+        .filter(|pt| inside(pt))
+        .filter(|pt| {
+            cells.insert((
+                (pt.y / spacing).floor() as i64,
+                (pt.x / spacing).floor() as i64,
+            ))
+        })
+        .map(|pt| (pt.y / 180. * PI, pt.x / 180. * PI))
+        .collect())
+}
+
+/// Check if a shapefile bounding box in degrees overlaps with the swath.
+fn overlaps(swath: &BoundingBox, bbox: &shapefile::record::GenericBBox<shapefile::Point>) -> bool {
+    if bbox.max.y < swath.south || bbox.min.y > swath.north {
+        return false;
+    }
+
+    if swath.west > swath.east {
+        // Crossing the antimeridian
+        bbox.max.x >= swath.west || bbox.min.x <= swath.east
+    } else {
+        bbox.max.x >= swath.west && bbox.min.x <= swath.east
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Track going south along some longitude, in degrees.
+    fn track(lon: f64) -> Georef {
+        let positions = (0..200)
+            .map(|i| ((-30. - i as f64 * 0.03).to_radians(), lon.to_radians()))
+            .collect();
+        Georef::from_track(positions, 0., 1., 1.).unwrap()
+    }
+
+    /// Check the nadir of a pass over Argentina and over the Atlantic.
+    #[test]
+    fn test_land_mask() {
+        let nadir = projection::nadir_column(false) as usize;
+        let width = PX_CHANNEL_IMAGE_DATA as usize;
+
+        let land = land_mask(&track(-62.)).unwrap();
+        assert_eq!(land.len(), width * 200);
+        assert!(land[100 * width + nadir]);
+
+        let sea = land_mask(&track(-40.)).unwrap();
+        assert!(!sea[100 * width + nadir]);
+    }
+
+    /// Check polygons much larger than the swath, with vertices far away from
+    /// it.
+    #[test]
+    fn test_fill_large_polygons() {
+        let georef = track(-62.);
+        let swath = projection::swath_bbox(&georef);
+
+        // Covers the whole swath
+        let continent = vec![vec![
+            (-100., -80.),
+            (-100., 20.),
+            (-20., 20.),
+            (-20., -80.),
+            (-100., -80.),
+        ]];
+        assert!(fill_rings(&georef, &swath, &continent).iter().all(|&v| v));
+
+        // Same one with a hole over the swath
+        let hole = vec![
+            (-85., -45.),
+            (-35., -45.),
+            (-35., -20.),
+            (-85., -20.),
+            (-85., -45.),
+        ];
+        let with_hole = vec![continent[0].clone(), hole];
+        assert!(fill_rings(&georef, &swath, &with_hole).iter().all(|&v| !v));
+
+        // East of the swath
+        let east = vec![vec![
+            (-40., -80.),
+            (-40., 20.),
+            (40., 20.),
+            (40., -80.),
+            (-40., -80.),
+        ]];
+        assert!(fill_rings(&georef, &swath, &east).iter().all(|&v| !v));
+    }
 }
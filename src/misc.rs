@@ -12,6 +12,7 @@ use crate::config::Settings;
 use crate::dsp::{self, Signal};
 use crate::err;
 use crate::noaa_apt::{RefTime, SatName};
+use crate::tle;
 
 /// Lookup table for numbers used in `bessel_i0()`
 ///
@@ -204,6 +205,30 @@ pub fn write_timestamp(timestamp: i64, filename: &Path) -> err::Result<()> {
     Ok(())
 }
 
+/// Header of a NumPy `.npy` file (version 1.0) with an array of little endian
+/// 32 bit floats of the given shape.
+pub fn npy_header(shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [len] => format!("({},)", len),
+        _ => format!(
+            "({})",
+            shape.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(", ")
+        ),
+    };
+    let mut dict = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}", shape);
+
+    // The header, including the magic string, version and length, must be a
+    // multiple of 64 bytes and end with a newline
+    let unpadded = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    dict.push('\n');
+
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
 /// Parse filename to get recording time and satellite name.
 ///
 /// Provide timezone to use.
@@ -408,11 +433,16 @@ fn read_from_file(filename: &Path) -> err::Result<String> {
 
 /// Download, save and return TLE from URL.
 ///
-/// Returns an error if unable to download TLE. Logs error message if unable to
-/// save to file.
+/// Also adds it to the TLE archive. Returns an error if unable to download TLE.
+/// Logs error message if unable to save to file.
 fn download_save_return_tle(addr: &str, filename: &Path) -> err::Result<String> {
     let tle = download_tle(addr)?;
 
+    match tle::store(&tle) {
+        Ok(count) => info!("Added {} element sets to the TLE archive", count),
+        Err(e) => error!("Could not archive TLE: {}", e),
+    }
+
     let mut file = match fs::File::create(filename) {
         Ok(f) => f,
         Err(e) => {
@@ -512,6 +542,22 @@ mod tests {
         assert_relative_eq!(bessel_i0(7.),  168.593908510290, max_relative = tolerance);
     }
 
+    #[test]
+    fn test_npy_header() {
+        for (shape, expected) in &[(vec![1234], "(1234,)"), (vec![10, 2080], "(10, 2080)")] {
+            let header = npy_header(shape);
+            assert_eq!(header.len() % 64, 0);
+            assert_eq!(&header[..8], b"\x93NUMPY\x01\x00");
+            assert_eq!(*header.last().unwrap(), b'\n');
+
+            let dict = String::from_utf8(header[10..].to_vec()).unwrap();
+            assert!(dict.starts_with(&format!(
+                "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+                expected
+            )));
+        }
+    }
+
     #[test]
     fn test_percent() {
         use std::iter::Iterator;
@@ -0,0 +1,120 @@
+//! Blending of several passes on a common grid.
+//!
+//! Each pass is reprojected like on `projection::reproject()`, but instead of
+//! keeping a single value per pixel, I keep a weighted sum of every pass that
+//! covers it. Pixels near the nadir have a higher weight because they have
+//! better resolution and less distortion.
+
+use image::Rgba;
+use log::info;
+
+use crate::decode::PX_CHANNEL_IMAGE_DATA;
+use crate::georef::Georef;
+use crate::noaa_apt::Image;
+use crate::projection::{self, Grid};
+
+/// Minimum weight, so the borders of the swath are not lost when covered by a
+/// single pass.
+const MIN_WEIGHT: f32 = 0.001;
+
+/// Weight of a pixel given its distance to the nadir column.
+fn nadir_weight(nadir_distance: f64) -> f32 {
+    let half_width = PX_CHANNEL_IMAGE_DATA as f64 / 2.;
+    let w = (1. - nadir_distance.abs() / half_width).max(0.);
+
+    (w * w) as f32 + MIN_WEIGHT
+}
+
+/// Weighted sum of several reprojected passes.
+pub struct Mosaic {
+    grid: Grid,
+
+    /// Weighted sum of the red, green and blue values.
+    sum: Vec<[f32; 3]>,
+
+    /// Sum of the weights.
+    weights: Vec<f32>,
+}
+
+impl Mosaic {
+    pub fn new(grid: Grid) -> Mosaic {
+        let len = grid.width as usize * grid.height as usize;
+        Mosaic {
+            grid,
+            sum: vec![[0.; 3]; len],
+            weights: vec![0.; len],
+        }
+    }
+
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    /// Add a pass.
+    ///
+    /// Takes a single channel image as given by `georef::crop_channel()`. If
+    /// `rotated` is true, the image was rotated 180 degrees.
+    pub fn add(&mut self, channel_img: &Image, georef: &Georef, rotated: bool) {
+        info!(
+            "Adding pass to mosaic, {}x{} pixels",
+            self.grid.width, self.grid.height
+        );
+
+        let nadir = projection::nadir_column(rotated);
+
+        for y in 0..self.grid.height {
+            for x in 0..self.grid.width {
+                let latlon = self.grid.px_to_latlon((x, y));
+                if let Some(px) = projection::latlon_to_channel_px(georef, rotated, latlon) {
+                    let value = projection::interpolate(channel_img, px);
+                    let weight = nadir_weight(px.0 - nadir);
+
+                    let i = (y * self.grid.width + x) as usize;
+                    for (sum, v) in self.sum[i].iter_mut().zip(value.0.iter()) {
+                        *sum += *v as f32 * weight;
+                    }
+                    self.weights[i] += weight;
+                }
+            }
+        }
+    }
+
+    /// Get the blended image, pixels not covered by any pass are transparent.
+    pub fn image(&self) -> Image {
+        let mut result = Image::new(self.grid.width, self.grid.height);
+
+        for (i, (sum, &weight)) in self.sum.iter().zip(self.weights.iter()).enumerate() {
+            if weight > 0. {
+                let x = i as u32 % self.grid.width;
+                let y = i as u32 / self.grid.width;
+                result.put_pixel(
+                    x,
+                    y,
+                    Rgba([
+                        (sum[0] / weight).round() as u8,
+                        (sum[1] / weight).round() as u8,
+                        (sum[2] / weight).round() as u8,
+                        255,
+                    ]),
+                );
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check that pixels near the nadir are preferred.
+    #[test]
+    fn test_nadir_weight() {
+        assert!(nadir_weight(0.) > nadir_weight(100.));
+        assert!(nadir_weight(-100.) > nadir_weight(300.));
+        assert_eq!(nadir_weight(100.), nadir_weight(-100.));
+        assert!(nadir_weight(1000.) > 0.);
+    }
+}
@@ -3,20 +3,34 @@
 //! Used by both the command-line and GUI versions of the program.
 
 pub use crate::decode::{decode, FINAL_RATE, PX_PER_CHANNEL, PX_PER_ROW};
+pub use crate::georef::Channel;
+pub use crate::iq::{load_iq, DopplerSettings, IqFormat, IqSettings};
+pub use crate::projection::{BoundingBox, Projection};
 pub use crate::resample::resample;
+pub use crate::stream::{decode_stream, RawFormat};
 
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
-use log::warn;
+use log::{info, warn};
 
+use crate::config;
+use crate::align;
+use crate::calibration;
 use crate::context::Context;
 use crate::dsp;
 use crate::dsp::{Rate, Signal};
 use crate::err;
+use crate::georef;
 use crate::map;
 use crate::misc;
+use crate::mosaic::Mosaic;
+use crate::orbit;
+use crate::parallel;
 use crate::processing;
+use crate::projection;
 use crate::telemetry;
+use crate::tle;
 use crate::wav;
 use image::GrayImage;
 
@@ -68,6 +82,90 @@ pub struct ColorSettings {
     pub ch_a_tune_end: f32,
     pub ch_b_tune_start: f32,
     pub ch_b_tune_end: f32,
+
+    /// If set, colorize using calibrated temperatures of channel B instead of
+    /// the palette and tune values above.
+    pub enhancement: Option<Enhancement>,
+
+    /// If set, the palette above is used on daylight and this one at night,
+    /// blending them according to the solar zenith angle of each pixel.
+    pub night_palette_filename: Option<PathBuf>,
+
+    /// If true, draw clouds from channel B over a land and sea background
+    /// instead of using a palette.
+    pub mcir: bool,
+}
+
+/// Temperatures in Celsius of the first and last rows of the palettes used by
+/// enhancements.
+///
+/// The WXtoImg sea palette colors the rows 79 to 146, so the scale is chosen
+/// to put there the sea surface temperatures, from 32 degrees to the freezing
+/// point of sea water (-2 degrees). Every palette uses the same scale.
+const PALETTE_TEMPERATURES: (f32, f32) = (72.1, -57.3);
+
+/// Enhancements driven by brightness temperatures of channel B.
+///
+/// The palettes are like the false color ones, the X axis is the channel A
+/// value and the Y axis is the channel B temperature, mapped linearly with
+/// `PALETTE_TEMPERATURES`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Enhancement {
+    /// Sea surface temperature, colors water between -2 and 32 degrees and
+    /// leaves land and clouds black.
+    SeaSurface,
+
+    /// Cloud top temperature, uses the WXtoImg NO palette.
+    CloudTop,
+
+    /// Thermal, colors the whole temperature scale.
+    Thermal,
+}
+
+impl Enhancement {
+    pub fn from_name(name: &str) -> Option<Enhancement> {
+        match name {
+            "sea" => Some(Enhancement::SeaSurface),
+            "cloud-top" => Some(Enhancement::CloudTop),
+            "thermal" => Some(Enhancement::Thermal),
+            _ => None,
+        }
+    }
+
+    pub fn palette_filename(self) -> PathBuf {
+        match self {
+            Enhancement::SeaSurface => res_path!("palettes", "WXtoImg-sea.png"),
+            Enhancement::CloudTop => res_path!("palettes", "WXtoImg-NO.png"),
+            Enhancement::Thermal => res_path!("palettes", "noaa-apt-thermal.png"),
+        }
+    }
+
+    /// First and last rows of the palette with meaningful colors.
+    fn palette_rows(self) -> (u32, u32) {
+        match self {
+            Enhancement::SeaSurface => (79, 146),
+            Enhancement::CloudTop | Enhancement::Thermal => (0, 255),
+        }
+    }
+
+    /// Temperatures in Celsius shown on the legend, from coldest to warmest.
+    ///
+    /// The ones of the rows given by `palette_rows()`.
+    pub fn legend_range(self) -> (f32, f32) {
+        let (first, last) = PALETTE_TEMPERATURES;
+        let row_temperature = |row: u32| first + (last - first) * row as f32 / 255.;
+
+        let (warmest_row, coldest_row) = self.palette_rows();
+        (row_temperature(coldest_row), row_temperature(warmest_row))
+    }
+
+    /// Row of the palette to use for some temperature in Celsius.
+    pub fn palette_row(self, celsius: f32) -> u32 {
+        let (first, last) = PALETTE_TEMPERATURES;
+        ((celsius - first) / (last - first) * 255.)
+            .round()
+            .clamp(0., 255.) as u32
+    }
 }
 
 /// Settings that need orbit calculations.
@@ -77,6 +175,35 @@ pub struct OrbitSettings {
     pub custom_tle: Option<String>,
     pub ref_time: RefTime,
     pub draw_map: Option<MapSettings>,
+
+    /// Warn if the TLE epoch is more than this number of days away from the
+    /// recording time.
+    pub max_epoch_days: i64,
+
+    /// TLE selected by `tle::for_orbit()` the first time it's needed, shared
+    /// between clones so the map, georeference, etc. use the same one.
+    pub tle: Arc<OnceLock<String>>,
+}
+
+/// Settings for georeferenced exports.
+#[derive(Clone, Debug)]
+pub struct GeorefSettings {
+    pub filename: PathBuf,
+    pub channel: Channel,
+}
+
+/// Settings for saving a channel reprojected to a map.
+#[derive(Clone, Debug)]
+pub struct ProjectionSettings {
+    pub filename: PathBuf,
+    pub channel: Channel,
+    pub projection: Projection,
+
+    /// Region to save, if `None` the whole swath is saved.
+    pub bbox: Option<BoundingBox>,
+
+    /// Size of each pixel in degrees of longitude.
+    pub resolution: f64,
 }
 
 /// Settings related to map overlays.
@@ -91,7 +218,7 @@ pub struct MapSettings {
 }
 
 /// Available satellites enum.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SatName {
     Noaa15,
     Noaa18,
@@ -191,7 +318,56 @@ pub fn process(
     let mut img: Image = image::DynamicImage::ImageLuma8(img).into_rgba8(); // convert to RGBA
 
     if let Some(color_settings) = &color {
-        processing::false_color(&mut img, color_settings)?;
+        if let Some(enhancement) = color_settings.enhancement {
+            context.status(0.4, "Calibrating thermal channel".to_string());
+
+            let sat_name = match &orbit {
+                Some(orbit_settings) => &orbit_settings.sat_name,
+                None => {
+                    return Err(err::Error::InvalidInput(
+                        "Can't calibrate if no satellite is provided".to_string(),
+                    ))
+                }
+            };
+            let telemetry = telemetry::read_telemetry(context, signal)?;
+            let calibration = calibration::Calibration::new(&telemetry, sat_name)?;
+            let temperatures =
+                calibration::channel_b_temperatures(&telemetry, &calibration, signal);
+
+            processing::temperature_color(&mut img, &temperatures, enhancement)?;
+        } else if color_settings.mcir {
+            context.status(0.4, "Rasterizing land mask".to_string());
+
+            let orbit_settings = orbit.as_ref().ok_or_else(|| {
+                err::Error::InvalidInput(
+                    "Can't generate MCIR image if no satellite and time is provided".to_string(),
+                )
+            })?;
+            let (georef, _) = georeference(height, Rotate::No, orbit_settings)?;
+            let land_mask = map::land_mask(&georef)?;
+
+            processing::mcir(&mut img, &land_mask)?;
+        } else if let Some(night_palette_filename) = &color_settings.night_palette_filename {
+            context.status(0.4, "Calculating daylight".to_string());
+
+            let orbit_settings = orbit.as_ref().ok_or_else(|| {
+                err::Error::InvalidInput(
+                    "Can't blend day and night if no satellite and time is provided".to_string(),
+                )
+            })?;
+            let (georef, _) = georeference(height, Rotate::No, orbit_settings)?;
+            let start_time = georef::start_time(&orbit_settings.ref_time, height);
+            let day_weights = processing::day_weights(&georef, start_time);
+
+            processing::day_night_false_color(
+                &mut img,
+                color_settings,
+                night_palette_filename,
+                &day_weights,
+            )?;
+        } else {
+            processing::false_color(&mut img, color_settings)?;
+        }
     }
 
     if let Contrast::Histogram = contrast_adjustment {
@@ -201,13 +377,10 @@ pub fn process(
     // --------------------
 
     if let Some(orbit_settings) = orbit.clone() {
-        if let Some(map_settings) = orbit_settings.draw_map {
+        if let Some(map_settings) = orbit_settings.draw_map.clone() {
             context.status(0.5, "Drawing map".to_string());
 
-            let tle = match orbit_settings.custom_tle {
-                Some(t) => t,
-                None => misc::get_current_tle()?,
-            };
+            let tle = tle::for_orbit(&orbit_settings)?;
 
             map::draw_map(
                 &mut img,
@@ -242,18 +415,314 @@ pub fn process(
     Ok(img)
 }
 
+/// Add a legend with the temperature scale of an enhancement.
+///
+/// Returns a taller image with the legend below, so it should be called after
+/// doing anything that needs the original image height like `save_georeferenced()`.
+pub fn draw_legend(img: &Image, enhancement: Enhancement) -> err::Result<Image> {
+    let palette = processing::load_palette(&enhancement.palette_filename())?;
+    Ok(processing::draw_legend(img, &palette, enhancement))
+}
+
+/// Align the map overlay by matching coastlines to the edges on the image.
+///
+/// Returns the orbit settings with the fitted recording time and map
+/// corrections, to give to `process()` and the exports. The signal should be
+/// the one returned by `decode()`.
+pub fn align_map(
+    context: &mut Context,
+    signal: &Signal,
+    orbit: &OrbitSettings,
+) -> err::Result<OrbitSettings> {
+    context.status(0., "Aligning map overlay".to_string());
+
+    let alignment = align::align(signal, orbit)?;
+
+    info!(
+        "Map alignment: time offset {:.1} s, yaw {:.2}°, horizontal scale {:.1}%, \
+        vertical scale {:.1}%, score {:.2}",
+        alignment.time_offset().num_milliseconds() as f64 / 1000.,
+        alignment.yaw.to_degrees(),
+        alignment.hscale * 100.,
+        alignment.vscale * 100.,
+        alignment.score,
+    );
+
+    Ok(alignment.apply(orbit))
+}
+
+/// Calculate the relation between pixels and coordinates of an image of
+/// `height` rows.
+///
+/// Also returns true if the image was rotated. Should be given the same
+/// settings given to `process()`.
+fn georeference(
+    height: u32,
+    rotate: Rotate,
+    orbit: &OrbitSettings,
+) -> err::Result<(georef::Georef, bool)> {
+    let tle = tle::for_orbit(orbit)?;
+
+    let (yaw, hscale, vscale) = match &orbit.draw_map {
+        Some(m) => (m.yaw, m.hscale, m.vscale),
+        None => (0., 1., 1.),
+    };
+
+    let georef = georef::Georef::new(
+        &tle,
+        &orbit.sat_name,
+        &orbit.ref_time,
+        height,
+        yaw,
+        hscale,
+        vscale,
+    )?;
+
+    let rotated = processing::is_rotated(&rotate, orbit)?;
+
+    Ok((georef, rotated))
+}
+
+/// Save a single channel of the image with ground control points.
+///
+/// The result can be reprojected by GIS software like QGIS or GDAL. Should be
+/// given the same image and settings returned by and given to `process()`.
+pub fn save_georeferenced(
+    context: &mut Context,
+    img: &Image,
+    rotate: Rotate,
+    orbit: &OrbitSettings,
+    channel: Channel,
+    filename: &Path,
+) -> err::Result<()> {
+    context.status(0.95, "Georeferencing".to_string());
+
+    let (georef, rotated) = georeference(img.height(), rotate, orbit)?;
+
+    let gcps = georef::channel_gcps(&georef, rotated, 100);
+    let channel_img = georef::crop_channel(img, channel);
+
+    georef::write_georeferenced(&channel_img, &gcps, filename)
+}
+
+/// Save a single channel of the image reprojected to a map.
+///
+/// Should be given the same image and settings returned by and given to
+/// `process()`.
+pub fn save_projected(
+    context: &mut Context,
+    img: &Image,
+    rotate: Rotate,
+    orbit: &OrbitSettings,
+    settings: &ProjectionSettings,
+) -> err::Result<()> {
+    context.status(0.95, "Reprojecting".to_string());
+
+    let (georef, rotated) = georeference(img.height(), rotate, orbit)?;
+
+    let bbox = match settings.bbox {
+        Some(bbox) => bbox,
+        None => projection::swath_bbox(&georef),
+    };
+    let grid = projection::Grid::new(settings.projection, bbox, settings.resolution)?;
+
+    let channel_img = georef::crop_channel(img, settings.channel);
+    let projected = projection::reproject(&channel_img, &georef, rotated, &grid);
+
+    projection::write_projected(&projected, &grid, &settings.filename)
+}
+
+/// Decode several passes and blend them on a map.
+///
+/// The passes are decoded one by one and reprojected to a common grid, if
+/// `settings.bbox` is `None` the grid covers every pass. The map corrections
+/// from `draw_map` are used to georeference each pass, but the map is not
+/// drawn.
+pub fn mosaic(
+    context: &mut Context,
+    settings: &config::Settings,
+    passes: &[(PathBuf, OrbitSettings)],
+    sync: bool,
+    contrast_adjustment: Contrast,
+    projection_settings: &ProjectionSettings,
+) -> err::Result<()> {
+    if passes.is_empty() {
+        return Err(err::Error::InvalidInput("No passes given".to_string()));
+    }
+
+    let mut georeferenced = Vec::with_capacity(passes.len());
+
+    for (filename, orbit) in passes {
+        context.status(0., format!("Decoding {}", filename.display()));
+
+        let (signal, rate) = load(filename)?;
+        let (raw_data, _sync_report, _quality) = decode(context, settings, &signal, rate, sync)?;
+
+        let mut process_orbit = orbit.clone();
+        process_orbit.draw_map = None;
+        let img = process(
+            context,
+            &raw_data,
+            contrast_adjustment.clone(),
+            Rotate::No,
+            None,
+            Some(process_orbit),
+        )?;
+
+        let (georef, rotated) = georeference(img.height(), Rotate::No, orbit)?;
+        georeferenced.push((georef::crop_channel(&img, projection_settings.channel), georef, rotated));
+    }
+
+    let bbox = match projection_settings.bbox {
+        Some(bbox) => bbox,
+        None => georeferenced
+            .iter()
+            .map(|(_, georef, _)| projection::swath_bbox(georef))
+            .fold(None, |acc: Option<BoundingBox>, bbox| match acc {
+                Some(acc) => Some(acc.union(&bbox)),
+                None => Some(bbox),
+            })
+            .unwrap(), // There is at least one pass
+    };
+
+    let grid = projection::Grid::new(
+        projection_settings.projection,
+        bbox,
+        projection_settings.resolution,
+    )?;
+    let mut mosaic = Mosaic::new(grid);
+
+    for (i, (channel_img, georef, rotated)) in georeferenced.iter().enumerate() {
+        context.status(
+            0.5 + 0.5 * i as f32 / georeferenced.len() as f32,
+            format!("Blending pass {} of {}", i + 1, georeferenced.len()),
+        );
+        mosaic.add(channel_img, georef, *rotated);
+    }
+
+    projection::write_projected(&mosaic.image(), mosaic.grid(), &projection_settings.filename)
+}
+
+/// Save a report of the telemetry, in physical units when possible.
+///
+/// Takes the raw image data returned by `decode()`. The orbit settings are
+/// used to know the satellite and recording start time.
+pub fn save_telemetry_report(
+    context: &mut Context,
+    signal: &Signal,
+    orbit: Option<&OrbitSettings>,
+    filename: &Path,
+) -> err::Result<()> {
+    context.status(0.95, "Reading telemetry".to_string());
+
+    let telemetry = telemetry::read_telemetry(context, signal)?;
+
+    let time = orbit.map(|o| match o.ref_time {
+        RefTime::Start(time) => time,
+        RefTime::End(time) => {
+            time - georef::line_duration() * (signal.len() as u32 / PX_PER_ROW) as i32
+        }
+    });
+
+    let report = telemetry.report(orbit.map(|o| &o.sat_name), time);
+    telemetry::write_report(&report, filename)
+}
+
+/// Save brightness temperatures of channel B in Kelvin.
+///
+/// Takes the raw image data returned by `decode()`, channel B should be a
+/// thermal channel. If the filename ends in `.npy` a NumPy array is saved,
+/// if it ends in `.tif` or `.tiff` a float GeoTIFF with ground control points
+/// is saved. Values are rotated like the image given the same `rotate`
+/// setting.
+pub fn save_temperatures(
+    context: &mut Context,
+    signal: &Signal,
+    rotate: Rotate,
+    orbit: &OrbitSettings,
+    filename: &Path,
+) -> err::Result<()> {
+    context.status(0.95, "Calibrating thermal channel".to_string());
+
+    let telemetry = telemetry::read_telemetry(context, signal)?;
+    let calibration = calibration::Calibration::new(&telemetry, &orbit.sat_name)?;
+    let mut temperatures = calibration::channel_b_temperatures(&telemetry, &calibration, signal);
+
+    let width = crate::decode::PX_CHANNEL_IMAGE_DATA;
+    let height = signal.len() as u32 / PX_PER_ROW;
+
+    let extension = filename
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match extension.as_deref() {
+        Some("npy") => {
+            if processing::is_rotated(&rotate, orbit)? {
+                temperatures.reverse(); // Same as rotating 180 degrees
+            }
+            calibration::write_npy(&temperatures, width, height, filename)
+        }
+        Some("tif") | Some("tiff") => {
+            let (georef, rotated) = georeference(height, rotate, orbit)?;
+            if rotated {
+                temperatures.reverse(); // Same as rotating 180 degrees
+            }
+            let gcps = georef::channel_gcps(&georef, rotated, 100);
+            georef::write_geotiff_tags(
+                georef::Raster::Float {
+                    data: &temperatures,
+                    width,
+                    height,
+                },
+                &georef::gcp_tiepoints(&gcps),
+                None,
+                &georef::GEOKEYS_WGS84,
+                filename,
+            )
+        }
+        _ => Err(err::Error::InvalidInput(
+            "Temperatures can only be saved as .npy or .tif".to_string(),
+        )),
+    }
+}
+
+/// List upcoming passes over the ground station set on the settings.
+///
+/// Looks `hours` ahead from now, skipping passes lower than `min_elevation`
+/// degrees.
+pub fn predict_passes(
+    settings: &config::Settings,
+    custom_tle: Option<&str>,
+    hours: f64,
+    min_elevation: f64,
+) -> err::Result<Vec<orbit::Pass>> {
+    let tle = match custom_tle {
+        Some(t) => t.to_string(),
+        None => misc::get_current_tle()?,
+    };
+
+    let start = chrono::Utc::now();
+    let end = start + chrono::Duration::seconds((hours * 3600.) as i64);
+
+    orbit::predict_passes(&tle, &settings.ground_station, start, end, min_elevation)
+}
+
 /// Maps float signal values to `u8`.
 ///
 /// `low` becomes 0 and `high` becomes 255. Values are clamped to prevent `u8`
 /// overflow.
-fn map_signal_u8(signal: &Signal, low: f32, high: f32) -> Vec<u8> {
+pub fn map_signal_u8(signal: &[f32], low: f32, high: f32) -> Vec<u8> {
     let range = high - low;
-    let raw_data: Vec<u8> = signal
-        .iter()
-        .map(|x|
-             // Map and clamp between 0 and 255 using min() and max()
-             ((x - low) / range * 255.).max(0.).min(255.).round() as u8)
-        .collect();
+    let raw_data: Vec<u8> = parallel::map_ranges(signal.len(), 1, parallel::MIN_CHUNK, |chunk| {
+        signal[chunk]
+            .iter()
+            .map(|x|
+                 // Map and clamp between 0 and 255 using min() and max()
+                 ((x - low) / range * 255.).max(0.).min(255.).round() as u8)
+            .collect::<Vec<u8>>()
+    })
+    .concat();
 
     raw_data
 }
@@ -279,4 +748,27 @@ mod tests {
 
         assert_eq!(expected, map_signal_u8(&shifted_values, low, high));
     }
+
+    /// Colder temperatures should be further down the palette, and the legend
+    /// should show the temperatures of the rows used.
+    #[test]
+    fn test_enhancement_palette_row() {
+        for enhancement in &[Enhancement::SeaSurface, Enhancement::CloudTop, Enhancement::Thermal] {
+            assert_eq!(enhancement.palette_row(100.), 0);
+            assert_eq!(enhancement.palette_row(-150.), 255);
+            assert!(enhancement.palette_row(0.) > enhancement.palette_row(20.));
+
+            let (coldest, warmest) = enhancement.legend_range();
+            assert_eq!(
+                (enhancement.palette_row(warmest), enhancement.palette_row(coldest)),
+                enhancement.palette_rows()
+            );
+        }
+
+        // Colored part of the sea palette
+        assert_eq!(Enhancement::SeaSurface.palette_row(32.), 79);
+        assert_eq!(Enhancement::SeaSurface.palette_row(-2.), 146);
+        let (coldest, warmest) = Enhancement::SeaSurface.legend_range();
+        assert!((coldest + 2.).abs() < 0.1 && (warmest - 32.).abs() < 0.1);
+    }
 }
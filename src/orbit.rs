@@ -0,0 +1,469 @@
+//! Orbit calculations relative to a ground station.
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use log::warn;
+use serde::Deserialize;
+
+use crate::err;
+use crate::noaa_apt::SatName;
+
+/// Speed of light in km/s.
+const SPEED_OF_LIGHT: f64 = 299_792.458;
+
+/// WGS84 equatorial radius in km.
+const EARTH_RADIUS: f64 = 6378.137;
+
+/// WGS84 flattening.
+const EARTH_FLATTENING: f64 = 1. / 298.257_223_563;
+
+/// Time step when looking for passes. Short enough to not miss low passes.
+const PASS_SEARCH_STEP: i64 = 30; // Seconds
+
+/// Location of the receiver.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct GroundStation {
+    /// Latitude in degrees, positive to the North.
+    pub latitude: f64,
+
+    /// Longitude in degrees, positive to the East.
+    pub longitude: f64,
+
+    /// Altitude above sea level in meters.
+    pub altitude: f64,
+}
+
+impl GroundStation {
+    /// Position in ECI coordinates (km), given the Greenwich sidereal time in
+    /// radians.
+    fn eci_position(&self, gmst: f64) -> (f64, f64, f64) {
+        let lat = self.latitude.to_radians();
+        let lon = self.longitude.to_radians() + gmst;
+        let alt = self.altitude / 1000.;
+
+        // Radius of curvature on the prime vertical
+        let e2 = EARTH_FLATTENING * (2. - EARTH_FLATTENING);
+        let n = EARTH_RADIUS / (1. - e2 * lat.sin().powi(2)).sqrt();
+
+        (
+            (n + alt) * lat.cos() * lon.cos(),
+            (n + alt) * lat.cos() * lon.sin(),
+            (n * (1. - e2) + alt) * lat.sin(),
+        )
+    }
+
+    /// Azimuth and elevation in degrees of a point in ECI coordinates (km),
+    /// given the Greenwich sidereal time in radians.
+    ///
+    /// Azimuth is measured clockwise from the North.
+    fn look_angles(&self, gmst: f64, position: (f64, f64, f64)) -> (f64, f64) {
+        let (x, y, z) = self.eci_position(gmst);
+        let (rx, ry, rz) = (position.0 - x, position.1 - y, position.2 - z);
+
+        let lat = self.latitude.to_radians();
+        let theta = self.longitude.to_radians() + gmst; // Local sidereal time
+
+        // Topocentric coordinates: South, East and Zenith
+        let south = lat.sin() * theta.cos() * rx + lat.sin() * theta.sin() * ry - lat.cos() * rz;
+        let east = -theta.sin() * rx + theta.cos() * ry;
+        let zenith = lat.cos() * theta.cos() * rx + lat.cos() * theta.sin() * ry + lat.sin() * rz;
+
+        let range = (rx * rx + ry * ry + rz * rz).sqrt();
+        let azimuth = east.atan2(-south).to_degrees().rem_euclid(360.);
+        let elevation = (zenith / range).asin().to_degrees();
+
+        (azimuth, elevation)
+    }
+}
+
+/// Direction of the satellite during a pass.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Northbound,
+    Southbound,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Northbound => write!(f, "northbound"),
+            Direction::Southbound => write!(f, "southbound"),
+        }
+    }
+}
+
+/// Pass of a satellite over the ground station.
+#[derive(Clone, Debug)]
+pub struct Pass {
+    pub sat_name: SatName,
+
+    /// Acquisition of signal, when the satellite rises over the horizon.
+    pub aos: DateTime<Utc>,
+
+    /// Loss of signal, when the satellite sets.
+    pub los: DateTime<Utc>,
+
+    /// Time of maximum elevation.
+    pub max_elevation_time: DateTime<Utc>,
+
+    /// Maximum elevation in degrees.
+    pub max_elevation: f64,
+
+    /// Azimuth in degrees at AOS.
+    pub aos_azimuth: f64,
+
+    /// Azimuth in degrees at LOS.
+    pub los_azimuth: f64,
+
+    pub direction: Direction,
+}
+
+/// Get the satellite from a TLE file.
+pub fn find_sat(tle: &str, sat_name: &SatName) -> err::Result<satellite::io::Satrec> {
+    let (sats, _errors) = satellite::io::parse_multiple(tle);
+    let sat_string = sat_name.to_string();
+
+    Ok(sats
+        .iter()
+        .find(|&sat| sat.name.as_ref() == Some(&sat_string))
+        .ok_or_else(|| {
+            err::Error::Internal(format!("Satellite \"{}\" not found in TLE", sat_string))
+        })?
+        .clone())
+}
+
+/// APT downlink frequency in Hz.
+pub fn downlink_frequency(sat_name: &SatName) -> f64 {
+    match sat_name {
+        SatName::Noaa15 => 137_620_000.,
+        SatName::Noaa18 => 137_912_500.,
+        SatName::Noaa19 => 137_100_000.,
+    }
+}
+
+/// Distance between the satellite and the ground station in km.
+pub fn range(
+    sat: &satellite::io::Satrec,
+    station: &GroundStation,
+    time: chrono::DateTime<chrono::Utc>,
+) -> err::Result<f64> {
+    let result = satellite::propogation::propogate_datetime(sat, time)
+        .map_err(|_| err::Error::Internal("Could not propagate orbit".to_string()))?;
+    let gmst = satellite::propogation::gstime::gstime_datetime(time);
+    let (x, y, z) = station.eci_position(gmst);
+
+    Ok(((result.position.x - x).powi(2)
+        + (result.position.y - y).powi(2)
+        + (result.position.z - z).powi(2))
+    .sqrt())
+}
+
+/// Human readable table of passes, times in UTC.
+pub fn passes_table(passes: &[Pass]) -> String {
+    let mut table = format!(
+        "{:<9} {:<19}  {:<8}  {:>7}  {:>6}  {:>6}  {}\n",
+        "Satellite", "AOS (UTC)", "LOS", "Max el", "AOS az", "LOS az", "Direction"
+    );
+
+    for pass in passes {
+        table.push_str(&format!(
+            "{:<9} {}  {}  {:>6.1}°  {:>5.0}°  {:>5.0}°  {}\n",
+            pass.sat_name.to_string(),
+            pass.aos.format("%Y-%m-%d %H:%M:%S"),
+            pass.los.format("%H:%M:%S"),
+            pass.max_elevation,
+            pass.aos_azimuth,
+            pass.los_azimuth,
+            pass.direction,
+        ));
+    }
+
+    table
+}
+
+/// Passes as a JSON array, times as RFC 3339 and angles in degrees.
+pub fn passes_json(passes: &[Pass]) -> err::Result<String> {
+    let values: Vec<serde_json::Value> = passes
+        .iter()
+        .map(|pass| {
+            serde_json::json!({
+                "satellite": pass.sat_name.to_string(),
+                "aos": pass.aos.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "los": pass.los.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "max_elevation_time":
+                    pass.max_elevation_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "max_elevation": pass.max_elevation,
+                "aos_azimuth": pass.aos_azimuth,
+                "los_azimuth": pass.los_azimuth,
+                "direction": pass.direction.to_string(),
+            })
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&values)
+        .map_err(|e| err::Error::Internal(format!("Could not write JSON: {}", e)))
+}
+
+/// Position of the satellite in ECI coordinates (km) and Greenwich sidereal
+/// time in radians.
+fn sat_position(
+    sat: &satellite::io::Satrec,
+    time: DateTime<Utc>,
+) -> err::Result<((f64, f64, f64), f64)> {
+    let result = satellite::propogation::propogate_datetime(sat, time)
+        .map_err(|_| err::Error::Internal("Could not propagate orbit".to_string()))?;
+    let gmst = satellite::propogation::gstime::gstime_datetime(time);
+
+    Ok(((result.position.x, result.position.y, result.position.z), gmst))
+}
+
+/// Azimuth and elevation of the satellite in degrees.
+pub fn look_angles(
+    sat: &satellite::io::Satrec,
+    station: &GroundStation,
+    time: DateTime<Utc>,
+) -> err::Result<(f64, f64)> {
+    let (position, gmst) = sat_position(sat, time)?;
+    Ok(station.look_angles(gmst, position))
+}
+
+/// List passes of NOAA 15, 18 and 19 starting between `start` and `end`,
+/// sorted by AOS.
+///
+/// Passes whose maximum elevation is lower than `min_elevation` degrees are
+/// skipped. Satellites missing from the TLE are skipped with a warning.
+pub fn predict_passes(
+    tle: &str,
+    station: &GroundStation,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    min_elevation: f64,
+) -> err::Result<Vec<Pass>> {
+    let mut passes = Vec::new();
+
+    for sat_name in &[SatName::Noaa15, SatName::Noaa18, SatName::Noaa19] {
+        match find_sat(tle, sat_name) {
+            Ok(sat) => passes.extend(
+                sat_passes(&sat, sat_name, station, start, end)?
+                    .into_iter()
+                    .filter(|pass| pass.max_elevation >= min_elevation),
+            ),
+            Err(e) => warn!("Skipping satellite: {}", e),
+        }
+    }
+
+    passes.sort_by_key(|pass| pass.aos);
+    Ok(passes)
+}
+
+/// Passes of one satellite starting between `start` and `end`.
+///
+/// Steps through the orbit looking for the satellite crossing the horizon,
+/// then refines the times by bisection. A pass already in progress at `start`
+/// has its AOS there.
+fn sat_passes(
+    sat: &satellite::io::Satrec,
+    sat_name: &SatName,
+    station: &GroundStation,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> err::Result<Vec<Pass>> {
+    let step = Duration::seconds(PASS_SEARCH_STEP);
+    let elevation = |time| look_angles(sat, station, time).map(|(_az, el)| el);
+
+    let mut passes = Vec::new();
+
+    let mut aos = if elevation(start)? > 0. { Some(start) } else { None };
+    let mut max = (start, elevation(start)?);
+    let mut time = start;
+
+    while time < end || aos.is_some() {
+        let prev_time = time;
+        time += step;
+        let el = elevation(time)?;
+
+        match aos {
+            None => {
+                if el > 0. && time - step < end {
+                    let t = horizon_crossing(&elevation, prev_time, time)?;
+                    aos = Some(t);
+                    max = (time, el);
+                }
+            }
+            Some(aos_time) => {
+                if el > max.1 {
+                    max = (time, el);
+                }
+                if el <= 0. {
+                    let los = horizon_crossing(&elevation, prev_time, time)?;
+                    let (max_elevation_time, max_elevation) =
+                        refine_max(&elevation, max.0 - step, max.0 + step)?;
+
+                    let (aos_position, _) = sat_position(sat, aos_time)?;
+                    let (los_position, _) = sat_position(sat, los)?;
+
+                    passes.push(Pass {
+                        sat_name: sat_name.clone(),
+                        aos: aos_time,
+                        los,
+                        max_elevation_time,
+                        max_elevation,
+                        aos_azimuth: look_angles(sat, station, aos_time)?.0,
+                        los_azimuth: look_angles(sat, station, los)?.0,
+                        direction: if los_position.2 > aos_position.2 {
+                            Direction::Northbound
+                        } else {
+                            Direction::Southbound
+                        },
+                    });
+                    aos = None;
+                }
+            }
+        }
+    }
+
+    Ok(passes)
+}
+
+/// Time when the elevation changes sign between `t1` and `t2`.
+fn horizon_crossing<F>(
+    elevation: &F,
+    mut t1: DateTime<Utc>,
+    mut t2: DateTime<Utc>,
+) -> err::Result<DateTime<Utc>>
+where
+    F: Fn(DateTime<Utc>) -> err::Result<f64>,
+{
+    let rising = elevation(t1)? <= 0.;
+    while t2 - t1 > Duration::milliseconds(100) {
+        let middle = t1 + (t2 - t1) / 2;
+        if (elevation(middle)? > 0.) == rising {
+            t2 = middle;
+        } else {
+            t1 = middle;
+        }
+    }
+
+    Ok(t1 + (t2 - t1) / 2)
+}
+
+/// Time and value of the maximum elevation between `t1` and `t2`, by ternary
+/// search.
+fn refine_max<F>(
+    elevation: &F,
+    mut t1: DateTime<Utc>,
+    mut t2: DateTime<Utc>,
+) -> err::Result<(DateTime<Utc>, f64)>
+where
+    F: Fn(DateTime<Utc>) -> err::Result<f64>,
+{
+    while t2 - t1 > Duration::milliseconds(100) {
+        let third = (t2 - t1) / 3;
+        if elevation(t1 + third)? < elevation(t2 - third)? {
+            t1 += third;
+        } else {
+            t2 -= third;
+        }
+    }
+
+    let time = t1 + (t2 - t1) / 2;
+    Ok((time, elevation(time)?))
+}
+
+/// Doppler shift in Hz of a signal transmitted at `freq` Hz.
+///
+/// The range rate is calculated numerically, so the rotation of the ground
+/// station is considered too.
+pub fn doppler_shift(
+    sat: &satellite::io::Satrec,
+    station: &GroundStation,
+    freq: f64,
+    time: chrono::DateTime<chrono::Utc>,
+) -> err::Result<f64> {
+    let delta = chrono::Duration::milliseconds(500);
+    let range_rate = (range(sat, station, time + delta)? - range(sat, station, time - delta)?)
+        / (2. * delta.num_milliseconds() as f64 / 1000.);
+
+    Ok(-freq * range_rate / SPEED_OF_LIGHT)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check the ground station position against known values.
+    #[test]
+    fn test_ground_station_position() {
+        let station = GroundStation {
+            latitude: 0.,
+            longitude: 90.,
+            altitude: 1000.,
+        };
+        let (x, y, z) = station.eci_position(0.);
+        assert!(x.abs() < 1e-9);
+        assert!((y - 6379.137).abs() < 1e-9);
+        assert!(z.abs() < 1e-9);
+
+        let station = GroundStation {
+            latitude: 90.,
+            longitude: 0.,
+            altitude: 0.,
+        };
+        let (_x, _y, z) = station.eci_position(1.);
+        assert!((z - 6356.752).abs() < 1e-3); // Polar radius
+    }
+
+    /// Check azimuth and elevation of points around a station on the equator.
+    #[test]
+    fn test_look_angles() {
+        let station = GroundStation {
+            latitude: 0.,
+            longitude: 0.,
+            altitude: 0.,
+        };
+
+        let (_az, el) = station.look_angles(0., (EARTH_RADIUS + 850., 0., 0.));
+        assert!((el - 90.).abs() < 1e-9);
+
+        let (az, el) = station.look_angles(0., (EARTH_RADIUS, 0., 1000.));
+        assert!(az.abs() < 1e-9);
+        assert!(el.abs() < 1e-9);
+
+        let (az, el) = station.look_angles(0., (EARTH_RADIUS + 1000., 1000., 0.));
+        assert!((az - 90.).abs() < 1e-9);
+        assert!((el - 45.).abs() < 1e-9);
+    }
+
+    /// Check that passes found on the test TLE are consistent.
+    #[test]
+    fn test_predict_passes() {
+        use chrono::TimeZone;
+
+        let tle = std::fs::read_to_string("./test/test_tle.txt").unwrap();
+        let station = GroundStation {
+            latitude: -34.6,
+            longitude: -58.4,
+            altitude: 25.,
+        };
+        let start = Utc.with_ymd_and_hms(2018, 12, 7, 0, 0, 0).unwrap();
+        let end = start + Duration::hours(24);
+
+        let passes = predict_passes(&tle, &station, start, end, 10.).unwrap();
+        assert!(passes.len() >= 6);
+
+        for pair in passes.windows(2) {
+            assert!(pair[0].aos <= pair[1].aos);
+        }
+        for pass in &passes {
+            assert!(pass.aos < end);
+            assert!(pass.aos < pass.max_elevation_time && pass.max_elevation_time < pass.los);
+            assert!(pass.max_elevation >= 10. && pass.max_elevation <= 90.);
+
+            if pass.aos > start {
+                let sat = find_sat(&tle, &pass.sat_name).unwrap();
+                let (_az, el) = look_angles(&sat, &station, pass.aos).unwrap();
+                assert!(el.abs() < 0.1);
+            }
+        }
+    }
+}
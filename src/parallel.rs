@@ -0,0 +1,162 @@
+//! Helpers to split work between threads.
+//!
+//! The number of threads is global, set once from the settings. The work is
+//! split in contiguous ranges and the results are joined in order, so the
+//! output is exactly the same no matter how many threads are used.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Minimum number of samples or bytes worth sending to another thread.
+pub const MIN_CHUNK: usize = 1 << 14;
+
+/// Number of threads to use, zero means one per processor.
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set number of threads to use, zero to use one per processor.
+pub fn set_threads(threads: usize) {
+    THREADS.store(threads, Ordering::Relaxed);
+}
+
+/// Number of threads to use.
+pub fn threads() -> usize {
+    match THREADS.load(Ordering::Relaxed) {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+/// Length of each chunk when splitting `len` items between `threads`.
+///
+/// The length is a multiple of `multiple` and at least `min_len`, so small
+/// inputs use fewer threads.
+fn chunk_len(threads: usize, len: usize, multiple: usize, min_len: usize) -> usize {
+    let multiple = multiple.max(1);
+    let per_thread = len.div_ceil(threads.max(1)).max(min_len).max(1);
+    per_thread.div_ceil(multiple) * multiple
+}
+
+/// Split `0..len` in contiguous ranges, one per thread, and call `f` on each
+/// one.
+///
+/// Ranges are a multiple of `multiple` long, except the last one. Returns the
+/// results in order, at least one even if `len` is zero.
+pub fn map_ranges<R, F>(len: usize, multiple: usize, min_len: usize, f: F) -> Vec<R>
+where
+    R: Send,
+    F: Fn(Range<usize>) -> R + Sync,
+{
+    map_ranges_with_threads(threads(), len, multiple, min_len, f)
+}
+
+/// Like `map_ranges()` but using the given number of threads instead of the
+/// global one.
+pub fn map_ranges_with_threads<R, F>(
+    threads: usize,
+    len: usize,
+    multiple: usize,
+    min_len: usize,
+    f: F,
+) -> Vec<R>
+where
+    R: Send,
+    F: Fn(Range<usize>) -> R + Sync,
+{
+    let chunk = chunk_len(threads, len, multiple, min_len);
+    if chunk >= len {
+        return vec![f(0..len)];
+    }
+
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..len)
+            .step_by(chunk)
+            .map(|start| scope.spawn(move || f(start..(start + chunk).min(len))))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// Split `items` in contiguous chunks, one per thread, and call `f` on each
+/// one with the index of its first item.
+///
+/// Chunks are a multiple of `multiple` long, except the last one.
+pub fn for_each_chunk_mut<T, F>(items: &mut [T], multiple: usize, min_len: usize, f: F)
+where
+    T: Send,
+    F: Fn(usize, &mut [T]) + Sync,
+{
+    for_each_chunk_mut_with_threads(threads(), items, multiple, min_len, f);
+}
+
+/// Like `for_each_chunk_mut()` but using the given number of threads instead
+/// of the global one.
+pub fn for_each_chunk_mut_with_threads<T, F>(
+    threads: usize,
+    items: &mut [T],
+    multiple: usize,
+    min_len: usize,
+    f: F,
+) where
+    T: Send,
+    F: Fn(usize, &mut [T]) + Sync,
+{
+    let chunk = chunk_len(threads, items.len(), multiple, min_len);
+    if chunk >= items.len() {
+        f(0, items);
+        return;
+    }
+
+    let f = &f;
+    std::thread::scope(|scope| {
+        for (i, part) in items.chunks_mut(chunk).enumerate() {
+            scope.spawn(move || f(i * chunk, part));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check that the ranges cover everything once and in order.
+    #[test]
+    fn test_map_ranges() {
+        for &threads in &[1, 3, 8] {
+            for &(len, multiple, min_len) in &[(0, 1, 1), (10, 1, 1), (1000, 7, 1), (1000, 1, 600)]
+            {
+                let ranges =
+                    map_ranges_with_threads(threads, len, multiple, min_len, |range| range);
+
+                assert!(ranges.len() <= threads.max(1));
+                assert_eq!(ranges.first().unwrap().start, 0);
+                assert_eq!(ranges.last().unwrap().end, len);
+                for pair in ranges.windows(2) {
+                    assert_eq!(pair[0].end, pair[1].start);
+                    assert_eq!(pair[0].len() % multiple, 0);
+                    assert!(pair[0].len() >= min_len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_for_each_chunk_mut() {
+        for &threads in &[1, 3, 8] {
+            let mut items: Vec<usize> = vec![0; 1000];
+            for_each_chunk_mut_with_threads(threads, &mut items, 10, 1, |start, chunk| {
+                assert_eq!(start % 10, 0);
+                for (i, item) in chunk.iter_mut().enumerate() {
+                    *item = start + i;
+                }
+            });
+
+            assert_eq!(items, (0..1000).collect::<Vec<usize>>());
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! Plots of the intermediate steps of the decoding process.
+//!
+//! Used by the `Context` when exporting steps as plots instead of WAV files.
+//! The plots are plain images without any text, the axes ranges are written on
+//! an HTML index next to each plot.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use image::{Rgb, RgbImage};
+use line_drawing::Bresenham;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::dsp::{Rate, Signal};
+use crate::err;
+
+/// Size of the plots in pixels.
+const WIDTH: u32 = 1000;
+const HEIGHT: u32 = 300;
+
+/// Length of each segment when calculating spectra. The spectra of several
+/// segments are averaged.
+const SPECTRUM_SEGMENT: usize = 1024;
+
+/// Maximum number of segments to average. On long signals the segments are
+/// spread over the whole signal.
+const SPECTRUM_MAX_SEGMENTS: usize = 256;
+
+/// Range in dB shown below the maximum value on spectra and frequency
+/// responses.
+const DB_RANGE: f32 = 100.;
+
+const BACKGROUND_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+const GRID_COLOR: Rgb<u8> = Rgb([220, 220, 220]);
+const TRACE_COLOR: Rgb<u8> = Rgb([30, 80, 180]);
+
+/// Range and label of a plot axis.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Axis {
+    pub label: &'static str,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Plot image and its axes.
+pub struct Plot {
+    /// Kind of plot, like "Spectrum".
+    pub title: &'static str,
+    pub image: RgbImage,
+    pub x: Axis,
+    pub y: Axis,
+}
+
+/// Plots of a step, as shown on the HTML index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    /// Step filename without extension.
+    pub name: String,
+
+    pub description: String,
+
+    /// Filename, kind and axes of each plot.
+    pub plots: Vec<(String, &'static str, Axis, Axis)>,
+}
+
+/// Draw values evenly spaced on the horizontal axis.
+///
+/// If there are more values than pixels, each column shows the minimum and
+/// maximum values on that column.
+fn draw(values: &[f32], y_min: f32, y_max: f32) -> RgbImage {
+    let mut img = RgbImage::from_pixel(WIDTH, HEIGHT, BACKGROUND_COLOR);
+
+    for i in 0..=10 {
+        let x = i * (WIDTH - 1) / 10;
+        for y in 0..HEIGHT {
+            img.put_pixel(x, y, GRID_COLOR);
+        }
+    }
+    for i in 0..=4 {
+        let y = i * (HEIGHT - 1) / 4;
+        for x in 0..WIDTH {
+            img.put_pixel(x, y, GRID_COLOR);
+        }
+    }
+
+    let to_y = |value: f32| -> i32 {
+        let relative = if y_max > y_min {
+            (value - y_min) / (y_max - y_min)
+        } else {
+            0.5
+        };
+        ((1. - relative.clamp(0., 1.)) * (HEIGHT - 1) as f32).round() as i32
+    };
+
+    let mut line = |from: (i32, i32), to: (i32, i32)| {
+        for (x, y) in Bresenham::new(from, to) {
+            if x >= 0 && y >= 0 && (x as u32) < WIDTH && (y as u32) < HEIGHT {
+                img.put_pixel(x as u32, y as u32, TRACE_COLOR);
+            }
+        }
+    };
+
+    let len = values.len();
+    if len > WIDTH as usize {
+        let mut last: Option<f32> = None;
+        for x in 0..WIDTH as usize {
+            let column = &values[x * len / WIDTH as usize..(x + 1) * len / WIDTH as usize];
+
+            // Include the last value of the previous column so the trace is
+            // continuous
+            let (min, max) = column
+                .iter()
+                .chain(last.iter())
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| {
+                    (min.min(v), max.max(v))
+                });
+            line((x as i32, to_y(max)), (x as i32, to_y(min)));
+            last = column.last().cloned();
+        }
+    } else {
+        let to_x = |i: usize| (i * (WIDTH as usize - 1) / (len - 1).max(1)) as i32;
+        for i in 1..len {
+            line((to_x(i - 1), to_y(values[i - 1])), (to_x(i), to_y(values[i])));
+        }
+        if len == 1 {
+            line((0, to_y(values[0])), (WIDTH as i32 - 1, to_y(values[0])));
+        }
+    }
+
+    img
+}
+
+/// Minimum and maximum of the finite values, with some margin.
+fn range(values: &[f32]) -> (f32, f32) {
+    let (min, max) = values
+        .iter()
+        .filter(|v| v.is_finite())
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| (min.min(v), max.max(v)));
+
+    if min > max {
+        (-1., 1.)
+    } else if min == max {
+        (min - 1., max + 1.)
+    } else {
+        let margin = (max - min) * 0.05;
+        (min - margin, max + margin)
+    }
+}
+
+/// Plot signal against time.
+pub fn time(signal: &Signal, rate: Rate) -> Plot {
+    let (min, max) = range(signal);
+
+    Plot {
+        title: "Time",
+        image: draw(signal, min, max),
+        x: Axis {
+            label: "Time (s)",
+            min: 0.,
+            max: signal.len() as f32 / rate.get_hz() as f32,
+        },
+        y: Axis {
+            label: "Amplitude",
+            min,
+            max,
+        },
+    }
+}
+
+/// Power spectrum in dB from zero to half the sample rate.
+///
+/// Averages the spectra of several segments of the signal, using a Hann
+/// window.
+fn power_spectrum(signal: &Signal) -> Vec<f32> {
+    let segment = SPECTRUM_SEGMENT.min(signal.len().next_power_of_two());
+    let segments = ((signal.len().max(segment) - segment) / segment + 1).min(SPECTRUM_MAX_SEGMENTS);
+
+    let window: Vec<f32> = (0..segment)
+        .map(|i| {
+            0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / (segment - 1).max(1) as f32).cos()
+        })
+        .collect();
+
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(segment);
+
+    let mut power = vec![0_f32; segment / 2 + 1];
+    for k in 0..segments {
+        let start = k * (signal.len().max(segment) - segment) / (segments - 1).max(1);
+        let mut buffer: Vec<Complex<f32>> = (0..segment)
+            .map(|i| Complex::new(signal.get(start + i).cloned().unwrap_or(0.) * window[i], 0.))
+            .collect();
+        fft.process(&mut buffer);
+        for (p, x) in power.iter_mut().zip(buffer.iter()) {
+            *p += x.norm_sqr() / segments as f32;
+        }
+    }
+
+    power.iter().map(|p| 10. * (p + 1e-20).log10()).collect()
+}
+
+/// Gain in dB of a filter from zero to pi radians per sample.
+fn frequency_response(coeff: &Signal) -> Vec<f32> {
+    let len = (coeff.len() * 8).next_power_of_two().max(4096);
+
+    let mut buffer: Vec<Complex<f32>> = (0..len)
+        .map(|i| Complex::new(coeff.get(i).cloned().unwrap_or(0.), 0.))
+        .collect();
+    FftPlanner::new().plan_fft_forward(len).process(&mut buffer);
+
+    buffer[..=len / 2]
+        .iter()
+        .map(|x| 20. * (x.norm() + 1e-10).log10())
+        .collect()
+}
+
+/// Range to show of some values in dB.
+fn db_range(values: &[f32]) -> (f32, f32) {
+    let (_, max) = range(values);
+    (max - DB_RANGE, max + 5.)
+}
+
+/// Plot spectrum of the signal.
+pub fn spectrum(signal: &Signal, rate: Rate) -> Plot {
+    let values = power_spectrum(signal);
+    let (min, max) = db_range(&values);
+
+    Plot {
+        title: "Spectrum",
+        image: draw(&values, min, max),
+        x: Axis {
+            label: "Frequency (Hz)",
+            min: 0.,
+            max: rate.get_hz() as f32 / 2.,
+        },
+        y: Axis {
+            label: "Power (dB)",
+            min,
+            max,
+        },
+    }
+}
+
+/// Plot frequency response of a filter given its coefficients.
+pub fn filter(coeff: &Signal) -> Plot {
+    let values = frequency_response(coeff);
+    let (min, max) = db_range(&values);
+
+    Plot {
+        title: "Frequency response",
+        image: draw(&values, min, max),
+        x: Axis {
+            label: "Frequency (fractions of pi radians per sample)",
+            min: 0.,
+            max: 1.,
+        },
+        y: Axis {
+            label: "Gain (dB)",
+            min,
+            max,
+        },
+    }
+}
+
+/// Write HTML page showing the plots of every step.
+pub fn write_index(filename: &Path, entries: &[IndexEntry]) -> err::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+
+    writeln!(file, "<!DOCTYPE html>")?;
+    writeln!(file, "<html><head><meta charset=\"utf-8\"><title>noaa-apt steps</title>")?;
+    writeln!(
+        file,
+        "<style>body {{ font-family: sans-serif; }} img {{ border: 1px solid #ccc; }}</style>"
+    )?;
+    writeln!(file, "</head><body>")?;
+    writeln!(file, "<h1>noaa-apt steps</h1>")?;
+
+    for entry in entries {
+        writeln!(file, "<h2>{}: {}</h2>", entry.name, entry.description)?;
+        for (plot_filename, title, x, y) in &entry.plots {
+            writeln!(file, "<h3>{}</h3>", title)?;
+            writeln!(
+                file,
+                "<p>Horizontal: {} from {} to {}. Vertical: {} from {} to {}.</p>",
+                x.label, x.min, x.max, y.label, y.min, y.max
+            )?;
+            writeln!(file, "<img src=\"{}\" alt=\"{}\">", plot_filename, title)?;
+        }
+    }
+
+    writeln!(file, "</body></html>")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::dsp::Freq;
+    use crate::filters::{self, Filter};
+
+    /// Check the gain of a lowpass filter on the passband and stopband.
+    #[test]
+    fn test_frequency_response() {
+        let coeff = filters::Lowpass {
+            cutout: Freq::pi_rad(0.3),
+            atten: 40.,
+            delta_w: Freq::pi_rad(0.1),
+        }
+        .design();
+
+        let response = frequency_response(&coeff);
+        let at = |f: f32| response[(f * (response.len() - 1) as f32) as usize];
+
+        assert!(at(0.).abs() < 0.5);
+        assert!(at(0.1).abs() < 0.5);
+        assert!(at(0.5) < -35.);
+        assert!(at(0.9) < -35.);
+    }
+
+    /// Check that the spectrum has a peak on the frequency of a sine.
+    #[test]
+    fn test_power_spectrum() {
+        let signal: Signal = (0..10000)
+            .map(|i| (2. * std::f32::consts::PI * 0.125 * i as f32).sin())
+            .collect();
+
+        let spectrum = power_spectrum(&signal);
+        let peak = (0..spectrum.len())
+            .max_by(|&a, &b| spectrum[a].total_cmp(&spectrum[b]))
+            .unwrap();
+
+        assert_eq!(spectrum.len(), SPECTRUM_SEGMENT / 2 + 1);
+        assert_eq!(peak, SPECTRUM_SEGMENT / 8);
+    }
+}
@@ -1,14 +1,21 @@
 //! Image processing functions.
 
-use image::{GenericImage, RgbaImage, Pixel};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use image::{GenericImage, Pixel, Rgba, RgbImage, RgbaImage};
 use log::info;
 
 use crate::decode::{PX_CHANNEL_IMAGE_DATA, PX_PER_CHANNEL, PX_SPACE_DATA, PX_SYNC_FRAME};
 use crate::err;
 use crate::geo;
+use crate::georef::{self, Georef};
 use crate::imageext;
-use crate::misc;
-use crate::noaa_apt::{ColorSettings, OrbitSettings, RefTime};
+use crate::orbit;
+use crate::parallel;
+use crate::sun;
+use crate::tle;
+use crate::noaa_apt::{ColorSettings, Enhancement, OrbitSettings, RefTime, Rotate};
 
 /// Rotates the channels in place, keeping the sync bands and telemetry intact.
 ///
@@ -38,21 +45,9 @@ pub fn rotate(img: &mut RgbaImage) {
 
 /// Returns true if this was a south to north pass, and the image needs to be rotated.
 pub fn south_to_north_pass(orbit_settings: &OrbitSettings) -> err::Result<bool> {
-    let tle = match &orbit_settings.custom_tle {
-        Some(t) => t.clone(),
-        None => misc::get_current_tle()?,
-    };
-
-    let (sats, _errors) = satellite::io::parse_multiple(&tle);
-    let sat_string = orbit_settings.sat_name.to_string();
+    let tle = tle::for_orbit(orbit_settings)?;
 
-    let sat = sats
-        .iter()
-        .find(|&sat| sat.name.as_ref() == Some(&sat_string))
-        .ok_or_else(|| {
-            err::Error::Internal(format!("Satellite \"{}\" not found in TLE", sat_string))
-        })?
-        .clone();
+    let sat = orbit::find_sat(&tle, &orbit_settings.sat_name)?;
 
     let start_time = match orbit_settings.ref_time {
         RefTime::Start(time) => time,
@@ -80,6 +75,15 @@ pub fn south_to_north_pass(orbit_settings: &OrbitSettings) -> err::Result<bool>
     return Ok(azimuth < PI / 4. || azimuth > 3. * PI / 4.);
 }
 
+/// Returns true if the image is rotated given the rotation setting.
+pub fn is_rotated(rotate: &Rotate, orbit_settings: &OrbitSettings) -> err::Result<bool> {
+    match rotate {
+        Rotate::Yes => Ok(true),
+        Rotate::No => Ok(false),
+        Rotate::Orbit => south_to_north_pass(orbit_settings),
+    }
+}
+
 /// Histogram equalization, in place, for each channel (A, B) separately.
 /// If `has_color=false`, it will treat the image as grayscale (R = G = B, A = 255).
 /// If `has_color=true`, it will convert image from Rgba to Lab, equalize the histogram
@@ -107,18 +111,91 @@ pub fn histogram_equalization(img: &mut RgbaImage, has_color: bool) {
 /// Uses a palette image to map channel brightness values to a color.
 pub fn false_color(img: &mut RgbaImage, color_settings: &ColorSettings) -> err::Result<()>{
 
-    let palette_img = image::open(&color_settings.palette_filename).map_err(
-            |_| err::Error::InvalidInput(format!("Could not load {:?}", &color_settings.palette_filename))
-        )?.into_rgb8();
+    let palette_img = load_palette(&color_settings.palette_filename)?;
 
-    if palette_img.width() != 256 || palette_img.height() != 256 {
-        return Err(err::Error::InvalidInput("Invalid palette image dimensions".to_string()))
+    colorize(img, color_settings, |_, val_a, val_b| {
+        palette_img.get_pixel(val_a, val_b).to_rgba()
+    });
+
+    Ok(())
+}
+
+/// Like `false_color()` but blending two palettes, one for daylight and one
+/// for the night.
+///
+/// Takes the weight of the daylight palette for each pixel of channel A as
+/// given by `day_weights()`.
+pub fn day_night_false_color(
+    img: &mut RgbaImage,
+    color_settings: &ColorSettings,
+    night_palette_filename: &Path,
+    day_weights: &[f32],
+) -> err::Result<()> {
+    let day_palette_img = load_palette(&color_settings.palette_filename)?;
+    let night_palette_img = load_palette(night_palette_filename)?;
+
+    if day_weights.len() != (PX_CHANNEL_IMAGE_DATA * img.height()) as usize {
+        return Err(err::Error::Internal(
+            "Day weights don't match the image size".to_string(),
+        ));
+    }
+
+    colorize(img, color_settings, |i, val_a, val_b| {
+        let day = day_palette_img.get_pixel(val_a, val_b);
+        let night = night_palette_img.get_pixel(val_a, val_b);
+        let w = day_weights[i];
+
+        let mut color = Rgba([0, 0, 0, 255]);
+        for c in 0..3 {
+            color[c] = (day[c] as f32 * w + night[c] as f32 * (1. - w)).round() as u8;
+        }
+        color
+    });
+
+    Ok(())
+}
+
+/// Weight of the daylight rendering for each pixel of channel A.
+///
+/// The image should not be rotated. The result has `PX_CHANNEL_IMAGE_DATA`
+/// values per row.
+pub fn day_weights(georef: &Georef, start_time: DateTime<Utc>) -> Vec<f32> {
+    info!("Calculating solar zenith angles");
+
+    let x_start = PX_SYNC_FRAME + PX_SPACE_DATA;
+    let mut weights = Vec::with_capacity((PX_CHANNEL_IMAGE_DATA * georef.height()) as usize);
+
+    for y in 0..georef.height() {
+        let time = start_time + georef::line_duration() * y as i32;
+        let subsolar = sun::subsolar_point(time);
+
+        for x in x_start..x_start + PX_CHANNEL_IMAGE_DATA {
+            let rel_x = x as f64 - georef::NADIR_A as f64;
+            let latlon = georef.rel_px_to_latlon((rel_x, y as f64));
+            weights.push(sun::day_weight(sun::zenith(latlon, subsolar)));
+        }
     }
 
+    weights
+}
+
+/// Colorize channel A, gets the color of each pixel from a function.
+///
+/// The function takes the index of the pixel, counting row by row only the
+/// pixels of channel A, and the channel A and B values to use as palette
+/// coordinates.
+///
+/// Each thread colorizes some rows.
+fn colorize<F>(img: &mut RgbaImage, color_settings: &ColorSettings, color: F)
+where
+    F: Fn(usize, u32, u32) -> Rgba<u8> + Sync,
+{
     // Determine region of channel A, which will be the only one colorized
     let x_start = PX_SYNC_FRAME + PX_SPACE_DATA;
     let x_end = x_start + PX_CHANNEL_IMAGE_DATA;
-    let image_height = img.height();
+
+    // Bytes on each row, 4 channels per pixel
+    let row_len = img.width() as usize * 4;
 
     // Assumes input and output values from 0 to 255
     // Gives output in u32 because that is the input to the next function
@@ -140,18 +217,230 @@ pub fn false_color(img: &mut RgbaImage, color_settings: &ColorSettings) -> err::
     };
 
     // Colorize
-    for x in x_start..x_end {
-        for y in 0..image_height {
-            let ch_a = img.get_pixel(x, y)[0]; // Red channel of channel A
-            let ch_b = img.get_pixel(x + PX_PER_CHANNEL, y)[0]; // Red channel of channel B
+    let buffer: &mut [u8] = img;
+    parallel::for_each_chunk_mut(buffer, row_len, parallel::MIN_CHUNK, |start, rows| {
+        for (row_index, row) in rows.chunks_exact_mut(row_len).enumerate() {
+            let y = (start / row_len + row_index) as u32;
 
-            let (val_a, val_b) = tune_input_values(ch_a, ch_b);
+            for x in x_start..x_end {
+                let px_a = x as usize * 4;
+                let px_b = (x + PX_PER_CHANNEL) as usize * 4;
+                let ch_a = row[px_a]; // Red channel of channel A
+                let ch_b = row[px_b]; // Red channel of channel B
 
-            img.put_pixel(x, y,
-                palette_img.get_pixel(val_a, val_b).to_rgba()
-            );
+                let (val_a, val_b) = tune_input_values(ch_a, ch_b);
+
+                let i = (y * PX_CHANNEL_IMAGE_DATA + x - x_start) as usize;
+                row[px_a..px_a + 4].copy_from_slice(&color(i, val_a, val_b).0);
+            }
         }
+    });
+}
+
+/// Background color of land on MCIR images.
+const MCIR_LAND: [f32; 3] = [64., 96., 40.];
+
+/// Background color of water on MCIR images.
+const MCIR_SEA: [f32; 3] = [16., 40., 96.];
+
+/// Channel B brightness where clouds start to cover the background on MCIR
+/// images, and where they are fully opaque.
+const MCIR_CLOUD_LOW: f32 = 100.;
+const MCIR_CLOUD_HIGH: f32 = 200.;
+
+/// Map color IR, draws the clouds from channel B over a land and sea
+/// background on channel A.
+///
+/// Takes the land mask for each pixel of channel A as given by
+/// `map::land_mask()`. Colder (brighter) pixels of channel B are more opaque.
+pub fn mcir(img: &mut RgbaImage, land_mask: &[bool]) -> err::Result<()> {
+    info!("Generating MCIR image");
+
+    if land_mask.len() != (PX_CHANNEL_IMAGE_DATA * img.height()) as usize {
+        return Err(err::Error::Internal(
+            "Land mask doesn't match the image size".to_string(),
+        ));
+    }
+
+    let x_start = PX_SYNC_FRAME + PX_SPACE_DATA;
+
+    for (i, &land) in land_mask.iter().enumerate() {
+        let x = x_start + i as u32 % PX_CHANNEL_IMAGE_DATA;
+        let y = i as u32 / PX_CHANNEL_IMAGE_DATA;
+
+        let ir = img.get_pixel(x + PX_PER_CHANNEL, y)[0] as f32;
+        let t = ((ir - MCIR_CLOUD_LOW) / (MCIR_CLOUD_HIGH - MCIR_CLOUD_LOW)).clamp(0., 1.);
+        let alpha = t * t * (3. - 2. * t);
+
+        let background = if land { MCIR_LAND } else { MCIR_SEA };
+
+        let mut color = Rgba([0, 0, 0, 255]);
+        for c in 0..3 {
+            color[c] = (background[c] * (1. - alpha) + ir * alpha).round() as u8;
+        }
+        img.put_pixel(x, y, color);
     }
 
     Ok(())
 }
+
+/// Load a 256x256 palette image.
+pub fn load_palette(filename: &Path) -> err::Result<RgbImage> {
+    let palette_img = image::open(filename)
+        .map_err(|_| err::Error::InvalidInput(format!("Could not load {:?}", filename)))?
+        .into_rgb8();
+
+    if palette_img.width() != 256 || palette_img.height() != 256 {
+        return Err(err::Error::InvalidInput("Invalid palette image dimensions".to_string()));
+    }
+
+    Ok(palette_img)
+}
+
+/// Colorize channel A using temperatures of channel B.
+///
+/// Takes the temperatures in Kelvin given by
+/// `calibration::channel_b_temperatures()` for the same image. Like on
+/// `false_color()`, the channel A value selects the column of the palette and
+/// the temperature selects the row. Pixels that could not be calibrated are
+/// left black.
+pub fn temperature_color(
+    img: &mut RgbaImage,
+    temperatures: &[f32],
+    enhancement: Enhancement,
+) -> err::Result<()> {
+    info!("Colorizing image using {:?} enhancement", enhancement);
+
+    let palette_img = load_palette(&enhancement.palette_filename())?;
+
+    if temperatures.len() != (PX_CHANNEL_IMAGE_DATA * img.height()) as usize {
+        return Err(err::Error::Internal(
+            "Temperatures don't match the image size".to_string(),
+        ));
+    }
+
+    let x_start = PX_SYNC_FRAME + PX_SPACE_DATA;
+
+    for (i, temperature) in temperatures.iter().enumerate() {
+        let x = x_start + i as u32 % PX_CHANNEL_IMAGE_DATA;
+        let y = i as u32 / PX_CHANNEL_IMAGE_DATA;
+
+        let color = if temperature.is_nan() {
+            Rgba([0, 0, 0, 255])
+        } else {
+            let column = img.get_pixel(x, y)[0] as u32;
+            let row = enhancement.palette_row(temperature - 273.15);
+            palette_img.get_pixel(column, row).to_rgba()
+        };
+        img.put_pixel(x, y, color);
+    }
+
+    Ok(())
+}
+
+/// Height of the legend drawn by `draw_legend()`.
+const LEGEND_HEIGHT: u32 = 48;
+
+/// Characters available for legends, each one has 5 rows of 3 pixels.
+const FONT: [(char, [u8; 5]); 13] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('°', [0b010, 0b101, 0b010, 0b000, 0b000]),
+    ('C', [0b111, 0b100, 0b100, 0b100, 0b111]),
+];
+
+/// Scale of the font used on legends.
+const FONT_SCALE: u32 = 2;
+
+/// Width of a character on legends, including spacing.
+const FONT_ADVANCE: u32 = 4 * FONT_SCALE;
+
+/// Draw text in white, unknown characters are left blank.
+fn draw_text(img: &mut RgbaImage, text: &str, x: u32, y: u32) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph = match FONT.iter().find(|(f, _)| *f == c) {
+            Some((_, glyph)) => glyph,
+            None => continue,
+        };
+
+        let x_glyph = x + i as u32 * FONT_ADVANCE;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..FONT_SCALE {
+                    for dx in 0..FONT_SCALE {
+                        let px = x_glyph + column * FONT_SCALE + dx;
+                        let py = y + row as u32 * FONT_SCALE + dy;
+                        if px < img.width() && py < img.height() {
+                            img.put_pixel(px, py, Rgba([255, 255, 255, 255]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Add a color scale below the image, with a label every 10 degrees Celsius.
+pub fn draw_legend(img: &RgbaImage, palette_img: &RgbImage, enhancement: Enhancement) -> RgbaImage {
+    info!("Drawing legend");
+
+    let mut result = RgbaImage::from_pixel(
+        img.width(),
+        img.height() + LEGEND_HEIGHT,
+        Rgba([0, 0, 0, 255]),
+    );
+    result
+        .copy_from(img, 0, 0)
+        .expect("Legend image should be larger than the image");
+
+    let (coldest, warmest) = enhancement.legend_range();
+    let margin = 40;
+    let bar_width = img.width().saturating_sub(2 * margin).max(2);
+    let y_bar = img.height() + 8;
+
+    // Horizontal position on the bar of some temperature
+    let temperature_to_x =
+        |t: f32| margin + ((t - coldest) / (warmest - coldest) * (bar_width - 1) as f32) as u32;
+
+    // Colors for a channel A value in the middle of the scale
+    for i in 0..bar_width {
+        let t = coldest + (warmest - coldest) * i as f32 / (bar_width - 1) as f32;
+        let color = palette_img.get_pixel(128, enhancement.palette_row(t)).to_rgba();
+        for y in y_bar..y_bar + 16 {
+            result.put_pixel(margin + i, y, color);
+        }
+    }
+
+    let mut t = (coldest / 10.).ceil() * 10.;
+    while t <= warmest {
+        let x = temperature_to_x(t);
+        for y in y_bar + 16..y_bar + 20 {
+            result.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+
+        let label = format!("{}°C", t);
+        let label_width = label.chars().count() as u32 * FONT_ADVANCE;
+        draw_text(
+            &mut result,
+            &label,
+            x.saturating_sub(label_width / 2),
+            y_bar + 22,
+        );
+
+        t += 10.;
+    }
+
+    result
+}
@@ -0,0 +1,527 @@
+//! Reprojection of channel images to regular latitude/longitude grids.
+//!
+//! For every pixel of the output grid I calculate the latitude and longitude,
+//! find the corresponding pixel on the swath with `Georef` and interpolate.
+//! Pixels outside of the swath are transparent.
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use image::Rgba;
+use log::info;
+
+use crate::decode::{PX_CHANNEL_IMAGE_DATA, PX_SPACE_DATA, PX_SYNC_FRAME};
+use crate::err;
+use crate::geo;
+use crate::georef::{self, Georef, NADIR_A};
+use crate::noaa_apt::Image;
+
+/// Radius used by the Web Mercator projection (EPSG:3857) in meters.
+const MERCATOR_RADIUS: f64 = 6_378_137.;
+
+/// Maximum latitude in degrees representable on Mercator maps, the same as
+/// web maps.
+const MERCATOR_MAX_LAT: f64 = 85.051_128_78;
+
+/// Maximum distance in radians between a coordinate and the position found on
+/// the swath, otherwise the coordinate is considered outside of the swath.
+const MAX_ERROR: f64 = 0.01;
+
+/// Available map projections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Plate carrée, EPSG:4326.
+    Equirectangular,
+
+    /// Web Mercator, EPSG:3857.
+    Mercator,
+}
+
+impl Projection {
+    pub fn from_name(name: &str) -> Option<Projection> {
+        match name {
+            "equirectangular" => Some(Projection::Equirectangular),
+            "mercator" => Some(Projection::Mercator),
+            _ => None,
+        }
+    }
+
+    /// Vertical coordinate in radians for a latitude in radians.
+    fn lat_to_y(self, lat: f64) -> f64 {
+        match self {
+            Projection::Equirectangular => lat,
+            Projection::Mercator => (PI / 4. + lat / 2.).tan().ln(),
+        }
+    }
+
+    /// Latitude in radians for a vertical coordinate in radians.
+    fn y_to_lat(self, y: f64) -> f64 {
+        match self {
+            Projection::Equirectangular => y,
+            Projection::Mercator => 2. * y.exp().atan() - PI / 2.,
+        }
+    }
+}
+
+/// Region of the world in degrees.
+///
+/// If `west` is greater than `east` the region crosses the antimeridian.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBox {
+    /// Width in degrees of longitude.
+    fn lon_span(&self) -> f64 {
+        let span = self.east - self.west;
+        if span <= 0. {
+            span + 360.
+        } else {
+            span
+        }
+    }
+
+    /// Smallest region that contains both regions.
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        // Unwrap longitudes relative to the west of this region
+        let unwrap = |lon: f64| self.west + (lon - self.west).rem_euclid(360.);
+
+        let other_west = unwrap(other.west);
+        let west = self.west.min(other_west);
+        let east = (self.west + self.lon_span()).max(other_west + other.lon_span());
+
+        // Candidates, going around the world in the other direction
+        let other_west = other_west - 360.;
+        let west2 = self.west.min(other_west);
+        let east2 = (self.west + self.lon_span()).max(other_west + other.lon_span());
+
+        let (west, east) = if east2 - west2 < east - west {
+            (west2, east2)
+        } else {
+            (west, east)
+        };
+
+        let normalize = |lon: f64| (lon + 180.).rem_euclid(360.) - 180.;
+
+        BoundingBox {
+            west: if east - west >= 360. {
+                -180.
+            } else {
+                normalize(west)
+            },
+            south: self.south.min(other.south),
+            east: if east - west >= 360. {
+                180.
+            } else {
+                normalize(east)
+            },
+            north: self.north.max(other.north),
+        }
+    }
+}
+
+/// Regular grid of pixels on some projection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid {
+    pub projection: Projection,
+    pub bbox: BoundingBox,
+
+    /// Size of each pixel in degrees of longitude. On Mercator maps, pixels
+    /// are square at every latitude.
+    pub resolution: f64,
+
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Grid {
+    pub fn new(projection: Projection, bbox: BoundingBox, resolution: f64) -> err::Result<Grid> {
+        if resolution <= 0. || !resolution.is_finite() {
+            return Err(err::Error::InvalidInput(
+                "Resolution should be positive".to_string(),
+            ));
+        }
+        if bbox.south >= bbox.north || bbox.south < -90. || bbox.north > 90. {
+            return Err(err::Error::InvalidInput(format!(
+                "Invalid bounding box latitudes: {} to {}",
+                bbox.south, bbox.north
+            )));
+        }
+
+        let mut bbox = bbox;
+        if projection == Projection::Mercator {
+            bbox.south = bbox.south.max(-MERCATOR_MAX_LAT);
+            bbox.north = bbox.north.min(MERCATOR_MAX_LAT);
+        }
+
+        let y_span = projection.lat_to_y(bbox.north.to_radians())
+            - projection.lat_to_y(bbox.south.to_radians());
+
+        let width = (bbox.lon_span() / resolution).ceil() as u32;
+        let height = (y_span / resolution.to_radians()).ceil() as u32;
+
+        if width as u64 * height as u64 > 100_000_000 {
+            return Err(err::Error::InvalidInput(format!(
+                "Grid too big ({}x{}), use a lower resolution",
+                width, height
+            )));
+        }
+
+        Ok(Grid {
+            projection,
+            bbox,
+            resolution,
+            width,
+            height,
+        })
+    }
+
+    /// (latitude, longitude) in radians of the center of a pixel.
+    pub fn px_to_latlon(&self, (x, y): (u32, u32)) -> (f64, f64) {
+        let res = self.resolution.to_radians();
+        let top = self.projection.lat_to_y(self.bbox.north.to_radians());
+
+        let lat = self.projection.y_to_lat(top - (y as f64 + 0.5) * res);
+        let lon = self.bbox.west.to_radians() + (x as f64 + 0.5) * res;
+
+        (lat, lon)
+    }
+
+    /// GDAL affine transform in the units of the projection: degrees or
+    /// meters.
+    ///
+    /// (origin x, pixel width, 0, origin y, 0, -pixel height)
+    pub fn geotransform(&self) -> [f64; 6] {
+        match self.projection {
+            Projection::Equirectangular => [
+                self.bbox.west,
+                self.resolution,
+                0.,
+                self.bbox.north,
+                0.,
+                -self.resolution,
+            ],
+            Projection::Mercator => {
+                let res = MERCATOR_RADIUS * self.resolution.to_radians();
+                let top = self.projection.lat_to_y(self.bbox.north.to_radians());
+                [
+                    MERCATOR_RADIUS * self.bbox.west.to_radians(),
+                    res,
+                    0.,
+                    MERCATOR_RADIUS * top,
+                    0.,
+                    -res,
+                ]
+            }
+        }
+    }
+
+    /// EPSG code of the projection.
+    fn epsg(&self) -> u16 {
+        match self.projection {
+            Projection::Equirectangular => 4326,
+            Projection::Mercator => 3857,
+        }
+    }
+}
+
+/// Nadir column relative to the channel image data.
+///
+/// If `rotated` is true, the image was rotated 180 degrees.
+pub fn nadir_column(rotated: bool) -> f64 {
+    let nadir = (NADIR_A - PX_SYNC_FRAME - PX_SPACE_DATA) as f64;
+    if rotated {
+        (PX_CHANNEL_IMAGE_DATA - 1) as f64 - nadir
+    } else {
+        nadir
+    }
+}
+
+/// Smallest region that contains the swath.
+pub fn swath_bbox(georef: &Georef) -> BoundingBox {
+    let width = PX_CHANNEL_IMAGE_DATA as f64;
+    let height = georef.height() as f64;
+
+    // Points along the border of the swath
+    let mut border = Vec::new();
+    for i in 0..=20 {
+        let x = width * i as f64 / 20. - nadir_column(false);
+        border.push((x, 0.));
+        border.push((x, height - 1.));
+    }
+    for i in 0..=100 {
+        let y = (height - 1.) * i as f64 / 100.;
+        border.push((-nadir_column(false), y));
+        border.push((width - 1. - nadir_column(false), y));
+    }
+
+    let latlons: Vec<(f64, f64)> = border
+        .into_iter()
+        .map(|p| {
+            let (lat, lon) = georef.rel_px_to_latlon(p);
+            (lat.to_degrees(), lon.to_degrees())
+        })
+        .collect();
+
+    // Unwrap longitudes relative to the first one, so passes crossing the
+    // antimeridian don't span the whole world
+    let first_lon = latlons[0].1;
+    let unwrap = |lon: f64| first_lon + (lon - first_lon + 180.).rem_euclid(360.) - 180.;
+
+    let south = latlons.iter().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let north = latlons
+        .iter()
+        .map(|p| p.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let west = latlons
+        .iter()
+        .map(|p| unwrap(p.1))
+        .fold(f64::INFINITY, f64::min);
+    let east = latlons
+        .iter()
+        .map(|p| unwrap(p.1))
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    let normalize = |lon: f64| (lon + 180.).rem_euclid(360.) - 180.;
+
+    if east - west >= 360. {
+        // Passing over a pole
+        BoundingBox {
+            west: -180.,
+            south,
+            east: 180.,
+            north,
+        }
+    } else {
+        BoundingBox {
+            west: normalize(west),
+            south,
+            east: normalize(east),
+            north,
+        }
+    }
+}
+
+/// Find the pixel on the channel image for some (latitude, longitude) in
+/// radians.
+///
+/// Returns `None` if outside of the swath. If `rotated` is true, the image was
+/// rotated 180 degrees.
+pub fn latlon_to_channel_px(
+    georef: &Georef,
+    rotated: bool,
+    latlon: (f64, f64),
+) -> Option<(f64, f64)> {
+    let width = PX_CHANNEL_IMAGE_DATA as f64;
+    let height = georef.height() as f64;
+
+    let (x, y) = georef.latlon_to_rel_px(latlon);
+    let col = x + nadir_column(false);
+
+    if col < 0. || col > width - 1. || y < 0. || y > height - 1. {
+        return None;
+    }
+
+    // latlon_to_rel_px() maps far away positions to wrong places, check the
+    // result
+    if geo::distance(georef.rel_px_to_latlon((x, y)), latlon) > MAX_ERROR {
+        return None;
+    }
+
+    if rotated {
+        Some((width - 1. - col, height - 1. - y))
+    } else {
+        Some((col, y))
+    }
+}
+
+/// Bilinear interpolation of a pixel.
+pub fn interpolate(img: &Image, (x, y): (f64, f64)) -> Rgba<u8> {
+    let x0 = (x.floor() as u32).min(img.width() - 1);
+    let y0 = (y.floor() as u32).min(img.height() - 1);
+    let x1 = (x0 + 1).min(img.width() - 1);
+    let y1 = (y0 + 1).min(img.height() - 1);
+    let dx = x - x0 as f64;
+    let dy = y - y0 as f64;
+
+    let mut result = [0_u8; 4];
+    for (i, value) in result.iter_mut().enumerate() {
+        let top =
+            img.get_pixel(x0, y0)[i] as f64 * (1. - dx) + img.get_pixel(x1, y0)[i] as f64 * dx;
+        let bottom =
+            img.get_pixel(x0, y1)[i] as f64 * (1. - dx) + img.get_pixel(x1, y1)[i] as f64 * dx;
+        *value = (top * (1. - dy) + bottom * dy).round() as u8;
+    }
+
+    Rgba(result)
+}
+
+/// Reproject a channel image to a grid.
+///
+/// Takes a single channel image as given by `georef::crop_channel()`.
+pub fn reproject(channel_img: &Image, georef: &Georef, rotated: bool, grid: &Grid) -> Image {
+    info!(
+        "Reprojecting to {:?}, {}x{} pixels",
+        grid.projection, grid.width, grid.height
+    );
+
+    let mut result = Image::new(grid.width, grid.height);
+
+    for y in 0..grid.height {
+        for x in 0..grid.width {
+            let latlon = grid.px_to_latlon((x, y));
+            if let Some(px) = latlon_to_channel_px(georef, rotated, latlon) {
+                result.put_pixel(x, y, interpolate(channel_img, px));
+            }
+        }
+    }
+
+    result
+}
+
+/// Write a reprojected image.
+///
+/// If the filename ends in `.tif` or `.tiff` a GeoTIFF is written. Otherwise
+/// the image is saved with the `image` crate and the transform is written to a
+/// `.aux.xml` sidecar that GDAL reads automatically. Only PNG keeps the
+/// transparency of pixels outside the swath.
+pub fn write_projected(img: &Image, grid: &Grid, filename: &Path) -> err::Result<()> {
+    let extension = filename
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    let transform = grid.geotransform();
+
+    match extension.as_deref() {
+        Some("tif") | Some("tiff") => {
+            info!("Writing GeoTIFF to {}", filename.display());
+
+            let geokeys: Vec<u16> = match grid.projection {
+                Projection::Equirectangular => georef::GEOKEYS_WGS84.to_vec(),
+                #[rustfmt::skip]
+                Projection::Mercator => vec![
+                    1, 1, 0, 3,       // Version, revision, minor revision, number of keys
+                    1024, 0, 1, 1,    // GTModelTypeGeoKey: Projected
+                    1025, 0, 1, 1,    // GTRasterTypeGeoKey: PixelIsArea
+                    3072, 0, 1, 3857, // ProjectedCSTypeGeoKey: Web Mercator
+                ],
+            };
+
+            georef::write_geotiff_tags(
+                georef::Raster::Rgb(img),
+                &[0., 0., 0., transform[0], transform[3], 0.],
+                Some([transform[1], -transform[5], 0.]),
+                &geokeys,
+                filename,
+            )
+        }
+        _ => {
+            img.save(filename)?;
+
+            let mut aux_filename = filename.as_os_str().to_owned();
+            aux_filename.push(".aux.xml");
+            info!("Writing geotransform to {:?}", aux_filename);
+
+            let mut file = BufWriter::new(File::create(Path::new(&aux_filename))?);
+            writeln!(file, "<PAMDataset>")?;
+            writeln!(file, "  <SRS>EPSG:{}</SRS>", grid.epsg())?;
+            writeln!(
+                file,
+                "  <GeoTransform>{}, {}, {}, {}, {}, {}</GeoTransform>",
+                transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]
+            )?;
+            writeln!(file, "</PAMDataset>")?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check that the projections are invertible and pixel centers fall
+    /// inside the bounding box.
+    #[test]
+    fn test_grid() {
+        let bbox = BoundingBox {
+            west: 170.,
+            south: -40.,
+            east: -170.,
+            north: -20.,
+        };
+
+        for &projection in &[Projection::Equirectangular, Projection::Mercator] {
+            for &lat in &[-1.2, -0.3, 0., 0.7] {
+                let y = projection.lat_to_y(lat);
+                assert!((projection.y_to_lat(y) - lat).abs() < 1e-12);
+            }
+
+            let grid = Grid::new(projection, bbox, 0.5).unwrap();
+            assert_eq!(grid.width, 40);
+
+            let (lat, lon) = grid.px_to_latlon((0, 0));
+            assert!((lon.to_degrees() - 170.25).abs() < 1e-9);
+            assert!(lat.to_degrees() < -20. && lat.to_degrees() > -20.5);
+
+            let (lat, _lon) = grid.px_to_latlon((grid.width - 1, grid.height - 1));
+            assert!(lat.to_degrees() < -39.5 && lat.to_degrees() > -40.5);
+        }
+    }
+
+    /// Check unions of regions, also crossing the antimeridian.
+    #[test]
+    fn test_bbox_union() {
+        let a = BoundingBox {
+            west: 170.,
+            south: -40.,
+            east: -170.,
+            north: -20.,
+        };
+        let b = BoundingBox {
+            west: -175.,
+            south: -50.,
+            east: -160.,
+            north: -30.,
+        };
+        let c = BoundingBox {
+            west: -60.,
+            south: 10.,
+            east: -50.,
+            north: 20.,
+        };
+
+        assert_eq!(
+            a.union(&b),
+            BoundingBox {
+                west: 170.,
+                south: -50.,
+                east: -160.,
+                north: -20.,
+            }
+        );
+        assert_eq!(
+            c.union(&BoundingBox {
+                west: -70.,
+                south: 0.,
+                east: -65.,
+                north: 5.,
+            }),
+            BoundingBox {
+                west: -70.,
+                south: 0.,
+                east: -50.,
+                north: 20.,
+            }
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Signal quality metrics of each line.
+//!
+//! Gives objective numbers to compare antennas and receivers: the SNR of each
+//! channel, measured as the contrast of the sync frame against the noise on the
+//! deep space view, the sync frames correlation, the telemetry variance and if
+//! the line looks like a dropout.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::decode::{
+    generate_sync_b_frame, generate_sync_frame, RowStatus, SyncReport, FINAL_RATE,
+    PX_PER_CHANNEL, PX_PER_ROW, PX_SPACE_DATA, PX_SYNC_FRAME,
+};
+use crate::dsp::{Rate, Signal};
+use crate::err;
+use crate::telemetry;
+
+/// Lines with a channel A SNR lower than this in dB are considered dropouts.
+const DROPOUT_SNR: f32 = 3.;
+
+/// SNR in dB that gives the maximum score, I've never seen better images above
+/// this value.
+const GOOD_SNR: f32 = 30.;
+
+/// Quality of an image line.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LineQuality {
+    /// Line number on the image.
+    pub line: usize,
+
+    /// SNR in dB of each channel, None if the deep space view has no noise at
+    /// all.
+    pub snr_a: Option<f32>,
+    pub snr_b: Option<f32>,
+
+    /// Normalized correlation of the sync frames, from -1 to 1.
+    pub sync_a: f32,
+    pub sync_b: f32,
+
+    /// Horizontal variance of the telemetry bands.
+    pub telemetry_variance: f32,
+
+    /// True if no sync frame was found or the signal is lost under the noise.
+    pub dropout: bool,
+}
+
+/// Summary of the quality of the whole pass.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QualitySummary {
+    pub lines: usize,
+    pub dropouts: usize,
+
+    /// Median SNR in dB of each channel.
+    pub snr_a: Option<f32>,
+    pub snr_b: Option<f32>,
+
+    /// Mean normalized correlation of the sync A frames.
+    pub sync_a: f32,
+
+    /// Median horizontal variance of the telemetry bands.
+    pub telemetry_variance: f32,
+
+    /// Sample rate error in ppm, if it was estimated.
+    pub sample_rate_error: Option<f64>,
+
+    /// Score of the pass from 0 to 100. It's the percentage of lines that are
+    /// not dropouts, scaled by how close the median SNR is to `GOOD_SNR`.
+    pub score: f32,
+}
+
+/// Quality metrics of each line and of the whole pass.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QualityReport {
+    pub summary: QualitySummary,
+    pub lines: Vec<LineQuality>,
+}
+
+/// SNR in dB, from the contrast of the sync frame and the noise of the deep
+/// space view.
+fn snr(line: &[f32], sync_frame: &[i8], offset: usize) -> Option<f32> {
+    let mean = |values: &mut dyn Iterator<Item = f32>| {
+        let (sum, count) = values.fold((0., 0), |(s, c), x| (s + x, c + 1));
+        sum / count as f32
+    };
+
+    let sync = &line[offset..offset + sync_frame.len()];
+    let high = mean(&mut sync.iter().zip(sync_frame).filter(|(_, &g)| g == 1).map(|(&x, _)| x));
+    let low = mean(&mut sync.iter().zip(sync_frame).filter(|(_, &g)| g != 1).map(|(&x, _)| x));
+
+    let space_start = offset + PX_SYNC_FRAME as usize;
+    let space = &line[space_start..space_start + PX_SPACE_DATA as usize];
+    let space_mean = mean(&mut space.iter().cloned());
+    let noise = mean(&mut space.iter().map(|x| (x - space_mean).powi(2))).sqrt();
+
+    let snr = 20. * ((high - low).abs() / noise).log10();
+    if snr.is_finite() {
+        Some(snr)
+    } else {
+        None
+    }
+}
+
+/// Median of some values, None if empty.
+fn median(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let mut sorted: Vec<f32> = values.collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    sorted.get(sorted.len() / 2).cloned()
+}
+
+impl QualityReport {
+    /// Measure the quality of the lines of a decoded image.
+    ///
+    /// Takes the signal returned by `decode()`, one sample per pixel, and the
+    /// sync report of the same decode.
+    pub fn new(signal: &Signal, sync_report: &SyncReport) -> err::Result<QualityReport> {
+        let sync_a = generate_sync_frame(Rate::hz(FINAL_RATE))?;
+        let sync_b = generate_sync_b_frame(Rate::hz(FINAL_RATE))?;
+
+        // Dropped rows are not on the image
+        let rows = sync_report
+            .rows
+            .iter()
+            .filter(|row| row.status != RowStatus::Dropped);
+
+        let lines: Vec<LineQuality> = signal
+            .chunks_exact(PX_PER_ROW as usize)
+            .zip(rows)
+            .enumerate()
+            .map(|(i, (line, row))| {
+                let snr_a = snr(line, &sync_a, 0);
+                LineQuality {
+                    line: i,
+                    snr_a,
+                    snr_b: snr(line, &sync_b, PX_PER_CHANNEL as usize),
+                    sync_a: row.sync_a,
+                    sync_b: row.sync_b,
+                    telemetry_variance: telemetry::line_variance(line),
+                    dropout: row.status == RowStatus::Interpolated
+                        || snr_a.is_none_or(|snr| snr < DROPOUT_SNR),
+                }
+            })
+            .collect();
+
+        let dropouts = lines.iter().filter(|l| l.dropout).count();
+        let snr_a = median(lines.iter().filter_map(|l| l.snr_a));
+
+        let score = if lines.is_empty() {
+            0.
+        } else {
+            100. * (1. - dropouts as f32 / lines.len() as f32)
+                * (snr_a.unwrap_or(0.) / GOOD_SNR).clamp(0., 1.)
+        };
+
+        Ok(QualityReport {
+            summary: QualitySummary {
+                lines: lines.len(),
+                dropouts,
+                snr_a,
+                snr_b: median(lines.iter().filter_map(|l| l.snr_b)),
+                sync_a: lines.iter().map(|l| l.sync_a).sum::<f32>() / lines.len().max(1) as f32,
+                telemetry_variance: median(lines.iter().map(|l| l.telemetry_variance))
+                    .unwrap_or(0.),
+                sample_rate_error: sync_report.sample_rate_error,
+                score,
+            },
+            lines,
+        })
+    }
+
+    /// Write report.
+    ///
+    /// If the filename ends in `.json` the summary and the lines are saved as
+    /// JSON, otherwise the lines are saved as CSV, one row per line.
+    pub fn write(&self, filename: &Path) -> err::Result<()> {
+        let mut file = BufWriter::new(File::create(filename)?);
+
+        let extension = filename
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        if extension.as_deref() == Some("json") {
+            serde_json::to_writer_pretty(&mut file, self)
+                .map_err(|e| err::Error::Internal(format!("Could not write JSON: {}", e)))?;
+            writeln!(file)?;
+            return Ok(());
+        }
+
+        let optional = |v: Option<f32>| v.map(|v| format!("{:.2}", v)).unwrap_or_default();
+
+        writeln!(file, "line,snr_a,snr_b,sync_a,sync_b,telemetry_variance,dropout")?;
+        for line in &self.lines {
+            writeln!(
+                file,
+                "{},{},{},{:.4},{:.4},{:.4},{}",
+                line.line,
+                optional(line.snr_a),
+                optional(line.snr_b),
+                line.sync_a,
+                line.sync_b,
+                line.telemetry_variance,
+                line.dropout,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decode::RowSync;
+
+    /// Check the metrics on a synthetic image with a noisy line.
+    #[test]
+    fn test_quality_report() {
+        let sync_a = generate_sync_frame(Rate::hz(FINAL_RATE)).unwrap();
+        let sync_b = generate_sync_b_frame(Rate::hz(FINAL_RATE)).unwrap();
+
+        // Pseudo random noise on every pixel
+        let noise = |i: usize| ((i * 7919) % 101) as f32 / 101. - 0.5;
+
+        let make_line = |amplitude: f32, line: usize| -> Signal {
+            let mut values: Signal =
+                (0..PX_PER_ROW as usize).map(|i| noise(i + line * 13) * 0.01).collect();
+            for (i, &v) in sync_a.iter().enumerate() {
+                values[i] += v as f32 * amplitude;
+            }
+            for (i, &v) in sync_b.iter().enumerate() {
+                values[PX_PER_CHANNEL as usize + i] += v as f32 * amplitude;
+            }
+            values
+        };
+
+        let row = |status| RowSync {
+            position: 0.,
+            sync_a: 0.9,
+            sync_b: 0.8,
+            sync_b_offset: 0,
+            swapped: false,
+            status,
+        };
+
+        let mut signal = make_line(1., 0);
+        signal.extend(make_line(0.001, 1));
+        signal.extend(make_line(1., 2));
+        let sync_report = SyncReport {
+            rows: vec![
+                row(RowStatus::Synced),
+                row(RowStatus::Synced),
+                row(RowStatus::Dropped),
+                row(RowStatus::Interpolated),
+            ],
+            sample_rate_error: Some(12.),
+        };
+
+        let report = QualityReport::new(&signal, &sync_report).unwrap();
+
+        assert_eq!(report.lines.len(), 3);
+        // Contrast of 2 against noise with standard deviation of about 0.003
+        assert!(report.lines[0].snr_a.unwrap() > 50.);
+        assert!(report.lines[0].snr_b.unwrap() > 50.);
+        assert!(!report.lines[0].dropout);
+        assert!(report.lines[1].dropout);
+        assert!(report.lines[2].dropout);
+        assert_eq!(report.summary.dropouts, 2);
+        assert_eq!(report.summary.sample_rate_error, Some(12.));
+        assert!((report.summary.score - 100. / 3.).abs() < 0.1);
+    }
+}
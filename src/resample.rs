@@ -1,25 +1,209 @@
 //! High-level function for resampling.
 
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use log::info;
+use log::{debug, info, warn};
 
 use crate::config;
 use crate::context::{Context, Step};
-use crate::dsp::{self, Freq, Rate};
+use crate::dsp::{self, Freq, Rate, Signal, StreamResampler};
 use crate::err;
 use crate::misc;
 use crate::wav;
 
-/// Load and resample WAV file.
+/// Resample WAV file.
 ///
-/// Copy the modification time timestamp too.
+/// The file is read and written by chunks so the memory used does not depend
+/// on the length of the recording. When exporting steps everything is loaded
+/// at once instead. Copy the modification time timestamp too.
 pub fn resample(
     context: &mut Context,
     settings: config::Settings,
     input_filename: &Path,
     output_filename: &Path,
     output_rate: u32,
+) -> err::Result<()> {
+    let atten = settings.wav_resample_atten;
+    let delta_w = Freq::pi_rad(settings.wav_resample_delta_freq);
+
+    if context.export_steps {
+        info!("Exporting steps, loading the whole WAV file in memory");
+        resample_in_memory(
+            context,
+            input_filename,
+            output_filename,
+            output_rate,
+            atten,
+            delta_w,
+        )
+    } else {
+        resample_by_chunks(
+            context,
+            input_filename,
+            output_filename,
+            output_rate,
+            atten,
+            delta_w,
+        )
+    }
+}
+
+/// Resample WAV file reading and writing by chunks.
+fn resample_by_chunks(
+    context: &mut Context,
+    input_filename: &Path,
+    output_filename: &Path,
+    output_rate: u32,
+    atten: f32,
+    delta_w: Freq,
+) -> err::Result<()> {
+    info!("Resampling to {}Hz by chunks", output_rate);
+    context.status(0., format!("Resampling to {}", output_rate));
+
+    let mut reader = wav::WavChunks::open(input_filename)?;
+    let input_rate = Rate::hz(reader.spec().sample_rate);
+    let timestamp = misc::read_timestamp(input_filename)?;
+
+    let mut resampler = StreamResampler::new(
+        input_rate,
+        Rate::hz(output_rate),
+        dsp::resample_lowpass(input_rate, Rate::hz(output_rate), atten, delta_w),
+    )?;
+
+    // The samples are normalized by the maximum, which is known only at the
+    // end. So write them first as floats to a temporary file
+    let mut temp_filename = output_filename.as_os_str().to_owned();
+    temp_filename.push(".tmp");
+    let temp_filename = PathBuf::from(temp_filename);
+
+    let result = write_resampled(
+        context,
+        &mut reader,
+        &mut resampler,
+        &temp_filename,
+        output_rate,
+    )
+    .and_then(|max| write_normalized(context, &temp_filename, output_filename, max));
+
+    if temp_filename.exists() {
+        if let Err(e) = fs::remove_file(&temp_filename) {
+            warn!(
+                "Could not remove temporary file '{}': {}",
+                temp_filename.display(),
+                e
+            );
+        }
+    }
+    result?;
+
+    misc::write_timestamp(timestamp, output_filename)?;
+
+    context.status(1., "Finished".to_string());
+    Ok(())
+}
+
+/// Resample every chunk from `reader` and write it as 32 bit float.
+///
+/// Returns the maximum sample.
+fn write_resampled(
+    context: &mut Context,
+    reader: &mut wav::WavChunks,
+    resampler: &mut StreamResampler,
+    filename: &Path,
+    output_rate: u32,
+) -> err::Result<f32> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: output_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(filename, spec)?;
+
+    let total = reader.duration().max(1);
+    let mut read: u64 = 0;
+    let mut last_percent = 0;
+    let mut max: Option<f32> = None;
+
+    let mut write = |samples: Signal| -> err::Result<()> {
+        for sample in samples {
+            max = Some(max.map_or(sample, |max| max.max(sample)));
+            writer.write_sample(sample)?;
+        }
+        Ok(())
+    };
+
+    while let Some(chunk) = reader.read()? {
+        context.check_cancelled()?;
+        write(resampler.process(&chunk))?;
+
+        // Notify every 10%, writing the output takes the last 10% of the bar
+        read += chunk.len() as u64;
+        let percent = read * 10 / total * 10;
+        if percent > last_percent {
+            last_percent = percent;
+            context.status(
+                0.9 * percent as f32 / 100.,
+                format!("Resampling to {}: {}%", output_rate, percent),
+            );
+        }
+    }
+    write(resampler.finish())?;
+
+    writer.finalize()?;
+
+    max.ok_or_else(|| {
+        err::Error::Internal(
+            "Got zero samples after resampling, audio file too short or \
+            output sampling frequency too low"
+                .to_string(),
+        )
+    })
+}
+
+/// Copy samples from the temporary file to the output file, normalized as
+/// 16 bit integers.
+fn write_normalized(
+    context: &mut Context,
+    temp_filename: &Path,
+    output_filename: &Path,
+    max: f32,
+) -> err::Result<()> {
+    info!("Writing WAV to '{}'", output_filename.display());
+    context.status(
+        0.9,
+        format!("Writing WAV to '{}'", output_filename.display()),
+    );
+    debug!("Max: {}", max);
+
+    let mut reader = wav::WavChunks::open(temp_filename)?;
+    let spec = hound::WavSpec {
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+        ..reader.spec()
+    };
+    let mut writer = hound::WavWriter::create(output_filename, spec)?;
+
+    while let Some(chunk) = reader.read()? {
+        context.check_cancelled()?;
+        for sample in chunk {
+            writer.write_sample((sample / max * i16::MAX as f32) as i16)?;
+        }
+    }
+
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Resample the whole WAV file at once, needed to export steps.
+fn resample_in_memory(
+    context: &mut Context,
+    input_filename: &Path,
+    output_filename: &Path,
+    output_rate: u32,
+    atten: f32,
+    delta_w: Freq,
 ) -> err::Result<()> {
     info!("Reading WAV file");
     context.status(0.0, "Reading WAV file".to_string());
@@ -38,8 +222,8 @@ pub fn resample(
         &input_signal,
         input_rate,
         Rate::hz(output_rate),
-        settings.wav_resample_atten,
-        Freq::pi_rad(settings.wav_resample_delta_freq),
+        atten,
+        delta_w,
     )?;
 
     if resampled.is_empty() {
@@ -69,3 +253,99 @@ pub fn resample(
     context.status(1., "Finished".to_string());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check that resampling a stereo file by chunks gives the same result as
+    /// loading it entirely, using only the first channel.
+    fn check_resample_by_chunks(input_rate: u32, output_rate: u32) {
+        let dir = std::env::temp_dir();
+        let name = |suffix: &str| {
+            dir.join(format!(
+                "noaa-apt-{}-{}-{}.wav",
+                std::process::id(),
+                input_rate,
+                suffix
+            ))
+        };
+        let (input, chunked, in_memory) = (name("input"), name("chunked"), name("in-memory"));
+
+        // Longer than a chunk, the second channel should be ignored
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: input_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let left: Vec<i16> = (0..100_000)
+            .map(|i| {
+                let t = i as f32 / input_rate as f32;
+                ((t * 2400. * std::f32::consts::TAU).sin() * (1. + (t * 3.).sin()) * 10000.) as i16
+            })
+            .collect();
+        let mut writer = hound::WavWriter::create(&input, spec).unwrap();
+        for (i, sample) in left.iter().enumerate() {
+            writer.write_sample(*sample).unwrap();
+            writer.write_sample((i % 200) as i16 * 100).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut context = Context::resample(|_, _| {}, Vec::new(), false);
+        let delta_w = Freq::pi_rad(0.1);
+        resample_by_chunks(&mut context, &input, &chunked, output_rate, 60., delta_w).unwrap();
+        resample_in_memory(&mut context, &input, &in_memory, output_rate, 60., delta_w).unwrap();
+
+        let load = |filename: &Path| -> Vec<i16> {
+            let mut reader = hound::WavReader::open(filename).unwrap();
+            assert_eq!(reader.spec().channels, 1);
+            assert_eq!(reader.spec().sample_rate, output_rate);
+            reader.samples::<i16>().map(|s| s.unwrap()).collect()
+        };
+        let (chunked_samples, in_memory_samples) = (load(&chunked), load(&in_memory));
+
+        for filename in &[input, chunked, in_memory] {
+            fs::remove_file(filename).unwrap();
+        }
+
+        // Both read the file in the same way, so check also that they used
+        // the first channel
+        let left: Signal = left.iter().map(|&x| x as f32).collect();
+        let expected = dsp::resample(
+            &mut context,
+            &left,
+            Rate::hz(input_rate),
+            Rate::hz(output_rate),
+            60.,
+            delta_w,
+        )
+        .unwrap();
+        let max = dsp::get_max(&expected).unwrap();
+
+        assert_eq!(chunked_samples.len(), expected.len());
+        assert_eq!(in_memory_samples.len(), expected.len());
+        for ((a, b), c) in chunked_samples
+            .iter()
+            .zip(in_memory_samples.iter())
+            .zip(expected.iter())
+        {
+            let c = (c / max * i16::MAX as f32) as i16;
+            assert!((a - b).abs() <= 1);
+            assert!((a - c).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_resample_by_chunks() {
+        check_resample_by_chunks(11025, 20800);
+    }
+
+    /// Without interpolation the filter is not the same as when interpolating.
+    #[test]
+    fn test_resample_by_chunks_decimation() {
+        check_resample_by_chunks(41600, 20800);
+        check_resample_by_chunks(62400, 20800);
+    }
+}
@@ -0,0 +1,250 @@
+//! Implementations of `StepSink`, destinations for the intermediate steps of
+//! the decoding process.
+//!
+//! The files are saved on the current folder, named after
+//! `StepInfo::filename`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config;
+use crate::context::{StepInfo, StepSink};
+use crate::dsp::{Rate, Signal};
+use crate::err;
+use crate::misc;
+use crate::plot;
+use crate::wav;
+
+/// Filename of the HTML index of the plots.
+const PLOTS_INDEX_FILENAME: &str = "steps.html";
+
+/// Sinks chosen by the user on the commandline.
+pub fn from_settings(settings: &config::Settings) -> Vec<Box<dyn StepSink>> {
+    let mut sinks: Vec<Box<dyn StepSink>> = Vec::new();
+
+    if settings.export_wav {
+        sinks.push(Box::new(WavSink));
+    }
+    if settings.export_plots {
+        sinks.push(Box::new(PlotSink::default()));
+    }
+    if let Some(format) = settings.export_float {
+        sinks.push(Box::new(FloatSink { format }));
+    }
+
+    sinks
+}
+
+/// Saves each step as a WAV file.
+pub struct WavSink;
+
+impl StepSink for WavSink {
+    fn step(&mut self, info: &StepInfo, signal: &Signal) -> err::Result<()> {
+        let writer_spec = hound::WavSpec {
+            channels: 1,
+            // Filters have no rate, could be anything
+            sample_rate: info.rate.map_or(1, |r| r.get_hz()),
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        let filename = PathBuf::from(info.filename).with_extension("wav");
+        wav::write_wav(&filename, signal, writer_spec)
+    }
+}
+
+/// Saves plots of each step as PNG and an HTML index of every plot.
+///
+/// Signals are plotted against time and also their spectrum, filters are
+/// plotted as their frequency response.
+#[derive(Default)]
+pub struct PlotSink {
+    /// Plots saved so far, for the HTML index.
+    entries: Vec<plot::IndexEntry>,
+}
+
+impl StepSink for PlotSink {
+    fn step(&mut self, info: &StepInfo, signal: &Signal) -> err::Result<()> {
+        let plots = match info.rate {
+            Some(rate) => vec![
+                ("time", plot::time(signal, rate)),
+                ("spectrum", plot::spectrum(signal, rate)),
+            ],
+            None => vec![("response", plot::filter(signal))],
+        };
+
+        let mut entry = plot::IndexEntry {
+            name: info.filename.to_string(),
+            description: info.description.to_string(),
+            plots: Vec::new(),
+        };
+        for (suffix, plot) in plots {
+            let plot_filename = format!("{}_{}.png", info.filename, suffix);
+            plot.image.save(&plot_filename)?;
+            entry.plots.push((plot_filename, plot.title, plot.x, plot.y));
+        }
+        self.entries.push(entry);
+
+        // Rewritten on every step, so it's available even if the decode fails
+        plot::write_index(Path::new(PLOTS_INDEX_FILENAME), &self.entries)
+    }
+}
+
+/// Format of the files saved by `FloatSink`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloatFormat {
+    /// NumPy array file, `.npy`.
+    Npy,
+
+    /// Raw 32 bit float samples, little endian, `.f32`.
+    Raw,
+}
+
+impl FloatFormat {
+    /// Parse format name as given on the commandline.
+    pub fn from_name(name: &str) -> Option<FloatFormat> {
+        match name {
+            "npy" => Some(FloatFormat::Npy),
+            "raw" => Some(FloatFormat::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// Saves each step as an array of 32 bit floats, easy to load from Python or
+/// GNU Octave. Unlike WAV files the sample rate is not saved.
+pub struct FloatSink {
+    pub format: FloatFormat,
+}
+
+impl StepSink for FloatSink {
+    fn step(&mut self, info: &StepInfo, signal: &Signal) -> err::Result<()> {
+        let extension = match self.format {
+            FloatFormat::Npy => "npy",
+            FloatFormat::Raw => "f32",
+        };
+        let filename = PathBuf::from(info.filename).with_extension(extension);
+        let mut file = BufWriter::new(File::create(filename)?);
+
+        if self.format == FloatFormat::Npy {
+            file.write_all(&misc::npy_header(&[signal.len()]))?;
+        }
+        for sample in signal {
+            file.write_all(&sample.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Step kept by `MemorySink`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedStep {
+    /// Sample rate, None for filters.
+    pub rate: Option<Rate>,
+    pub signal: Signal,
+}
+
+/// Keeps every step on memory, for programs that want to look at the
+/// intermediate signals without reading files.
+///
+/// Clones share the same steps, so keep a clone before giving the sink to the
+/// `Context`.
+#[allow(dead_code)] // Not used by noaa-apt itself
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    /// Every occurrence of each step, by step id.
+    steps: Arc<Mutex<HashMap<String, Vec<RecordedStep>>>>,
+}
+
+#[allow(dead_code)] // Not used by noaa-apt itself
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    /// Every occurrence of a step, in order. Empty if the step didn't happen.
+    ///
+    /// Some steps like `resample_filter` happen twice while decoding.
+    pub fn get(&self, id: &str) -> Vec<RecordedStep> {
+        self.steps
+            .lock()
+            .expect("Poisoned lock")
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Ids of the steps received so far.
+    pub fn ids(&self) -> Vec<String> {
+        self.steps.lock().expect("Poisoned lock").keys().cloned().collect()
+    }
+}
+
+impl StepSink for MemorySink {
+    fn step(&mut self, info: &StepInfo, signal: &Signal) -> err::Result<()> {
+        self.steps
+            .lock()
+            .map_err(|_| err::Error::Internal("Poisoned lock".to_string()))?
+            .entry(info.id.to_string())
+            .or_default()
+            .push(RecordedStep {
+                rate: info.rate,
+                signal: signal.clone(),
+            });
+
+        Ok(())
+    }
+}
+
+/// Ignores every step.
+///
+/// Useful where a sink is needed but nothing should be saved, the `Context`
+/// doesn't do any extra work to produce the steps.
+#[allow(dead_code)] // Not used by noaa-apt itself
+pub struct NullSink;
+
+impl StepSink for NullSink {
+    fn step(&mut self, _info: &StepInfo, _signal: &Signal) -> err::Result<()> {
+        Ok(())
+    }
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::context::{Context, Step};
+
+    /// Check that the steps reach the sinks with the metadata of the
+    /// `Context`.
+    #[test]
+    fn test_memory_sink() {
+        let memory = MemorySink::new();
+        let mut context = Context::resample(|_, _| {}, vec![Box::new(memory.clone())], false);
+        assert!(context.export_steps);
+
+        let signal: Signal = vec![1., 2., 3.];
+        context.step(Step::signal("input", &signal, Some(Rate::hz(1000)))).unwrap();
+        context.step(Step::filter("resample_filter", &signal)).unwrap();
+        // Not expected on this Context
+        context.step(Step::signal("sync_correlation", &signal, None)).unwrap();
+
+        let input = memory.get("input");
+        assert_eq!(input.len(), 1);
+        assert_eq!(input[0].rate, Some(Rate::hz(1000)));
+        assert_eq!(input[0].signal, signal);
+        assert_eq!(memory.get("resample_filter")[0].rate, None);
+        assert!(memory.get("sync_correlation").is_empty());
+
+        let context = Context::resample(|_, _| {}, vec![Box::new(NullSink)], false);
+        assert!(!context.export_steps);
+    }
+}
@@ -0,0 +1,580 @@
+//! High-level function for decoding a live stream of samples.
+//!
+//! Reads raw PCM samples (for example from `rtl_fm` through a pipe) and decodes
+//! them incrementally, the output image is rewritten every few rows so it can
+//! be watched while the pass is still in progress.
+
+use std::io::Read;
+use std::path::Path;
+
+use image::{GrayImage, ImageBuffer, PixelWithColorType};
+use log::{debug, info};
+
+use crate::config;
+use crate::context::{CancelToken, Context};
+use crate::decode::{
+    self, generate_sync_frame, sync_report, LineTracker, RowStatus, SyncReport, CARRIER_FREQ,
+    FINAL_RATE, PX_PER_ROW,
+};
+use crate::dsp::{self, Freq, Rate, Signal, StreamDemodulator, StreamFilter, StreamResampler};
+use crate::err;
+use crate::filters;
+use crate::misc;
+use crate::noaa_apt::{self, ColorSettings, Contrast, OrbitSettings, Rotate};
+
+/// Number of rows between each rewrite of the output image.
+const ROWS_PER_UPDATE: usize = 20;
+
+/// Number of samples to read each time from the input.
+const SAMPLES_PER_READ: usize = 4096;
+
+/// Raw sample formats supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawFormat {
+    /// Unsigned 8 bit integer, centered on 127.5. Used by RTL-SDR IQ
+    /// recordings.
+    U8,
+
+    /// Signed 16 bit integer, little endian.
+    S16Le,
+
+    /// 32 bit float, little endian.
+    F32Le,
+}
+
+impl RawFormat {
+    /// Parse format name as given on the commandline.
+    pub fn from_name(name: &str) -> Option<RawFormat> {
+        match name {
+            "u8" => Some(RawFormat::U8),
+            "s16le" => Some(RawFormat::S16Le),
+            "f32le" => Some(RawFormat::F32Le),
+            _ => None,
+        }
+    }
+
+    /// Size of each sample in bytes.
+    pub fn sample_size(self) -> usize {
+        match self {
+            RawFormat::U8 => 1,
+            RawFormat::S16Le => 2,
+            RawFormat::F32Le => 4,
+        }
+    }
+
+    /// Convert bytes to a sample. `bytes` should have the correct length.
+    ///
+    /// Integers are not normalized, the same as when loading WAV files.
+    /// Unsigned integers are only centered on zero.
+    fn to_sample(self, bytes: &[u8]) -> f32 {
+        match self {
+            RawFormat::U8 => bytes[0] as f32 - 127.5,
+            RawFormat::S16Le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+            RawFormat::F32Le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+/// Reads samples of a given format from a `Read`.
+///
+/// Keeps bytes of incomplete samples between reads, because pipes can give us
+/// any amount of bytes.
+pub struct RawReader<R: Read> {
+    reader: R,
+    format: RawFormat,
+    buffer: Vec<u8>,
+    leftover: Vec<u8>,
+}
+
+impl<R: Read> RawReader<R> {
+    pub fn new(reader: R, format: RawFormat) -> RawReader<R> {
+        RawReader {
+            reader,
+            format,
+            buffer: vec![0; SAMPLES_PER_READ * format.sample_size()],
+            leftover: Vec::new(),
+        }
+    }
+
+    /// Read next samples. Returns `None` at the end of the stream.
+    pub fn read(&mut self) -> err::Result<Option<Signal>> {
+        let size = self.format.sample_size();
+
+        let count = loop {
+            match self.reader.read(&mut self.buffer) {
+                Ok(count) => break count,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        if count == 0 {
+            if !self.leftover.is_empty() {
+                debug!(
+                    "Ignoring {} bytes of incomplete sample",
+                    self.leftover.len()
+                );
+            }
+            return Ok(None);
+        }
+
+        self.leftover.extend_from_slice(&self.buffer[..count]);
+        let complete = self.leftover.len() / size * size;
+        let samples = self.leftover[..complete]
+            .chunks_exact(size)
+            .map(|bytes| self.format.to_sample(bytes))
+            .collect();
+        self.leftover.drain(..complete);
+
+        Ok(Some(samples))
+    }
+}
+
+/// Decodes image rows from consecutive chunks of samples.
+///
+/// Does the same as `decode::decode()`, but keeps in memory only the samples
+/// needed for the next rows.
+struct StreamDecoder {
+    resampler: StreamResampler,
+    demodulator: StreamDemodulator,
+    filter: StreamFilter,
+    rows: StreamSync,
+}
+
+impl StreamDecoder {
+    fn new(settings: &config::Settings, input_rate: Rate, sync: bool) -> err::Result<Self> {
+        let work_rate = Rate::hz(settings.work_rate);
+
+        // Same filters as decode::decode()
+        let resampler = StreamResampler::new(
+            input_rate,
+            work_rate,
+            filters::LowpassDcRemoval {
+                cutout: Freq::hz(settings.resample_cutout, input_rate),
+                atten: settings.resample_atten,
+                delta_w: Freq::hz(settings.resample_delta_freq, input_rate),
+            },
+        )?;
+
+        let cutout = Freq::pi_rad(FINAL_RATE as f32 / work_rate.get_hz() as f32);
+        let filter = StreamFilter::new(filters::Lowpass {
+            cutout,
+            atten: settings.demodulation_atten,
+            delta_w: cutout / 5.,
+        });
+
+        Ok(StreamDecoder {
+            resampler,
+            demodulator: StreamDemodulator::new(Freq::hz(CARRIER_FREQ as f32, work_rate)),
+            filter,
+            rows: StreamSync::new(work_rate, sync)?,
+        })
+    }
+
+    /// Process a new chunk of input samples, returns decoded rows at
+    /// `FINAL_RATE`, can be empty.
+    fn process(&mut self, chunk: &[f32]) -> err::Result<Signal> {
+        let resampled = self.resampler.process(chunk);
+        self.push_work(&resampled)
+    }
+
+    /// Process the last samples, returns the last decoded rows.
+    fn finish(&mut self) -> err::Result<Signal> {
+        let resampled = self.resampler.finish();
+        self.push_work(&resampled)
+    }
+
+    /// Demodulate and filter samples at `work_rate`, then get the rows
+    /// available.
+    fn push_work(&mut self, resampled: &[f32]) -> err::Result<Signal> {
+        let filtered = self.filter.process(&self.demodulator.process(resampled));
+        self.rows.push(&filtered)
+    }
+}
+
+/// Splits a demodulated signal at `work_rate` in rows, by chunks.
+///
+/// The sync frames are searched with a `LineTracker` like
+/// `decode::find_sync()` does, but the correlation is calculated only on the
+/// window where the tracker expects the next sync frame.
+struct StreamSync {
+    work_rate: Rate,
+    sync: bool,
+    tracker: LineTracker,
+    guard: Vec<i8>,
+
+    /// Samples on each image row when at `work_rate`.
+    samples_per_work_row: usize,
+
+    /// Width of pixels at `work_rate`, also the decimation factor to
+    /// `FINAL_RATE`.
+    pixel_width: usize,
+
+    /// Samples at `work_rate` not used yet.
+    buffer: Signal,
+
+    /// Index of the first sample in `buffer`.
+    buffer_start: usize,
+
+    /// Sync information of the rows given so far.
+    report: SyncReport,
+}
+
+impl StreamSync {
+    fn new(work_rate: Rate, sync: bool) -> err::Result<Self> {
+        let pixel_width = (work_rate.get_hz() / FINAL_RATE) as usize;
+        let samples_per_work_row = PX_PER_ROW as usize * pixel_width;
+
+        Ok(StreamSync {
+            work_rate,
+            sync,
+            tracker: LineTracker::new(samples_per_work_row as f64),
+            guard: generate_sync_frame(work_rate)?,
+            samples_per_work_row,
+            pixel_width,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            report: SyncReport::default(),
+        })
+    }
+
+    /// Add samples, returns the rows available at `FINAL_RATE`, can be empty.
+    fn push(&mut self, samples: &[f32]) -> err::Result<Signal> {
+        self.buffer.extend_from_slice(samples);
+
+        let row = self.samples_per_work_row;
+        let received = self.buffer_start + self.buffer.len();
+        let mut output = Vec::new();
+
+        loop {
+            let (position, status) = if self.sync {
+                // We need the correlation until `to` and a whole row after it
+                let (from, to) = self.tracker.search_range(self.pixel_width);
+                if received < to + row + 2 {
+                    break;
+                }
+
+                let measured = self.find_sync(from, to);
+                self.tracker.update(measured);
+
+                let status = match measured {
+                    Some(_) => RowStatus::Synced,
+                    None => RowStatus::Interpolated,
+                };
+                (self.tracker.phase().max(0.), status)
+            } else {
+                let position = self.report.rows.len() * row;
+                if received < position + row {
+                    break;
+                }
+                (position as f64, RowStatus::Unsynced)
+            };
+
+            let local = position - self.buffer_start as f64;
+            let samples = dsp::fractional_slice(&self.buffer, local, row);
+            output.extend(samples.iter().step_by(self.pixel_width));
+
+            let mut report = sync_report(&self.buffer, &[(local, status)], self.work_rate)?;
+            report.rows[0].position = position;
+            self.report.rows.append(&mut report.rows);
+
+            // Drop samples that are not going to be used again, the
+            // interpolation needs one sample before the next row
+            let next = if self.sync {
+                self.tracker.search_range(self.pixel_width).0
+            } else {
+                position as usize + row
+            };
+            let drop =
+                (next.saturating_sub(2).saturating_sub(self.buffer_start)).min(self.buffer.len());
+            self.buffer.drain(..drop);
+            self.buffer_start += drop;
+        }
+
+        Ok(output)
+    }
+
+    /// Find sync frame searching from `from` to `to` not included, both
+    /// absolute indices.
+    fn find_sync(&self, from: usize, to: usize) -> Option<f64> {
+        // Also one sample before and after the window, needed to get the
+        // fractional position
+        let start = from.saturating_sub(1);
+        let correlation: Signal = (start..=to)
+            .map(|i| {
+                let window = &self.buffer[i - self.buffer_start..];
+                self.guard
+                    .iter()
+                    .zip(window.iter())
+                    .map(|(&g, &x)| g as f32 * x)
+                    .sum()
+            })
+            .collect();
+
+        decode::sync_peak(&correlation, from - start, to - start, |i| {
+            decode::normalized_correlation(&self.buffer, start + i - self.buffer_start, &self.guard)
+        })
+        .map(|position| position + start as f64)
+    }
+}
+
+/// Grayscale image of the rows received so far.
+///
+/// New rows are mapped to pixels with the last contrast limits and appended.
+/// The limits are calculated again with 98 percent contrast adjustment only
+/// when the number of rows doubles, so the work done on each update doesn't
+/// grow with the length of the pass.
+struct Preview {
+    /// Pixels of the rows mapped so far.
+    pixels: Vec<u8>,
+
+    /// Contrast limits, low and high.
+    limits: (f32, f32),
+
+    /// Number of rows when the limits were calculated.
+    rows_at_limits: usize,
+}
+
+impl Preview {
+    fn new() -> Preview {
+        Preview {
+            pixels: Vec::new(),
+            limits: (0., 0.),
+            rows_at_limits: 0,
+        }
+    }
+
+    /// Add the new rows of the signal received so far and save the image.
+    fn save(&mut self, signal: &Signal, output_filename: &Path) -> err::Result<()> {
+        let rows = signal.len() / PX_PER_ROW as usize;
+        let len = rows * PX_PER_ROW as usize;
+
+        if rows >= 2 * self.rows_at_limits {
+            debug!("Adjusting preview contrast with {} rows", rows);
+            self.limits = misc::percent(signal, 0.98)?;
+            self.rows_at_limits = rows;
+            self.pixels.clear();
+        }
+
+        let (low, high) = self.limits;
+        let new_pixels = noaa_apt::map_signal_u8(&signal[self.pixels.len()..len], low, high);
+        self.pixels.extend(new_pixels);
+
+        let img = GrayImage::from_vec(PX_PER_ROW, rows as u32, std::mem::take(&mut self.pixels))
+            .ok_or_else(|| {
+                err::Error::Internal("Could not create image, wrong buffer length".to_string())
+            })?;
+        let result = save_replacing(&img, output_filename);
+        self.pixels = img.into_raw();
+
+        result
+    }
+}
+
+/// Decode raw samples from a stream until it ends or until cancelled.
+///
+/// Rewrites the image on `output_filename` every `ROWS_PER_UPDATE` rows.
+/// Intermediate images are a `Preview` in grayscale, the final image uses the
+/// given settings.
+///
+/// When cancelled the final image is saved anyway with the rows received so
+/// far, and then fails with `err::Error::Cancelled`.
+#[allow(clippy::too_many_arguments)]
+pub fn decode_stream(
+    context: &mut Context,
+    settings: &config::Settings,
+    reader: impl Read,
+    format: RawFormat,
+    input_rate: Rate,
+    sync: bool,
+    output_filename: &Path,
+    contrast_adjustment: Contrast,
+    rotate: Rotate,
+    color: Option<ColorSettings>,
+    orbit: Option<OrbitSettings>,
+) -> err::Result<()> {
+    info!("Decoding {:?} stream at {}Hz", format, input_rate.get_hz());
+
+    let mut reader = RawReader::new(reader, format);
+    let mut decoder = StreamDecoder::new(settings, input_rate, sync)?;
+
+    let mut signal: Signal = Vec::new();
+    let mut preview = Preview::new();
+    let mut last_update_rows = 0;
+    let mut cancelled = false;
+
+    while let Some(samples) = reader.read()? {
+        if context.check_cancelled().is_err() {
+            info!("Cancelled, saving the rows received so far");
+            cancelled = true;
+            break;
+        }
+
+        signal.extend(decoder.process(&samples)?);
+
+        let rows = signal.len() / PX_PER_ROW as usize;
+        if rows >= last_update_rows + ROWS_PER_UPDATE {
+            last_update_rows = rows;
+            context.status(0., format!("Got {} rows", rows));
+
+            preview.save(&signal, output_filename)?;
+        }
+    }
+
+    signal.extend(decoder.finish()?);
+
+    let report = &decoder.rows.report;
+    info!(
+        "Rows: {} synced, {} interpolated, {} unsynced, {} with channels swapped",
+        report.count(RowStatus::Synced),
+        report.count(RowStatus::Interpolated),
+        report.count(RowStatus::Unsynced),
+        report.rows.iter().filter(|r| r.swapped).count(),
+    );
+
+    if signal.len() < 10 * PX_PER_ROW as usize {
+        if cancelled {
+            return Err(err::Error::Cancelled);
+        }
+        return Err(err::Error::Internal(
+            "Got less than 10 rows of samples, stream is too short".to_string(),
+        ));
+    }
+
+    // Don't let the cancellation stop the final image, we want to keep what
+    // we received
+    if cancelled {
+        context.set_cancel_token(CancelToken::new());
+    }
+
+    context.status(
+        0.9,
+        format!(
+            "Stream finished, got {} rows",
+            signal.len() / PX_PER_ROW as usize
+        ),
+    );
+
+    let enhancement = color.as_ref().and_then(|c| c.enhancement);
+    let mut img = noaa_apt::process(context, &signal, contrast_adjustment, rotate, color, orbit)?;
+    if let Some(enhancement) = enhancement {
+        img = noaa_apt::draw_legend(&img, enhancement)?;
+    }
+    save_replacing(&img, output_filename)?;
+
+    if cancelled {
+        return Err(err::Error::Cancelled);
+    }
+
+    context.status(1., "Finished".to_string());
+    Ok(())
+}
+
+/// Save image to a temporary file and then rename it, so programs watching the
+/// output never find a half written image.
+fn save_replacing<P>(img: &ImageBuffer<P, Vec<u8>>, output_filename: &Path) -> err::Result<()>
+where
+    P: PixelWithColorType<Subpixel = u8>,
+{
+    let mut temp_filename = output_filename.as_os_str().to_owned();
+    temp_filename.push(".tmp");
+    let temp_filename = std::path::PathBuf::from(temp_filename);
+
+    // The extension of the temporary file is not known by the image library,
+    // so give the format explicitly
+    let format = image::ImageFormat::from_path(output_filename)?;
+    img.save_with_format(&temp_filename, format)?;
+    std::fs::rename(&temp_filename, output_filename)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check that the rows are found at the same positions as when decoding
+    /// the whole signal.
+    #[test]
+    fn test_stream_sync() {
+        let work_rate = Rate::hz(FINAL_RATE * 2);
+        let period = PX_PER_ROW as f64 * 2. * 1.004;
+        let rows = 60;
+
+        // Some values that don't look like sync frames, and a fade without
+        // them
+        let mut seed: u32 = 1;
+        let mut random = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as f32 / 65536.
+        };
+        let mut signal: Signal = (0..(rows as f64 * period) as usize)
+            .map(|_| random())
+            .collect();
+        let sync_a = generate_sync_frame(work_rate).unwrap();
+        for row in (0..rows).filter(|row| !(25..35).contains(row)) {
+            let start = (100. + row as f64 * period).round() as usize;
+            for (i, &v) in sync_a.iter().enumerate() {
+                if start + i < signal.len() {
+                    signal[start + i] = v as f32 + random() * 0.6;
+                }
+            }
+        }
+
+        let expected = decode::find_sync(
+            &mut Context::resample(|_, _| {}, Vec::new(), false), // Dummy context, not important
+            &signal,
+            work_rate,
+        )
+        .unwrap();
+
+        let mut stream_sync = StreamSync::new(work_rate, true).unwrap();
+        let mut output = Vec::new();
+        for chunk in signal.chunks(1000) {
+            output.extend(stream_sync.push(chunk).unwrap());
+        }
+        let found = &stream_sync.report.rows;
+
+        assert!(found.len() >= rows - 2);
+        assert_eq!(output.len(), found.len() * PX_PER_ROW as usize);
+        for (row, (expected, found)) in expected.iter().zip(found.iter()).enumerate() {
+            assert_eq!(
+                expected.1,
+                found.status == RowStatus::Interpolated,
+                "row {}",
+                row
+            );
+            assert!((expected.0 - found.position).abs() < 1e-3, "row {}", row);
+        }
+    }
+
+    /// Check that samples split between reads are joined correctly.
+    #[test]
+    fn test_raw_reader_partial_samples() {
+        /// Gives one byte at a time
+        struct Slow(Vec<u8>, usize);
+        impl Read for Slow {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.1 >= self.0.len() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[self.1];
+                self.1 += 1;
+                Ok(1)
+            }
+        }
+
+        let bytes: Vec<u8> = [1_i16, -2, 300, -32768]
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+        let mut reader = RawReader::new(Slow(bytes, 0), RawFormat::S16Le);
+
+        let mut samples = Vec::new();
+        while let Some(s) = reader.read().unwrap() {
+            samples.extend(s);
+        }
+        assert_eq!(vec![1., -2., 300., -32768.], samples);
+    }
+}
@@ -0,0 +1,81 @@
+//! Position of the Sun.
+//!
+//! Uses the low precision formulas from the Astronomical Almanac, good to about
+//! 0.01 degrees, which is more than enough to know if a pixel is on daylight.
+
+use std::f64::consts::PI;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::geo;
+
+/// Solar zenith angle in degrees where the day ends, below this only the
+/// daytime rendering is used.
+const DAY_ZENITH: f64 = 80.;
+
+/// Solar zenith angle in degrees where the night starts, above this only the
+/// night rendering is used. Includes civil twilight.
+const NIGHT_ZENITH: f64 = 96.;
+
+/// Point on Earth where the Sun is at the zenith, (latitude, longitude) in
+/// radians.
+pub fn subsolar_point(time: DateTime<Utc>) -> (f64, f64) {
+    let j2000 = Utc.with_ymd_and_hms(2000, 1, 1, 12, 0, 0).unwrap();
+    let n = (time - j2000).num_milliseconds() as f64 / 86_400_000.; // Days
+
+    // Mean longitude and mean anomaly
+    let l = (280.460 + 0.985_647_4 * n).to_radians();
+    let g = (357.528 + 0.985_600_3 * n).to_radians();
+
+    // Ecliptic longitude and obliquity of the ecliptic
+    let lambda = l + (1.915 * g.sin() + 0.020 * (2. * g).sin()).to_radians();
+    let epsilon = (23.439 - 0.000_000_4 * n).to_radians();
+
+    let right_ascension = (epsilon.cos() * lambda.sin()).atan2(lambda.cos());
+    let declination = (epsilon.sin() * lambda.sin()).asin();
+
+    // Greenwich mean sidereal time
+    let gmst = (280.460_618_37 + 360.985_647_366_29 * n).to_radians();
+
+    let lon = (right_ascension - gmst + PI).rem_euclid(2. * PI) - PI;
+
+    (declination, lon)
+}
+
+/// Solar zenith angle in degrees of some (latitude, longitude) in radians.
+///
+/// Takes the point given by `subsolar_point()`.
+pub fn zenith(latlon: (f64, f64), subsolar: (f64, f64)) -> f64 {
+    geo::distance(latlon, subsolar).to_degrees()
+}
+
+/// How much of the daytime rendering to use given the solar zenith angle in
+/// degrees, from 1 on daylight to 0 at night.
+///
+/// Smooth so there are no visible steps across the terminator.
+pub fn day_weight(zenith: f64) -> f32 {
+    let t = ((NIGHT_ZENITH - zenith) / (NIGHT_ZENITH - DAY_ZENITH)).clamp(0., 1.);
+    (t * t * (3. - 2. * t)) as f32
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check the subsolar point on a solstice and an equinox.
+    #[test]
+    fn test_subsolar_point() {
+        let (lat, lon) = subsolar_point(Utc.with_ymd_and_hms(2020, 6, 20, 21, 44, 0).unwrap());
+        assert!((lat.to_degrees() - 23.44).abs() < 0.05);
+        assert!((lon.to_degrees() - -146.).abs() < 1.);
+
+        let (lat, lon) = subsolar_point(Utc.with_ymd_and_hms(2021, 3, 20, 12, 0, 0).unwrap());
+        assert!(lat.to_degrees().abs() < 0.5);
+        assert!(lon.to_degrees().abs() < 3.);
+
+        assert_eq!(day_weight(30.), 1.);
+        assert_eq!(day_weight(120.), 0.);
+        assert!(day_weight(85.) > day_weight(90.));
+    }
+}
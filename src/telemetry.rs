@@ -1,11 +1,48 @@
 //! Code for telemetry decoding.
 
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
 use log::{debug, info, warn};
+use serde::Serialize;
 
 use crate::context::{Context, Step};
-use crate::decode::PX_PER_ROW;
-use crate::dsp::Signal;
+use crate::decode::{PX_PER_CHANNEL, PX_PER_ROW, PX_SPACE_DATA, PX_SYNC_FRAME};
+use crate::dsp::{self, Signal};
 use crate::err;
+use crate::noaa_apt::SatName;
+
+/// Rows on each telemetry frame, 16 wedges of 8 rows.
+const ROWS_PER_FRAME: usize = 16 * 8;
+
+/// PRT (platinum resistance thermometer) coefficients, from the NOAA KLM User's
+/// Guide, Appendix D.
+///
+/// The temperature in Kelvin of each PRT is `d0 + d1 * C + d2 * C^2` where `C`
+/// are the counts. Higher order coefficients are zero for these satellites.
+pub fn prt_coefficients(sat_name: &SatName) -> [[f64; 3]; 4] {
+    match sat_name {
+        SatName::Noaa15 => [
+            [276.60157, 0.051045, 1.36328e-6],
+            [276.62531, 0.050909, 1.47266e-6],
+            [276.67413, 0.050907, 1.47656e-6],
+            [276.59258, 0.050966, 1.47656e-6],
+        ],
+        SatName::Noaa18 => [
+            [276.601, 0.05090, 1.657e-6],
+            [276.683, 0.05101, 1.482e-6],
+            [276.565, 0.05117, 1.313e-6],
+            [276.615, 0.05103, 1.484e-6],
+        ],
+        SatName::Noaa19 => [
+            [276.6067, 0.051111, 1.405783e-6],
+            [276.6119, 0.051090, 1.496037e-6],
+            [276.6311, 0.051033, 1.496990e-6],
+            [276.6268, 0.051058, 1.493110e-6],
+        ],
+    }
+}
 
 /// Determines if working channel A or B.
 pub enum Channel {
@@ -20,9 +57,87 @@ pub struct Telemetry {
     // One value for each wedge on each band
     values_a: Vec<f32>,
     values_b: Vec<f32>,
+
+    // Deep space view of each channel, if known
+    space_a: Option<f32>,
+    space_b: Option<f32>,
+
+    // Slope and intercept used by `to_counts()`
+    counts_fit: (f32, f32),
+}
+
+/// Telemetry values in physical units, saved for tracking the instrument
+/// health.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TelemetryReport {
+    pub satellite: Option<String>,
+
+    /// Recording time on RFC 3339 format.
+    pub time: Option<String>,
+
+    pub channel_a: String,
+    pub channel_b: String,
+
+    /// Counts (0 to 1023) of wedges 1 to 16 for each channel.
+    pub wedges_a: Vec<f32>,
+    pub wedges_b: Vec<f32>,
+
+    /// Counts of the four PRTs on the black body, wedges 10 to 13.
+    pub prt_counts: [f32; 4],
+
+    /// Temperature in Kelvin of each PRT, needs the satellite name.
+    pub prt_temperatures: Option<[f32; 4]>,
+
+    /// Mean temperature of the PRTs in Kelvin.
+    pub black_body_temperature: Option<f32>,
+
+    /// Patch temperature in Kelvin, wedge 14.
+    pub patch_temperature: f32,
+
+    /// Counts of the back scan (black body view) of each channel, wedge 15.
+    pub back_scan_a: f32,
+    pub back_scan_b: f32,
+
+    /// Counts of the deep space view of each channel.
+    pub space_view_a: Option<f32>,
+    pub space_view_b: Option<f32>,
 }
 
 impl Telemetry {
+    /// Create from the values of wedges 1 to 16 of each channel, the deep
+    /// space views are unknown.
+    fn new(values_a: Vec<f32>, values_b: Vec<f32>) -> Self {
+        // Least squares fit of the contrast wedges: wedge 9 is zero and wedges
+        // 1 to 8 are 1/8 to 8/8 of the full scale
+        let points: Vec<(f32, f32)> = (1..=9)
+            .map(|wedge| {
+                let counts = if wedge == 9 {
+                    0.
+                } else {
+                    wedge as f32 * 1023. / 8.
+                };
+                ((values_a[wedge - 1] + values_b[wedge - 1]) / 2., counts)
+            })
+            .collect();
+
+        let n = points.len() as f32;
+        let mean_x = points.iter().map(|p| p.0).sum::<f32>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f32>() / n;
+        let slope = points
+            .iter()
+            .map(|p| (p.0 - mean_x) * (p.1 - mean_y))
+            .sum::<f32>()
+            / points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f32>();
+
+        Self {
+            values_a,
+            values_b,
+            space_a: None,
+            space_b: None,
+            counts_fit: (slope, mean_y - slope * mean_x),
+        }
+    }
+
     /// Read telemetry from received bands.
     ///
     /// Takes two signals containing the horizontal averages of each band (8
@@ -42,8 +157,8 @@ impl Telemetry {
             .take(16 + 9)
             .collect();
 
-        let telemetry = Self {
-            values_a: (1..=16).map(|wedge|
+        let telemetry = Self::new(
+            (1..=16).map(|wedge|
                 // Contrast wedges 1-9 are averaged to the ones on the next frame
                 if wedge <= 9 {
                     (means_a[wedge - 1] + means_a[wedge + 16 - 1]) / 2.
@@ -51,8 +166,7 @@ impl Telemetry {
                     means_a[wedge - 1]
                 }
             ).collect(),
-
-            values_b: (1..=16).map(|wedge|
+            (1..=16).map(|wedge|
                 // Contrast wedges 1-9 are averaged to the ones on the next frame
                 if wedge <= 9 {
                     (means_b[wedge - 1] + means_b[wedge + 16 - 1]) / 2.
@@ -60,7 +174,7 @@ impl Telemetry {
                     means_b[wedge - 1]
                 }
             ).collect(),
-        };
+        );
 
         debug!(
             "Telemetry wedges_a: {:?}, wedges_b: {:?}",
@@ -87,6 +201,96 @@ impl Telemetry {
         }
     }
 
+    /// Convert a value to 10 bit counts (0 to 1023).
+    ///
+    /// Uses a least squares fit of the contrast wedges calculated when
+    /// creating the `Telemetry`.
+    pub fn to_counts(&self, value: f32) -> f32 {
+        let (slope, intercept) = self.counts_fit;
+        slope * value + intercept
+    }
+
+    /// Get value of wedge in counts.
+    pub fn get_wedge_counts(&self, wedge: u32, channel: Option<Channel>) -> f32 {
+        self.to_counts(self.get_wedge_value(wedge, channel))
+    }
+
+    /// Temperature in Kelvin of each PRT on the black body.
+    pub fn prt_temperatures(&self, sat_name: &SatName) -> [f32; 4] {
+        let coefficients = prt_coefficients(sat_name);
+        let mut result = [0.; 4];
+        for (i, (temp, d)) in result.iter_mut().zip(coefficients.iter()).enumerate() {
+            // PRTs are on wedges 10 to 13, equal on both channels
+            let c = self.get_wedge_counts(10 + i as u32, None) as f64;
+            *temp = (d[0] + d[1] * c + d[2] * c * c) as f32;
+        }
+        result
+    }
+
+    /// Temperature of the black body in Kelvin, mean of the PRTs.
+    pub fn black_body_temperature(&self, sat_name: &SatName) -> f32 {
+        self.prt_temperatures(sat_name).iter().sum::<f32>() / 4.
+    }
+
+    /// Patch temperature in Kelvin.
+    ///
+    /// Conversion from the NOAA KLM User's Guide, equal for every satellite.
+    pub fn patch_temperature(&self) -> f32 {
+        0.124 * self.get_wedge_counts(14, None) + 90.113
+    }
+
+    /// Counts of the back scan, the view of the black body by the channel.
+    pub fn back_scan_counts(&self, channel: Channel) -> f32 {
+        self.get_wedge_counts(15, Some(channel))
+    }
+
+    /// Counts of the deep space view of the channel, if known.
+    pub fn space_view_counts(&self, channel: Channel) -> Option<f32> {
+        let value = match channel {
+            Channel::A => self.space_a,
+            Channel::B => self.space_b,
+        };
+        value.map(|v| self.to_counts(v))
+    }
+
+    /// Get every value, in physical units when possible.
+    ///
+    /// Temperatures of the PRTs are only available if the satellite is
+    /// known.
+    pub fn report(
+        &self,
+        sat_name: Option<&SatName>,
+        time: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> TelemetryReport {
+        let prt_temperatures = sat_name.map(|s| self.prt_temperatures(s));
+
+        TelemetryReport {
+            satellite: sat_name.map(|s| s.to_string()),
+            time: time.map(|t| t.to_rfc3339()),
+            channel_a: self.get_channel_name(Channel::A).to_string(),
+            channel_b: self.get_channel_name(Channel::B).to_string(),
+            wedges_a: (1..=16)
+                .map(|w| self.get_wedge_counts(w, Some(Channel::A)))
+                .collect(),
+            wedges_b: (1..=16)
+                .map(|w| self.get_wedge_counts(w, Some(Channel::B)))
+                .collect(),
+            prt_counts: [
+                self.get_wedge_counts(10, None),
+                self.get_wedge_counts(11, None),
+                self.get_wedge_counts(12, None),
+                self.get_wedge_counts(13, None),
+            ],
+            prt_temperatures,
+            black_body_temperature: sat_name.map(|s| self.black_body_temperature(s)),
+            patch_temperature: self.patch_temperature(),
+            back_scan_a: self.back_scan_counts(Channel::A),
+            back_scan_b: self.back_scan_counts(Channel::B),
+            space_view_a: self.space_view_counts(Channel::A),
+            space_view_b: self.space_view_counts(Channel::B),
+        }
+    }
+
     /// Get channel name.
     pub fn get_channel_name(&self, channel: Channel) -> &str {
         // Find the contrast wedge (1 to 9) closest to the channel
@@ -117,6 +321,19 @@ impl Telemetry {
     }
 }
 
+/// Horizontal variance of both telemetry bands of a row, indicates if there is
+/// noise.
+///
+/// The row should have `PX_PER_ROW` values.
+pub fn line_variance(line: &[f32]) -> f32 {
+    let band_variance = |values: &[f32]| {
+        let mean: f32 = values.iter().sum::<f32>() / values.len() as f32;
+        values.iter().map(|x| (x - mean).powi(2)).sum::<f32>()
+    };
+
+    (band_variance(&line[994..(994 + 44)]) + band_variance(&line[2034..(2034 + 44)])) / 88.
+}
+
 /// Read telemetry from aligned signal.
 ///
 /// Takes already synced signal, it's a Vec where the first `PX_PER_ROW` values
@@ -142,31 +359,36 @@ pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Tel
     let mut mean_b: Signal = Vec::with_capacity(signal.len() / PX_PER_ROW as usize);
     // Horizontal variance of both telemetry bands, indicates if there is noise
     let mut variance: Signal = Vec::with_capacity(signal.len() / PX_PER_ROW as usize);
+    // Horizontal average of the deep space view of both channels
+    let mut space_a: Signal = Vec::with_capacity(signal.len() / PX_PER_ROW as usize);
+    let mut space_b: Signal = Vec::with_capacity(signal.len() / PX_PER_ROW as usize);
+
+    let space_start = PX_SYNC_FRAME as usize;
+    let space_end = (PX_SYNC_FRAME + PX_SPACE_DATA) as usize;
 
     // Iterate a row at a time (each row is one pixel high)
     for line in signal.chunks_exact(PX_PER_ROW as usize) {
+        context.check_cancelled()?;
+
         // Values on each band
         let a_values = &line[994..(994 + 44)];
         let b_values = &line[2034..(2034 + 44)];
 
-        // Horizontal average
-        let curr_mean_a: f32 = a_values.iter().sum::<f32>() / 44.;
-        let curr_mean_b: f32 = b_values.iter().sum::<f32>() / 44.;
-        mean_a.push(curr_mean_a);
-        mean_b.push(curr_mean_b);
-
-        // Horizontal variance
-        variance.push(
-            (a_values
+        space_a.push(
+            line[space_start..space_end].iter().sum::<f32>() / PX_SPACE_DATA as f32,
+        );
+        space_b.push(
+            line[PX_PER_CHANNEL as usize + space_start..PX_PER_CHANNEL as usize + space_end]
                 .iter()
-                .map(|x| (x - curr_mean_a).powi(2))
                 .sum::<f32>()
-                + b_values
-                    .iter()
-                    .map(|x| (x - curr_mean_b).powi(2))
-                    .sum::<f32>())
-                / 88.,
+                / PX_SPACE_DATA as f32,
         );
+
+        // Horizontal average
+        mean_a.push(a_values.iter().sum::<f32>() / 44.);
+        mean_b.push(b_values.iter().sum::<f32>() / 44.);
+
+        variance.push(line_variance(line));
     }
 
     // Cross correlation between telemetry band averages and the telemetry
@@ -198,23 +420,21 @@ pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Tel
     }
 
     // Cross correlation of both telemetry bands with a sample
-    for i in 0..mean_a.len() - telemetry_sample.len() {
-        let mut sum: f32 = 0.;
-        for j in 0..telemetry_sample.len() {
-            sum += telemetry_sample[j] * mean_a[i + j];
-            sum += telemetry_sample[j] * mean_b[i + j];
-        }
+    let bands: Signal = mean_a.iter().zip(mean_b.iter()).map(|(a, b)| a + b).collect();
+    let sums = dsp::correlate(context, &bands, &telemetry_sample)?;
+
+    // sqrt() for standard deviation instead of variance, otherwise variance
+    // is too big and noise affects the quality estimation too much compared
+    // to the correlation
+    // Check standard deviation on the same places where we cross correlate,
+    // that's why I sum telemetry_sample.len() values
+    let deviation: Signal = variance.iter().map(|x| x.sqrt()).collect();
+    let deviation_sums =
+        dsp::correlate(context, &deviation, &vec![1.; telemetry_sample.len()])?;
 
-        // sqrt() for standard deviation instead of variance, otherwise variance
-        // is too big and noise affects the quality estimation too much compared
-        // to the correlation
-        // Check standard deviation on the same places where we cross correlate,
-        // that's why I use telemetry_sample.len()
-        let q = sum
-            / variance[i..(i + telemetry_sample.len())]
-                .iter()
-                .map(|x| x.sqrt())
-                .sum::<f32>();
+    for i in 0..mean_a.len() - telemetry_sample.len() {
+        let sum = sums[i];
+        let q = sum / deviation_sums[i];
 
         if q > best.1 {
             best = (i, q);
@@ -226,7 +446,13 @@ pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Tel
         }
     }
 
-    let telemetry = Telemetry::from_bands(&mean_a, &mean_b, best.0);
+    let mut telemetry = Telemetry::from_bands(&mean_a, &mean_b, best.0);
+
+    // Use the median to ignore the minute markers
+    let frame_end = (best.0 + ROWS_PER_FRAME).min(space_a.len());
+    telemetry.space_a = Some(median(&space_a[best.0..frame_end]));
+    telemetry.space_b = Some(median(&space_b[best.0..frame_end]));
+
     info!(
         "Channel A: {}, Channel B: {}",
         telemetry.get_channel_name(Channel::A),
@@ -242,6 +468,75 @@ pub fn read_telemetry(context: &mut Context, signal: &Signal) -> err::Result<Tel
     Ok(telemetry)
 }
 
+/// Median of some values.
+fn median(values: &[f32]) -> f32 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("Can't compare values"));
+    sorted[sorted.len() / 2]
+}
+
+/// Write telemetry report.
+///
+/// If the filename ends in `.json` the report is saved as JSON, otherwise as
+/// CSV with a header and a single row. Useful for appending reports of several
+/// passes.
+pub fn write_report(report: &TelemetryReport, filename: &Path) -> err::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+
+    let extension = filename
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    if extension.as_deref() == Some("json") {
+        serde_json::to_writer_pretty(&mut file, report)
+            .map_err(|e| err::Error::Internal(format!("Could not write JSON: {}", e)))?;
+        writeln!(file)?;
+        return Ok(());
+    }
+
+    let optional = |v: Option<f32>| v.map(|v| format!("{:.3}", v)).unwrap_or_default();
+
+    let mut header: Vec<String> = vec!["satellite".to_string(), "time".to_string()];
+    let mut row: Vec<String> = vec![
+        report.satellite.clone().unwrap_or_default(),
+        report.time.clone().unwrap_or_default(),
+    ];
+
+    header.extend(vec!["channel_a".to_string(), "channel_b".to_string()]);
+    row.extend(vec![report.channel_a.clone(), report.channel_b.clone()]);
+
+    for i in 0..4 {
+        header.push(format!("prt{}_counts", i + 1));
+        row.push(format!("{:.3}", report.prt_counts[i]));
+    }
+    for i in 0..4 {
+        header.push(format!("prt{}_temperature", i + 1));
+        row.push(optional(report.prt_temperatures.map(|t| t[i])));
+    }
+    header.push("black_body_temperature".to_string());
+    row.push(optional(report.black_body_temperature));
+    header.push("patch_temperature".to_string());
+    row.push(format!("{:.3}", report.patch_temperature));
+    header.extend(vec!["back_scan_a".to_string(), "back_scan_b".to_string()]);
+    row.push(format!("{:.3}", report.back_scan_a));
+    row.push(format!("{:.3}", report.back_scan_b));
+    header.extend(vec!["space_view_a".to_string(), "space_view_b".to_string()]);
+    row.push(optional(report.space_view_a));
+    row.push(optional(report.space_view_b));
+    for (name, wedges) in &[("a", &report.wedges_a), ("b", &report.wedges_b)] {
+        for (i, value) in wedges.iter().enumerate() {
+            header.push(format!("wedge{}_{}", i + 1, name));
+            row.push(format!("{:.3}", value));
+        }
+    }
+
+    writeln!(file, "{}", header.join(","))?;
+    writeln!(file, "{}", row.join(","))?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -324,7 +619,7 @@ mod tests {
             let mut values_b = sample_means.to_vec();
             values_a.push(channel_a);
             values_b.push(channel_b);
-            Telemetry { values_a, values_b }
+            Telemetry::new(values_a, values_b)
         };
 
         // (Expected channel A name, Channel A wedge 16 value,
@@ -346,4 +641,40 @@ mod tests {
             assert_eq!(telemetry.get_channel_name(Channel::B), case.2);
         }
     }
+
+    /// Check conversion to counts and temperatures.
+    #[test]
+    fn test_telemetry_counts() {
+        // Contrast wedges on a signal with an offset and gain, the rest have
+        // known counts
+        let to_value = |counts: f32| counts * 0.01 - 3.;
+        let mut values: Vec<f32> = (1..=8).map(|w| to_value(w as f32 * 1023. / 8.)).collect();
+        values.push(to_value(0.));
+        values.extend(&[
+            to_value(500.), // PRT 1
+            to_value(500.), // PRT 2
+            to_value(500.), // PRT 3
+            to_value(500.), // PRT 4
+            to_value(1000.), // Patch temperature
+            to_value(300.), // Back scan
+            to_value(0.), // Channel ID
+        ]);
+
+        let mut telemetry = Telemetry::new(values.clone(), values);
+        telemetry.space_a = Some(to_value(900.));
+
+        assert!((telemetry.get_wedge_counts(10, None) - 500.).abs() < 0.01);
+        assert!((telemetry.back_scan_counts(Channel::A) - 300.).abs() < 0.01);
+        assert!((telemetry.space_view_counts(Channel::A).unwrap() - 900.).abs() < 0.01);
+        assert_eq!(telemetry.space_view_counts(Channel::B), None);
+        assert!((telemetry.patch_temperature() - 214.113).abs() < 0.01);
+
+        // 276.6067 + 0.051111 * 500 + 1.405783e-6 * 500^2
+        let temperatures = telemetry.prt_temperatures(&SatName::Noaa19);
+        assert!((temperatures[0] - 302.51).abs() < 0.01);
+
+        let report = telemetry.report(Some(&SatName::Noaa19), None);
+        assert_eq!(report.wedges_a.len(), 16);
+        assert_eq!(report.prt_temperatures, Some(temperatures));
+    }
 }
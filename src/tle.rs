@@ -0,0 +1,320 @@
+//! Local archive of TLEs.
+//!
+//! Every downloaded TLE is added to an archive on the settings folder, and bulk
+//! files can be imported too. When calculating orbits I use the element set
+//! whose epoch is closest to the recording time, otherwise old recordings end
+//! up with misaligned maps.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::{info, warn};
+
+use crate::err;
+use crate::misc;
+use crate::noaa_apt::{OrbitSettings, RefTime, SatName};
+
+/// Name of the archive file on the settings folder.
+const ARCHIVE_FILENAME: &str = "tle_archive.txt";
+
+/// If the archive doesn't have an element set closer than this to the
+/// recording time, I try to download a new TLE.
+const DOWNLOAD_EPOCH_DISTANCE_DAYS: i64 = 7;
+
+/// Satellite element set from a TLE.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElementSet {
+    pub sat_name: SatName,
+    pub line1: String,
+    pub line2: String,
+    pub epoch: DateTime<Utc>,
+}
+
+impl ElementSet {
+    /// As a three line element set, using the name expected by
+    /// `orbit::find_sat()`.
+    pub fn to_tle(&self) -> String {
+        format!("{}\n{}\n{}\n", self.sat_name.to_string(), self.line1, self.line2)
+    }
+}
+
+/// NORAD catalog number of each satellite.
+fn catalog_number(sat_name: &SatName) -> u32 {
+    match sat_name {
+        SatName::Noaa15 => 25338,
+        SatName::Noaa18 => 28654,
+        SatName::Noaa19 => 33591,
+    }
+}
+
+/// Parse the epoch on the first line of a TLE, "YYDDD.DDDDDDDD".
+fn parse_epoch(line1: &str) -> Option<DateTime<Utc>> {
+    let year: i32 = line1.get(18..20)?.trim().parse().ok()?;
+    let day: f64 = line1.get(20..32)?.trim().parse().ok()?;
+
+    // Two digit years from 57 to 99 belong to the 20th century
+    let year = if year < 57 { 2000 + year } else { 1900 + year };
+
+    let start = Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single()?;
+    Some(start + Duration::milliseconds(((day - 1.) * 86_400_000.).round() as i64))
+}
+
+/// Parse element sets of NOAA 15, 18 and 19, ignoring other satellites.
+///
+/// Accepts three line element sets and also plain two line element sets,
+/// satellites are identified by their catalog number.
+pub fn parse(text: &str) -> Vec<ElementSet> {
+    let lines: Vec<&str> = text.lines().map(|l| l.trim_end()).collect();
+    let mut sets = Vec::new();
+
+    for (i, pair) in lines.windows(2).enumerate() {
+        let (line1, line2) = (pair[0], pair[1]);
+        if !line1.starts_with("1 ") || !line2.starts_with("2 ") {
+            continue;
+        }
+
+        let number: Option<u32> = line1.get(2..7).and_then(|n| n.trim().parse().ok());
+        let sat_name = [SatName::Noaa15, SatName::Noaa18, SatName::Noaa19]
+            .iter()
+            .find(|s| Some(catalog_number(s)) == number)
+            .cloned();
+
+        match (sat_name, parse_epoch(line1)) {
+            (Some(sat_name), Some(epoch)) => sets.push(ElementSet {
+                sat_name,
+                line1: line1.to_string(),
+                line2: line2.to_string(),
+                epoch,
+            }),
+            (Some(_), None) => warn!("Invalid TLE epoch on line {}", i + 1),
+            (None, _) => (),
+        }
+    }
+
+    sets
+}
+
+/// Element set of the satellite whose epoch is closest to `time`.
+pub fn nearest<'a>(
+    sets: &'a [ElementSet],
+    sat_name: &SatName,
+    time: DateTime<Utc>,
+) -> Option<&'a ElementSet> {
+    sets.iter()
+        .filter(|set| &set.sat_name == sat_name)
+        .min_by_key(|set| (set.epoch - time).num_milliseconds().abs())
+}
+
+/// Location of the archive, None if the settings folder is not available.
+fn archive_path() -> Option<PathBuf> {
+    directories::ProjectDirs::from("ar.com.mbernardi", "", "noaa-apt")
+        .map(|dirs| dirs.config_dir().join(ARCHIVE_FILENAME))
+}
+
+/// Element sets on an archive file, empty if the file doesn't exist.
+fn load_archive(path: &Path) -> err::Result<Vec<ElementSet>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+/// Append element sets of some TLE text not already on the archive file.
+///
+/// Returns the number of element sets added.
+fn add_to_archive(path: &Path, text: &str) -> err::Result<usize> {
+    let archived = load_archive(path)?;
+    let mut new_sets: Vec<ElementSet> = Vec::new();
+
+    for set in parse(text) {
+        let known = archived
+            .iter()
+            .chain(new_sets.iter())
+            .any(|a| a.sat_name == set.sat_name && a.epoch == set.epoch);
+        if !known {
+            new_sets.push(set);
+        }
+    }
+
+    if !new_sets.is_empty() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        for set in &new_sets {
+            file.write_all(set.to_tle().as_bytes())?;
+        }
+    }
+
+    Ok(new_sets.len())
+}
+
+/// Add element sets of some TLE text to the archive, for example a
+/// downloaded TLE or a bulk file with TLEs from several dates.
+///
+/// Returns the number of element sets added.
+pub fn store(text: &str) -> err::Result<usize> {
+    match archive_path() {
+        Some(path) => add_to_archive(&path, text),
+        None => Err(err::Error::Internal(
+            "Could not get system settings directory, can't archive TLE".to_string(),
+        )),
+    }
+}
+
+/// TLE of the satellite with the epoch closest to `time`.
+///
+/// If a custom TLE is given I look only there, it can contain several element
+/// sets of the same satellite. Otherwise I look on the archive and download a
+/// new TLE if there is nothing close enough. Gives a warning if the epoch is
+/// more than `max_epoch_days` away from `time`.
+pub fn select(
+    custom_tle: Option<&str>,
+    sat_name: &SatName,
+    time: DateTime<Utc>,
+    max_epoch_days: i64,
+) -> err::Result<String> {
+    let distance = |set: &ElementSet| (set.epoch - time).num_days().abs();
+
+    let sets = match custom_tle {
+        Some(tle) => parse(tle),
+        None => {
+            let mut sets = match archive_path() {
+                Some(path) => load_archive(&path)?,
+                None => Vec::new(),
+            };
+
+            let is_close = |set: &ElementSet| distance(set) < DOWNLOAD_EPOCH_DISTANCE_DAYS;
+            if !nearest(&sets, sat_name, time).is_some_and(is_close) {
+                match misc::get_current_tle() {
+                    Ok(tle) => sets.extend(parse(&tle)),
+                    Err(e) if nearest(&sets, sat_name, time).is_some() => {
+                        warn!("Could not get a new TLE, using archived one: {}", e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            sets
+        }
+    };
+
+    let set = nearest(&sets, sat_name, time).ok_or_else(|| {
+        err::Error::Internal(format!("Satellite \"{}\" not found in TLE", sat_name.to_string()))
+    })?;
+
+    if distance(set) <= max_epoch_days {
+        info!("Using TLE with epoch {}", set.epoch);
+    } else {
+        warn!(
+            "Using TLE with epoch {}, {} days away from the recording. Expect a misaligned map, \
+            import a TLE from around that date or provide a custom one",
+            set.epoch,
+            distance(set)
+        );
+    }
+
+    Ok(set.to_tle())
+}
+
+/// TLE to use for the satellite and recording time given.
+///
+/// Selected only the first time, then I keep it on the `OrbitSettings`.
+pub fn for_orbit(orbit: &OrbitSettings) -> err::Result<String> {
+    if let Some(tle) = orbit.tle.get() {
+        return Ok(tle.clone());
+    }
+
+    let time = match orbit.ref_time {
+        RefTime::Start(time) | RefTime::End(time) => time,
+    };
+
+    let tle = select(orbit.custom_tle.as_deref(), &orbit.sat_name, time, orbit.max_epoch_days)?;
+    Ok(orbit.tle.get_or_init(|| tle).clone())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const NOAA_19_OLD: &str = "\
+NOAA 19
+1 33591U 09005A   18340.58237596  .00000039  00000-0  46344-4 0  9992
+2 33591  99.1396 335.3335 0013998 261.8606  98.0975 14.12326924506413
+";
+
+    const NOAA_19_NEW: &str = "\
+1 33591U 09005A   20001.50000000  .00000039  00000-0  46344-4 0  9992
+2 33591  99.1396 335.3335 0013998 261.8606  98.0975 14.12326924506413
+";
+
+    #[test]
+    fn test_parse_epoch() {
+        let sets = parse(NOAA_19_OLD);
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].sat_name, SatName::Noaa19);
+        assert_eq!(
+            sets[0].epoch,
+            Utc.with_ymd_and_hms(2018, 12, 6, 13, 58, 37).unwrap()
+                + Duration::milliseconds(283)
+        );
+
+        // Without name
+        let sets = parse(NOAA_19_NEW);
+        assert_eq!(sets[0].epoch, Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap());
+    }
+
+    /// Check that the closest element set is selected and that the archive
+    /// doesn't store duplicates.
+    #[test]
+    fn test_archive() {
+        let path = std::env::temp_dir()
+            .join(format!("noaa-apt-test-{}", std::process::id()))
+            .join(ARCHIVE_FILENAME);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(add_to_archive(&path, NOAA_19_OLD).unwrap(), 1);
+        assert_eq!(add_to_archive(&path, NOAA_19_OLD).unwrap(), 0);
+        assert_eq!(add_to_archive(&path, &format!("{}{}", NOAA_19_OLD, NOAA_19_NEW)).unwrap(), 1);
+
+        let sets = load_archive(&path).unwrap();
+        assert_eq!(sets.len(), 2);
+
+        let time = Utc.with_ymd_and_hms(2019, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(nearest(&sets, &SatName::Noaa19, time), Some(&sets[0]));
+        let time = Utc.with_ymd_and_hms(2019, 12, 1, 0, 0, 0).unwrap();
+        assert_eq!(nearest(&sets, &SatName::Noaa19, time), Some(&sets[1]));
+        assert_eq!(nearest(&sets, &SatName::Noaa15, time), None);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Check that the TLE is selected once and shared by every clone of the
+    /// orbit settings.
+    #[test]
+    fn test_for_orbit_once() {
+        let orbit = OrbitSettings {
+            sat_name: SatName::Noaa19,
+            custom_tle: Some(NOAA_19_OLD.to_string()),
+            ref_time: RefTime::Start(Utc.with_ymd_and_hms(2018, 12, 6, 0, 0, 0).unwrap()),
+            draw_map: None,
+            max_epoch_days: 7,
+            tle: Default::default(),
+        };
+        let clone = orbit.clone();
+
+        let tle = for_orbit(&clone).unwrap();
+        assert_eq!(orbit.tle.get(), Some(&tle));
+
+        // Not looked at again
+        let changed = OrbitSettings {
+            custom_tle: Some(NOAA_19_NEW.to_string()),
+            ..orbit
+        };
+        assert_eq!(for_orbit(&changed).unwrap(), tle);
+    }
+}
@@ -0,0 +1,382 @@
+//! Decode recordings as they appear on a folder.
+//!
+//! Meant to be left running next to a recorder that saves a WAV file after
+//! each pass. Every few seconds I look for WAV files that were not modified
+//! recently and decode them. Processed filenames are appended to a state file
+//! on the same folder, so restarting doesn't decode old passes again. Inputs
+//! that fail are moved to a `failed` subfolder together with the error.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+
+use crate::config::Settings;
+use crate::context::Context;
+use crate::dsp::Rate;
+use crate::err;
+use crate::misc;
+use crate::noaa_apt::{
+    self, ColorSettings, Contrast, Enhancement, MapSettings, OrbitSettings, RefTime, Rotate,
+    SatName,
+};
+
+/// Name of the file that keeps track of processed recordings.
+const STATE_FILENAME: &str = ".noaa-apt-watch";
+
+/// Name of the folder where failed recordings are moved.
+const FAILED_FOLDER: &str = "failed";
+
+/// Time between looks at the folder.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Recordings are considered finished if they were not modified for this long.
+const SETTLE_TIME: Duration = Duration::from_secs(10);
+
+/// Images to generate from each recording.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Product {
+    /// Grayscale image.
+    Raw,
+
+    /// False color using the default palette.
+    FalseColor,
+
+    /// False color blending the day and night palettes.
+    DayNight,
+
+    /// Map color IR.
+    Mcir,
+
+    /// Enhancement driven by temperatures, with legend.
+    Enhancement(Enhancement),
+}
+
+impl Product {
+    pub fn from_name(name: &str) -> Option<Product> {
+        match name {
+            "raw" => Some(Product::Raw),
+            "false-color" => Some(Product::FalseColor),
+            "day-night" => Some(Product::DayNight),
+            "mcir" => Some(Product::Mcir),
+            _ => Enhancement::from_name(name).map(Product::Enhancement),
+        }
+    }
+
+    /// Name used on output filenames, the same accepted by `from_name()`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Product::Raw => "raw",
+            Product::FalseColor => "false-color",
+            Product::DayNight => "day-night",
+            Product::Mcir => "mcir",
+            Product::Enhancement(Enhancement::SeaSurface) => "sea",
+            Product::Enhancement(Enhancement::CloudTop) => "cloud-top",
+            Product::Enhancement(Enhancement::Thermal) => "thermal",
+        }
+    }
+
+    fn color_settings(self, settings: &Settings) -> Option<ColorSettings> {
+        let color = ColorSettings {
+            palette_filename: settings.default_palette_filename.clone(),
+            ch_a_tune_start: 0.,
+            ch_a_tune_end: 0.,
+            ch_b_tune_start: 0.,
+            ch_b_tune_end: 0.,
+            enhancement: None,
+            night_palette_filename: None,
+            mcir: false,
+        };
+
+        match self {
+            Product::Raw => None,
+            Product::FalseColor => Some(color),
+            Product::DayNight => Some(ColorSettings {
+                night_palette_filename: Some(res_path!("palettes", "noaa-apt-night.png")),
+                ..color
+            }),
+            Product::Mcir => Some(ColorSettings { mcir: true, ..color }),
+            Product::Enhancement(enhancement) => Some(ColorSettings {
+                enhancement: Some(enhancement),
+                ..color
+            }),
+        }
+    }
+}
+
+/// Settings for the watch mode.
+#[derive(Clone, Debug)]
+pub struct WatchSettings {
+    /// Folder to watch.
+    pub directory: PathBuf,
+
+    pub products: Vec<Product>,
+
+    /// Output filename, relative to the watched folder. Can contain `{name}`,
+    /// `{product}`, `{sat}` and `{time}`.
+    pub template: String,
+
+    pub sync: bool,
+    pub contrast_adjustment: Contrast,
+    pub rotate: Rotate,
+    pub custom_tle: Option<String>,
+    pub draw_map: Option<MapSettings>,
+
+    /// Fit the recording time and map corrections of each recording.
+    pub auto_align: bool,
+}
+
+/// Watch the folder forever.
+pub fn watch(settings: &Settings, watch_settings: &WatchSettings) -> err::Result<()> {
+    let directory = &watch_settings.directory;
+    if !directory.is_dir() {
+        return Err(err::Error::InvalidInput(format!(
+            "{} is not a folder",
+            directory.display()
+        )));
+    }
+
+    let state_path = directory.join(STATE_FILENAME);
+    let mut processed = load_state(&state_path)?;
+
+    info!(
+        "Watching {}, {} recordings already processed",
+        directory.display(),
+        processed.len()
+    );
+
+    loop {
+        for path in finished_recordings(directory, &processed)? {
+            info!("Decoding {}", path.display());
+
+            match decode_recording(settings, watch_settings, &path) {
+                Ok(outputs) => {
+                    for output in outputs {
+                        info!("Saved {}", output.display());
+                    }
+                }
+                Err(e) => {
+                    error!("Could not decode {}: {}", path.display(), e);
+                    quarantine(directory, &path, &e)?;
+                }
+            }
+
+            let name = file_name(&path)?;
+            append_state(&state_path, &name)?;
+            processed.insert(name);
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Read names of processed recordings.
+fn load_state(path: &Path) -> err::Result<HashSet<String>> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+
+    let mut processed = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            processed.insert(line);
+        }
+    }
+
+    Ok(processed)
+}
+
+/// Add the name of a processed recording.
+fn append_state(path: &Path, name: &str) -> err::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", name)?;
+
+    Ok(())
+}
+
+fn file_name(path: &Path) -> err::Result<String> {
+    path.file_name()
+        .and_then(std::ffi::OsStr::to_str)
+        .map(|s| s.to_string())
+        .ok_or_else(|| err::Error::Internal(format!("Invalid filename {}", path.display())))
+}
+
+/// WAV files not processed yet and not modified recently, sorted by name.
+fn finished_recordings(directory: &Path, processed: &HashSet<String>) -> err::Result<Vec<PathBuf>> {
+    let now = SystemTime::now();
+    let mut result = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        let is_wav = path
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)
+            .is_some_and(|e| e.eq_ignore_ascii_case("wav"));
+        if !is_wav || !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        if processed.contains(&file_name(&path)?) {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+        let settled = now
+            .duration_since(modified)
+            .is_ok_and(|age| age >= SETTLE_TIME);
+        if settled {
+            result.push(path);
+        }
+    }
+
+    result.sort();
+    Ok(result)
+}
+
+/// Move a recording to the failed folder and save the error next to it.
+fn quarantine(directory: &Path, path: &Path, error: &err::Error) -> err::Result<()> {
+    let failed = directory.join(FAILED_FOLDER);
+    fs::create_dir_all(&failed)?;
+
+    let name = file_name(path)?;
+    fs::rename(path, failed.join(&name))?;
+
+    let mut file = File::create(failed.join(format!("{}.txt", name)))?;
+    writeln!(file, "{}", error)?;
+
+    Ok(())
+}
+
+/// Name of a satellite to use on filenames.
+fn sat_file_name(sat_name: &SatName) -> &'static str {
+    match sat_name {
+        SatName::Noaa15 => "noaa_15",
+        SatName::Noaa18 => "noaa_18",
+        SatName::Noaa19 => "noaa_19",
+    }
+}
+
+/// Replace the fields of the output filename template.
+fn output_filename(
+    template: &str,
+    name: &str,
+    product: Product,
+    sat_name: &SatName,
+    ref_time: &RefTime,
+) -> String {
+    let time = match ref_time {
+        RefTime::Start(time) | RefTime::End(time) => time,
+    };
+
+    template
+        .replace("{name}", name)
+        .replace("{product}", product.name())
+        .replace("{sat}", sat_file_name(sat_name))
+        .replace("{time}", &time.format("%Y%m%d-%H%M%S").to_string())
+}
+
+/// Decode a recording and save every product, returns the saved filenames.
+fn decode_recording(
+    settings: &Settings,
+    watch_settings: &WatchSettings,
+    path: &Path,
+) -> err::Result<Vec<PathBuf>> {
+    let (ref_time, sat_name) = misc::infer_time_sat(settings, path)?;
+
+    let mut context = Context::decode(
+        |_progress, description| info!("{}", description),
+        Rate::hz(settings.work_rate),
+        Rate::hz(noaa_apt::FINAL_RATE),
+        Vec::new(),
+        false,
+    );
+
+    let (signal, rate) = noaa_apt::load(path)?;
+    let (raw_data, _sync_report, _quality) =
+        noaa_apt::decode(&mut context, settings, &signal, rate, watch_settings.sync)?;
+
+    let orbit = OrbitSettings {
+        sat_name: sat_name.clone(),
+        custom_tle: watch_settings.custom_tle.clone(),
+        ref_time: ref_time.clone(),
+        draw_map: watch_settings.draw_map.clone(),
+        max_epoch_days: settings.tle_max_epoch_days,
+        tle: Default::default(),
+    };
+    let orbit = if watch_settings.auto_align {
+        noaa_apt::align_map(&mut context, &raw_data, &orbit)?
+    } else {
+        orbit
+    };
+
+    let name = path
+        .file_stem()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or("output");
+
+    let mut outputs = Vec::new();
+
+    for &product in &watch_settings.products {
+        let mut img = noaa_apt::process(
+            &mut context,
+            &raw_data,
+            watch_settings.contrast_adjustment.clone(),
+            watch_settings.rotate.clone(),
+            product.color_settings(settings),
+            Some(orbit.clone()),
+        )?;
+
+        if let Product::Enhancement(enhancement) = product {
+            img = noaa_apt::draw_legend(&img, enhancement)?;
+        }
+
+        let filename = watch_settings.directory.join(output_filename(
+            &watch_settings.template,
+            name,
+            product,
+            &sat_name,
+            &ref_time,
+        ));
+        if let Some(parent) = filename.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        img.save(&filename)?;
+        outputs.push(filename);
+    }
+
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_output_filename() {
+        let time = RefTime::Start(Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap());
+
+        assert_eq!(
+            output_filename(
+                "{sat}/{time}_{name}-{product}.png",
+                "pass",
+                Product::Enhancement(Enhancement::CloudTop),
+                &SatName::Noaa18,
+                &time,
+            ),
+            "noaa_18/20200102-030405_pass-cloud-top.png"
+        );
+
+        for name in &["raw", "false-color", "day-night", "mcir", "sea", "cloud-top", "thermal"] {
+            assert_eq!(Product::from_name(name).unwrap().name(), *name);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 //! Functions for loading and saving WAV files.
 
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
 
 use log::{debug, warn};
@@ -7,52 +9,86 @@ use log::{debug, warn};
 use crate::dsp::{self, Signal};
 use crate::err;
 
-/// Load wav file, return `Signal` and specs.
-pub fn load_wav(filename: &Path) -> err::Result<(Signal, hound::WavSpec)> {
-    debug!("Loading WAV: {}", filename.display());
+/// Number of frames read at once by `WavChunks`.
+const CHUNK_LEN: usize = 1 << 16;
+
+/// Reads the first channel of a WAV file by chunks.
+pub struct WavChunks {
+    reader: hound::WavReader<BufReader<File>>,
+    spec: hound::WavSpec,
+}
+
+impl WavChunks {
+    /// Open WAV file.
+    pub fn open(filename: &Path) -> err::Result<WavChunks> {
+        debug!("Opening WAV: {}", filename.display());
 
-    let mut reader = hound::WavReader::open(filename)?;
-    let spec = reader.spec();
+        let reader = hound::WavReader::open(filename)?;
+        let spec = reader.spec();
 
-    if spec.channels != 1 {
-        warn!(
-            "WAV file has {} channels (probably stereo), processing only the \
-            first one",
-            spec.channels
-        );
+        if spec.channels != 1 {
+            warn!(
+                "WAV file has {} channels (probably stereo), processing only the \
+                first one",
+                spec.channels
+            );
+        }
+
+        debug!("WAV specifications: {:?}", spec);
+
+        Ok(WavChunks { reader, spec })
     }
 
-    debug!("WAV specifications: {:?}", spec);
+    pub fn spec(&self) -> hound::WavSpec {
+        self.spec
+    }
 
-    // Convert samples to float, also if there is more than one channel, the
-    // samples are interleaved so we drop samples from extra channels using
-    // filter_map()
-    let input_samples: Signal = match spec.sample_format {
-        hound::SampleFormat::Int => reader
-            .samples::<i32>()
-            .collect::<Result<Vec<i32>, hound::Error>>()?
-            .iter()
-            .enumerate()
-            .filter_map(|(i, x)| match i % spec.channels as usize {
-                0 => Some(*x as f32),
-                _ => None,
-            })
-            .collect(),
-        hound::SampleFormat::Float => reader
-            .samples::<f32>()
-            .collect::<Result<Vec<f32>, hound::Error>>()?
-            .iter()
-            .enumerate()
-            .filter_map(|(i, x)| match i % spec.channels as usize {
-                0 => Some(*x),
-                _ => None,
-            })
-            .collect(),
-    };
+    /// Number of samples on each channel.
+    pub fn duration(&self) -> u64 {
+        self.reader.duration() as u64
+    }
+
+    /// Read next samples. Returns `None` at the end of the file.
+    pub fn read(&mut self) -> err::Result<Option<Signal>> {
+        // If there is more than one channel, the samples are interleaved so we
+        // drop samples from extra channels using step_by()
+        let channels = self.spec.channels as usize;
+        let samples: Signal = match self.spec.sample_format {
+            hound::SampleFormat::Int => self
+                .reader
+                .samples::<i32>()
+                .take(CHUNK_LEN * channels)
+                .step_by(channels)
+                .map(|s| s.map(|x| x as f32))
+                .collect::<Result<Signal, hound::Error>>()?,
+            hound::SampleFormat::Float => self
+                .reader
+                .samples::<f32>()
+                .take(CHUNK_LEN * channels)
+                .step_by(channels)
+                .collect::<Result<Signal, hound::Error>>()?,
+        };
+
+        if samples.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(samples))
+        }
+    }
+}
+
+/// Load wav file, return `Signal` and specs.
+pub fn load_wav(filename: &Path) -> err::Result<(Signal, hound::WavSpec)> {
+    let mut reader = WavChunks::open(filename)?;
+
+    let mut input_samples: Signal = Vec::with_capacity(reader.duration() as usize);
+    while let Some(samples) = reader.read()? {
+        input_samples.extend(samples);
+    }
 
     debug!("Finished reading WAV");
 
-    Ok((input_samples, spec))
+    Ok((input_samples, reader.spec()))
 }
 
 /// Write signal to file.